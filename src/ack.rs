@@ -0,0 +1,113 @@
+// Acknowledged delivery. Uploaded files of the tables that wait for acks are kept until downstream writes an ack
+// file for them on the destination, then deleted as usual. A file whose ack doesn't appear within
+// ACK_TIMEOUT_SECONDS is kept in place and flagged instead.
+// Without ACK_FILE_PATH, every function here is a no-op.
+use log::error;
+use std::{
+    collections::HashSet,
+    io,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+// An uploaded file waiting for its ack file
+pub struct PendingAck {
+    pub src_file: String,
+    pub metadata_file: String,
+    // Remote path of the ack file
+    pub ack_path: String,
+    since: Instant,
+}
+
+impl PendingAck {
+    pub fn waited(&self) -> Duration {
+        self.since.elapsed()
+    }
+}
+
+pub enum AckOutcome {
+    Acknowledged(PendingAck),
+    TimedOut(PendingAck),
+}
+
+struct Acks {
+    poll_interval: Duration,
+    timeout: Duration,
+    last_poll: Instant,
+    pending: Vec<PendingAck>,
+    // Files kept after their ack timed out, so they are not picked up again
+    timed_out: HashSet<String>,
+}
+
+static ACKS: OnceLock<Mutex<Acks>> = OnceLock::new();
+
+pub fn init(ack_file_path: Option<&str>, poll_seconds: u64, timeout_seconds: u64) {
+    if ack_file_path.is_none() {
+        return;
+    }
+    let _ = ACKS.set(Mutex::new(Acks {
+        poll_interval: Duration::from_secs(poll_seconds),
+        timeout: Duration::from_secs(timeout_seconds),
+        last_poll: Instant::now(),
+        pending: Vec::new(),
+        timed_out: HashSet::new(),
+    }));
+}
+
+pub fn await_ack(src_file: &str, metadata_file: &str, ack_path: String) {
+    if let Some(acks) = ACKS.get() {
+        acks.lock().unwrap().pending.push(PendingAck {
+            src_file: src_file.to_string(),
+            metadata_file: metadata_file.to_string(),
+            ack_path,
+            since: Instant::now(),
+        });
+    }
+}
+
+pub fn is_waiting() -> bool {
+    // Whether any file is waiting for its ack
+    ACKS.get().is_some_and(|acks| !acks.lock().unwrap().pending.is_empty())
+}
+
+pub fn is_held(src_file: &str) -> bool {
+    // Whether a file is waiting for its ack or was kept after its ack timed out
+    ACKS.get().is_some_and(|acks| {
+        let acks = acks.lock().unwrap();
+        acks.timed_out.contains(src_file) || acks.pending.iter().any(|pending| pending.src_file == src_file)
+    })
+}
+
+pub fn poll(existing_acks: impl FnOnce(&[&str]) -> io::Result<HashSet<String>>) -> Vec<AckOutcome> {
+    // Called from the watch loop. Once the poll interval has passed, looks up the ack files of every waiting file
+    // with existing_acks and returns the files that were acknowledged or ran out of time.
+    let Some(acks) = ACKS.get() else {
+        return Vec::new();
+    };
+    let mut acks = acks.lock().unwrap();
+    if acks.pending.is_empty() || acks.last_poll.elapsed() < acks.poll_interval {
+        return Vec::new();
+    }
+    acks.last_poll = Instant::now();
+    let ack_paths: Vec<&str> = acks.pending.iter().map(|pending| pending.ack_path.as_str()).collect();
+    // A failed lookup still times files out, so an unreachable destination doesn't hold them forever
+    let found = existing_acks(&ack_paths).unwrap_or_else(|e| {
+        error!("Failed to look up {} ack file(s). Error: {}", ack_paths.len(), e);
+        HashSet::new()
+    });
+    let timeout = acks.timeout;
+    let mut outcomes: Vec<AckOutcome> = Vec::new();
+    let mut waiting: Vec<PendingAck> = Vec::new();
+    for pending in std::mem::take(&mut acks.pending) {
+        if found.contains(&pending.ack_path) {
+            outcomes.push(AckOutcome::Acknowledged(pending));
+        } else if pending.waited() >= timeout {
+            acks.timed_out.insert(pending.src_file.clone());
+            outcomes.push(AckOutcome::TimedOut(pending));
+        } else {
+            waiting.push(pending);
+        }
+    }
+    acks.pending = waiting;
+    outcomes
+}
@@ -0,0 +1,52 @@
+// Bundles of files sharing a key, by default their base name, sent together with BUNDLE_WINDOW_SECONDS: a csv and
+// its sidecar files go in one transfer unit, whose files are all deleted or all kept. Files are assigned to their
+// bundle when queued, keyed by their suffixed name, so the outcome of the unit can be logged after the transfer.
+// Without BUNDLE_WINDOW_SECONDS, every function here is a no-op.
+use log::{error, info};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Mutex, OnceLock},
+};
+
+static BUNDLES: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+
+pub fn init(enabled: bool) {
+    if enabled {
+        let _ = BUNDLES.set(Mutex::new(HashMap::new()));
+    }
+}
+
+pub fn assign(src_file: &str, key: &str) {
+    if let Some(bundles) = BUNDLES.get() {
+        bundles.lock().unwrap().insert(src_file.to_string(), key.to_string());
+    }
+}
+
+pub fn forget(src_file: &str) {
+    if let Some(bundles) = BUNDLES.get() {
+        bundles.lock().unwrap().remove(src_file);
+    }
+}
+
+pub fn log_outcome(table_name: &str, src_files: &[String], delivered: bool) {
+    // Log one line per bundle among a table's transferred files, forgetting the files of delivered bundles.
+    // Failed files keep their bundle for their retry.
+    let Some(bundles) = BUNDLES.get() else {
+        return;
+    };
+    let mut bundles = bundles.lock().unwrap();
+    let mut file_counts: BTreeMap<String, usize> = BTreeMap::new();
+    for src_file in src_files {
+        let key = if delivered { bundles.remove(src_file) } else { bundles.get(src_file).cloned() };
+        if let Some(key) = key {
+            *file_counts.entry(key).or_default() += 1;
+        }
+    }
+    for (key, file_count) in file_counts {
+        if delivered {
+            info!("Bundle {:?} of table {:?} delivered: all {} file(s) transferred", key, table_name, file_count);
+        } else {
+            error!("Bundle {:?} of table {:?} failed: none of its {} file(s) were deleted", key, table_name, file_count);
+        }
+    }
+}
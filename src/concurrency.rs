@@ -0,0 +1,52 @@
+// Adaptive transfer concurrency (AIMD). The number of tables transferred at once grows by one after a full round of
+// successful transfers and halves on every failure, staying within TRANSFER_CONCURRENCY_MIN and TRANSFER_CONCURRENCY_MAX.
+use log::info;
+use std::sync::{Mutex, OnceLock};
+
+struct Controller {
+    min: usize,
+    max: usize,
+    current: usize,
+    // Successful transfers since the last change
+    successes: usize,
+}
+
+static CONTROLLER: OnceLock<Mutex<Controller>> = OnceLock::new();
+
+pub fn init(min: usize, max: usize) {
+    let _ = CONTROLLER.set(Mutex::new(Controller {
+        min,
+        max,
+        current: min,
+        successes: 0,
+    }));
+    if max > min {
+        info!("Transfer concurrency starts at {} and adapts between {} and {}", min, min, max);
+    }
+}
+
+pub fn current() -> usize {
+    CONTROLLER.get().map_or(1, |controller| controller.lock().unwrap().current)
+}
+
+pub fn record(succeeded: bool) {
+    let Some(controller) = CONTROLLER.get() else {
+        return;
+    };
+    let mut controller = controller.lock().unwrap();
+    let previous = controller.current;
+    if succeeded {
+        controller.successes += 1;
+        if controller.successes >= controller.current && controller.current < controller.max {
+            controller.current += 1;
+            controller.successes = 0;
+            info!("Transfer concurrency {} -> {} after {} successful transfer(s)", previous, controller.current, previous);
+        }
+    } else {
+        controller.successes = 0;
+        controller.current = (controller.current / 2).max(controller.min);
+        if controller.current != previous {
+            info!("Transfer concurrency {} -> {} after a failed transfer", previous, controller.current);
+        }
+    }
+}
@@ -0,0 +1,2475 @@
+use chrono::NaiveTime;
+use chrono_tz::Tz;
+use dotenv::dotenv;
+use encoding_rs::Encoding;
+use log::LevelFilter;
+use regex::Regex;
+use syslog::Facility;
+use std::{
+    collections::HashMap,
+    env,
+    fmt::Debug,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+
+// Where a setting's value came from, in order of precedence
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    Cli,
+    Env,
+    File,
+    Default,
+}
+
+// Raw setting values from each source, before parsing
+pub struct Layers {
+    cli: HashMap<String, String>,
+    env: HashMap<String, String>,
+    file: HashMap<String, String>,
+}
+
+pub struct Setting {
+    pub key: &'static str,
+    pub kind: &'static str,
+    // None marks a required setting
+    pub default: Option<&'static str>,
+    pub description: &'static str,
+}
+
+// rsync protocol versions accepted by RSYNC_PROTOCOL, from rsync 2.x up to the newest releases with some headroom
+const RSYNC_PROTOCOLS: std::ops::RangeInclusive<u32> = 20..=40;
+
+// Every supported setting. AppConfig::from_layers only reads keys listed here, so --print-config-schema never drifts.
+pub const SETTINGS: &[Setting] = &[
+    Setting {
+        key: "CONFIG_FILE",
+        kind: "path",
+        default: Some(""),
+        description: "File of KEY=value settings, overridden by environment variables and command line flags.",
+    },
+    Setting {
+        key: "SOURCE_DIR",
+        kind: "path",
+        default: None,
+        description: "Local directory watched recursively for csv files.",
+    },
+    Setting {
+        key: "DEST_USER",
+        kind: "string",
+        default: None,
+        description: "SSH user on the destination host.",
+    },
+    Setting {
+        key: "DEST_HOST",
+        kind: "string",
+        default: None,
+        description: "Destination host that files are pushed to.",
+    },
+    Setting {
+        key: "DEST_DIR",
+        kind: "path",
+        default: None,
+        description: "Remote base directory. Files land in DEST_DIR/<table_name>.",
+    },
+    Setting {
+        key: "TEMPLATE_DIR",
+        kind: "path",
+        default: None,
+        description: "Comma separated directories of <table_name>_template.csv files. Later directories override earlier ones.",
+    },
+    Setting {
+        key: "FILE_SUFFIX",
+        kind: "chrono format",
+        default: Some("%Y%m%d%H%M%S%f"),
+        description: "Timestamp suffix appended to source file names before upload.",
+    },
+    Setting {
+        key: "CSV_EVENT_WAIT_SECONDS",
+        kind: "integer",
+        default: Some("5"),
+        description: "Seconds without new csv events before a batch is processed.",
+    },
+    Setting {
+        key: "CSV_EVENT_UPPER_LIMIT",
+        kind: "integer",
+        default: Some("100"),
+        description: "Number of queued csv events that triggers processing immediately.",
+    },
+    Setting {
+        key: "HEADER_ALIASES",
+        kind: "list of source=canonical",
+        default: Some(""),
+        description: "Source field names replaced by canonical names before template matching.",
+    },
+    Setting {
+        key: "LOG_LEVEL",
+        kind: "off|error|warn|info|debug|trace",
+        default: Some("trace"),
+        description: "Console log level. Full rsync output is logged at debug and above.",
+    },
+    Setting {
+        key: "DEST_DATE_FOLDER",
+        kind: "chrono format",
+        default: Some(""),
+        description: "When set, files land in a dated subfolder DEST_DIR/<table_name>/<date>.",
+    },
+    Setting {
+        key: "DEST_DATE_TZ",
+        kind: "IANA timezone",
+        default: Some(""),
+        description: "Timezone used for DEST_DATE_FOLDER. Defaults to the host local timezone.",
+    },
+    Setting {
+        key: "TEMPLATE_HOT_RELOAD",
+        kind: "bool",
+        default: Some("false"),
+        description: "Reload templates when files in TEMPLATE_DIR change.",
+    },
+    Setting {
+        key: "VERIFY_TEMPLATES_BEFORE_UPLOAD",
+        kind: "bool",
+        default: Some("false"),
+        description: "Re-match files before upload if templates were reloaded after matching.",
+    },
+    Setting {
+        key: "TEMPLATE_SUBDIR_ROUTING",
+        kind: "bool",
+        default: Some("false"),
+        description: "Load templates from subfolders and mirror the subfolder into DEST_DIR.",
+    },
+    Setting {
+        key: "RSYNC_SUCCESS_CODES",
+        kind: "list of integers",
+        default: Some(""),
+        description: "Non-zero rsync exit codes treated as success, e.g. 24 for vanished source files.",
+    },
+    Setting {
+        key: "STARTUP_SCAN",
+        kind: "bool",
+        default: Some("false"),
+        description: "Process csv files already in SOURCE_DIR when the watcher starts.",
+    },
+    Setting {
+        key: "TABLE_NAME_CASE",
+        kind: "upper|lower",
+        default: Some(""),
+        description: "Case applied to the table name when building the remote directory.",
+    },
+    Setting {
+        key: "TABLE_NAME_PREFIX",
+        kind: "string",
+        default: Some(""),
+        description: "Prefix added to the table name when building the remote directory.",
+    },
+    Setting {
+        key: "TABLE_NAME_SUFFIX",
+        kind: "string",
+        default: Some(""),
+        description: "Suffix added to the table name when building the remote directory.",
+    },
+    Setting {
+        key: "OTEL_EXPORTER_OTLP_ENDPOINT",
+        kind: "url",
+        default: Some(""),
+        description: "OTLP/HTTP endpoint for per-file OpenTelemetry traces. Requires the otel feature.",
+    },
+    Setting {
+        key: "DEAD_LETTER_DIR",
+        kind: "path",
+        default: Some(""),
+        description: "Directory that files rejected by a dead-letter policy are moved to.",
+    },
+    Setting {
+        key: "EMPTY_FILE_POLICY",
+        kind: "upload|skip|deadletter",
+        default: Some("upload"),
+        description: "What to do with files that match a template but have no data rows.",
+    },
+    Setting {
+        key: "EMPTY_FILE_TABLE_POLICIES",
+        kind: "list of table=policy",
+        default: Some(""),
+        description: "Per-table overrides of EMPTY_FILE_POLICY.",
+    },
+    Setting {
+        key: "UPLOAD_LOG_MTIME",
+        kind: "bool",
+        default: Some("false"),
+        description: "Include the source file's last modified time in upload log success lines.",
+    },
+    Setting {
+        key: "UNMATCHED_MAX_AGE",
+        kind: "integer (seconds)",
+        default: Some(""),
+        description: "When set, csv files matching no template and older than this are cleaned up.",
+    },
+    Setting {
+        key: "UNMATCHED_ACTION",
+        kind: "delete|quarantine",
+        default: Some("quarantine"),
+        description: "Cleanup action for old unmatched files. quarantine moves them to DEAD_LETTER_DIR.",
+    },
+    Setting {
+        key: "UNMATCHED_CHECK_INTERVAL_SECONDS",
+        kind: "integer",
+        default: Some("3600"),
+        description: "How often the source directory is checked for old unmatched files.",
+    },
+    Setting {
+        key: "SCHEMA_SAMPLE_ROWS",
+        kind: "integer",
+        default: Some("100"),
+        description: "Number of data rows checked against the column types of templates that declare them.",
+    },
+    Setting {
+        key: "RSYNC_BINARY",
+        kind: "string",
+        default: Some("rsync"),
+        description: "rsync executable to run, e.g. the full path to cwRsync on Windows.",
+    },
+    Setting {
+        key: "LOCAL_DEST_MODE",
+        kind: "rsync|copy|ssh",
+        default: Some("rsync"),
+        description: "Transfer used when DEST_HOST is this host. ssh keeps the rsync over ssh round trip.",
+    },
+    Setting {
+        key: "COMPRESSION",
+        kind: "rsync|gzip|zstd|none",
+        default: Some("rsync"),
+        description: "How files are compressed for transfer. gzip and zstd compress files before sending them.",
+    },
+    Setting {
+        key: "TABLE_COMPRESSION",
+        kind: "list of table=compression",
+        default: Some(""),
+        description: "Per-table overrides of COMPRESSION.",
+    },
+    Setting {
+        key: "TABLE_DEST_FILENAME_CASE",
+        kind: "list of table=upper|lower",
+        default: Some(""),
+        description: "Per-table case applied to the destination file name. The local file keeps its name.",
+    },
+    Setting {
+        key: "TABLE_DEST_FILENAME_EXTENSION",
+        kind: "list of table=extension",
+        default: Some(""),
+        description: "Per-table extension that replaces the destination file name's extension.",
+    },
+    Setting {
+        key: "TEMPLATE_NAME_REGEX",
+        kind: "regex",
+        default: Some("^(.+)_template$"),
+        description: "Regex applied to template file names (without extension). Its first capture group is the table name.",
+    },
+    Setting {
+        key: "SYSLOG_FACILITY",
+        kind: "syslog facility",
+        default: Some(""),
+        description: "When set (e.g. daemon, local0), logs are also sent to the local syslog with this facility.",
+    },
+    Setting {
+        key: "SYSLOG_TAG",
+        kind: "string",
+        default: Some("rsync_csv"),
+        description: "Process name syslog messages are tagged with.",
+    },
+    Setting {
+        key: "SYSLOG_SEVERITIES",
+        kind: "list of level=severity",
+        default: Some(""),
+        description: "Overrides of the log level to syslog severity mapping, e.g. info=notice.",
+    },
+    Setting {
+        key: "LOG_CONSOLE",
+        kind: "bool",
+        default: Some("true"),
+        description: "Log to the console. Set to false to log to syslog only.",
+    },
+    Setting {
+        key: "TRANSPORT",
+        kind: "rsync|sftp",
+        default: Some("rsync"),
+        description: "How files are sent to DEST_HOST. sftp needs a build with the sftp feature.",
+    },
+    Setting {
+        key: "SFTP_PORT",
+        kind: "integer",
+        default: Some("22"),
+        description: "SSH port of DEST_HOST for the sftp transport.",
+    },
+    Setting {
+        key: "SFTP_IDENTITY_FILE",
+        kind: "path",
+        default: Some(""),
+        description: "Private key for the sftp transport. When unset, the ssh agent is used.",
+    },
+    Setting {
+        key: "SFTP_POOL_SIZE",
+        kind: "integer",
+        default: Some("4"),
+        description: "Maximum idle SSH sessions kept open per destination for reuse.",
+    },
+    Setting {
+        key: "SFTP_IDLE_TIMEOUT_SECONDS",
+        kind: "integer",
+        default: Some("300"),
+        description: "Idle SSH sessions older than this are closed instead of reused.",
+    },
+    Setting {
+        key: "CHECKSUM_ALGORITHM",
+        kind: "sha256|sha512",
+        default: Some(""),
+        description: "When set, a checksum sidecar file is transferred with every csv file.",
+    },
+    Setting {
+        key: "CHECKSUM_SUFFIX",
+        kind: "string",
+        default: Some(""),
+        description: "Suffix appended to the csv file name for its checksum sidecar. Defaults to .sha256 or .sha512.",
+    },
+    Setting {
+        key: "UPLOAD_RETRIES",
+        kind: "integer",
+        default: Some("3"),
+        description: "Times a failed upload is retried before it is logged as failed.",
+    },
+    Setting {
+        key: "UPLOAD_RETRY_BACKOFF_SECONDS",
+        kind: "integer",
+        default: Some("5"),
+        description: "Delay before the first retry of a failed upload, doubled after every further failure.",
+    },
+    Setting {
+        key: "HEADER_SCAN_LINES",
+        kind: "integer",
+        default: Some("1"),
+        description: "Number of leading lines searched for a header matching a template, for files with a preamble.",
+    },
+    Setting {
+        key: "CSV_DELIMITERS",
+        kind: "characters",
+        default: Some(","),
+        description: "Delimiters tried when matching headers, in priority order, e.g. ,;| or \\t for tab.",
+    },
+    Setting {
+        key: "VOLUME_REPORT_DIR",
+        kind: "path",
+        default: Some(""),
+        description: "Directory for daily reports of files and bytes uploaded per table. Days follow DEST_DATE_TZ.",
+    },
+    Setting {
+        key: "SLOW_FILE_WARNING_SECONDS",
+        kind: "integer",
+        default: Some(""),
+        description: "Warn about files whose match and transfer take longer than this. Processing is not aborted.",
+    },
+    Setting {
+        key: "TEMPLATE_NAME_CHARS",
+        kind: "regex character class",
+        default: Some(""),
+        description: "Characters allowed in template table names and header fields, e.g. A-Za-z0-9_. Templates with others are rejected.",
+    },
+    Setting {
+        key: "UPLOAD_LOG_FALLBACK",
+        kind: "path",
+        default: Some(""),
+        description: "Log file for upload statuses that cannot be written to upload.log in the source file's directory.",
+    },
+    Setting {
+        key: "DELETE_STAGING_DIR",
+        kind: "path",
+        default: Some(""),
+        description: "When set, uploaded files are moved here and only deleted after DELETE_CONFIRMATION_SECONDS.",
+    },
+    Setting {
+        key: "DELETE_CONFIRMATION_SECONDS",
+        kind: "integer",
+        default: Some("3600"),
+        description: "How long uploaded files stay in DELETE_STAGING_DIR before they are permanently deleted.",
+    },
+    Setting {
+        key: "HEADER_WRAPPER_CHARS",
+        kind: "string",
+        default: Some(""),
+        description: "Characters stripped from both ends of every csv and template header field before matching, e.g. \"[].",
+    },
+    Setting {
+        key: "RECONCILE_INTERVAL_SECONDS",
+        kind: "integer",
+        default: Some(""),
+        description: "Rescan the source directory this often for matching csv files whose events were missed.",
+    },
+    Setting {
+        key: "RECONCILE_MIN_AGE_SECONDS",
+        kind: "integer",
+        default: Some("300"),
+        description: "Files must be unmodified for this long before a rescan picks them up.",
+    },
+    Setting {
+        key: "TRANSFER_CONCURRENCY_MIN",
+        kind: "integer",
+        default: Some("1"),
+        description: "Fewest tables transferred at once. Concurrency halves towards this on every failed transfer.",
+    },
+    Setting {
+        key: "TRANSFER_CONCURRENCY_MAX",
+        kind: "integer",
+        default: Some("1"),
+        description: "Most tables transferred at once. Concurrency grows by one towards this after a round of successes.",
+    },
+    Setting {
+        key: "UPLOAD_MARKER_PATH",
+        kind: "path",
+        default: Some(""),
+        description: "Marker file replaced after every successful upload of a table. {table} is replaced with the table name.",
+    },
+    Setting {
+        key: "SYMLINK_POLICY",
+        kind: "follow|skip|within",
+        default: Some("follow"),
+        description: "Whether csv files reached through symlinks are processed: always, never, or only if the target is inside SOURCE_DIR.",
+    },
+    Setting {
+        key: "TEMPLATE_LOAD_THREADS",
+        kind: "integer",
+        default: Some("4"),
+        description: "Threads used to read template files when templates are loaded or reloaded.",
+    },
+    Setting {
+        key: "SFTP_STREAM_UPLOADS",
+        kind: "bool",
+        default: Some("false"),
+        description: "Compress files while uploading them over sftp instead of writing a compressed copy to disk first.",
+    },
+    Setting {
+        key: "REMOTE_MKDIR_FAILURE",
+        kind: "fail|retry",
+        default: Some("fail"),
+        description: "When rsync can't create the remote directory, fail the transfer or retry once assuming it exists.",
+    },
+    Setting {
+        key: "QUIET_HOURS",
+        kind: "list of HH:MM-HH:MM",
+        default: Some(""),
+        description: "Daily windows during which csv files are queued instead of transferred, e.g. 18:00-22:00.",
+    },
+    Setting {
+        key: "QUIET_HOURS_TZ",
+        kind: "IANA timezone",
+        default: Some(""),
+        description: "Timezone of QUIET_HOURS. Defaults to the host local timezone.",
+    },
+    Setting {
+        key: "HEADER_MATCH_MODE",
+        kind: "exact|prefix",
+        default: Some("exact"),
+        description: "exact needs the csv headers to equal a template, prefix also accepts extra trailing columns.",
+    },
+    Setting {
+        key: "QUEUE_AGE_REPORT_SECONDS",
+        kind: "integer",
+        default: Some(""),
+        description: "When set, how often the wait times of queued csv files are summarised in the log.",
+    },
+    Setting {
+        key: "REMOTE_MKDIR_RETRIES",
+        kind: "integer",
+        default: Some("0"),
+        description: "Times a transfer is retried right away when only creating the remote directory failed.",
+    },
+    Setting {
+        key: "XLSX_MODE",
+        kind: "off|convert|asis",
+        default: Some("off"),
+        description: "Match the first sheet of .xlsx files and upload it as csv or as the workbook. Requires the xlsx feature.",
+    },
+    Setting {
+        key: "IN_FLIGHT_EVENT_POLICY",
+        kind: "coalesce|drop",
+        default: Some("coalesce"),
+        description: "Events for a file that arrive while it is being processed re-process it once afterwards, or are dropped.",
+    },
+    Setting {
+        key: "TRIGGER_FILE_NAME",
+        kind: "string",
+        default: Some(""),
+        description: "With --once, an empty file of this name is transferred after every file of the run transferred successfully.",
+    },
+    Setting {
+        key: "TRIGGER_FILE_DIR",
+        kind: "path",
+        default: Some(""),
+        description: "Remote directory the trigger file is sent to. Defaults to every table directory that received files in the run.",
+    },
+    Setting {
+        key: "HEADER_UNICODE_FORM",
+        kind: "nfc|nfd|off",
+        default: Some("nfc"),
+        description: "Unicode normalization form csv and template headers are converted to before they are compared.",
+    },
+    Setting {
+        key: "ENCODING_CHECK",
+        kind: "bool",
+        default: Some("false"),
+        description: "Move csv files that don't decode as SOURCE_ENCODING to DEAD_LETTER_DIR instead of uploading them.",
+    },
+    Setting {
+        key: "SOURCE_ENCODING",
+        kind: "encoding",
+        default: Some("utf-8"),
+        description: "Encoding csv files are checked against when ENCODING_CHECK is set.",
+    },
+    Setting {
+        key: "ENCODING_CHECK_BYTES",
+        kind: "integer",
+        default: Some(""),
+        description: "Only the first this many bytes of a file are checked for its encoding. Whole files are checked when unset.",
+    },
+    Setting {
+        key: "CATCHALL_TABLE",
+        kind: "string",
+        default: Some(""),
+        description: "Table that csv files matching no template are uploaded to, instead of being ignored.",
+    },
+    Setting {
+        key: "DEST_AUTH",
+        kind: "agent|key|password",
+        default: Some("agent"),
+        description: "How to authenticate to DEST_HOST: ssh's defaults, DEST_IDENTITY_FILE or the password in DEST_PASSWORD_FILE.",
+    },
+    Setting {
+        key: "DEST_IDENTITY_FILE",
+        kind: "path",
+        default: Some(""),
+        description: "Private key used for DEST_HOST when DEST_AUTH is key.",
+    },
+    Setting {
+        key: "DEST_PASSWORD_FILE",
+        kind: "path",
+        default: Some(""),
+        description: "File holding the password for DEST_HOST when DEST_AUTH is password.",
+    },
+    Setting {
+        key: "ACK_FILE_PATH",
+        kind: "string",
+        default: Some(""),
+        description: "Remote ack file that must appear before an uploaded file is deleted. {dir} and {file} are the upload's remote directory and name.",
+    },
+    Setting {
+        key: "ACK_TABLES",
+        kind: "list of tables",
+        default: Some(""),
+        description: "Tables whose files wait for an ack file. Every table when unset.",
+    },
+    Setting {
+        key: "ACK_POLL_SECONDS",
+        kind: "integer",
+        default: Some("30"),
+        description: "How often the destination is checked for ack files.",
+    },
+    Setting {
+        key: "ACK_TIMEOUT_SECONDS",
+        kind: "integer",
+        default: Some("3600"),
+        description: "Files whose ack file hasn't appeared after this long are kept in place and flagged.",
+    },
+    Setting {
+        key: "OUTPUT_DIR_OVERLAP",
+        kind: "error|exclude",
+        default: Some("exclude"),
+        description: "Output directories inside SOURCE_DIR are excluded from watching, or refused at startup.",
+    },
+    Setting {
+        key: "TRANSFORM_TEMP_DIR",
+        kind: "path",
+        default: Some(""),
+        description: "Directory, e.g. on tmpfs, for compressed copies, checksums and renamed files written for transfers.",
+    },
+    Setting {
+        key: "FIELD_COUNT_CHECK",
+        kind: "bool",
+        default: Some("false"),
+        description: "Quarantine files whose first data row has a different field count than the header.",
+    },
+    Setting {
+        key: "RSYNC_PROTOCOL",
+        kind: "integer",
+        default: Some(""),
+        description: "rsync protocol version passed as --protocol, for mismatched local and remote rsync versions.",
+    },
+    Setting {
+        key: "COMPRESSION_STATS",
+        kind: "bool",
+        default: Some("false"),
+        description: "Log the bytes saved by compression for every transfer and add them to the volume report.",
+    },
+    Setting {
+        key: "DEAD_LETTER_BY_REASON",
+        kind: "bool",
+        default: Some("false"),
+        description: "Sort dead-lettered files into a DEAD_LETTER_DIR subdirectory named after their reason code.",
+    },
+    Setting {
+        key: "DEST_PROBE_SECONDS",
+        kind: "integer",
+        default: Some(""),
+        description: "Interval of a background check that the destination is reachable, for reporting only.",
+    },
+    Setting {
+        key: "DEST_PROBE_STATUS_PATH",
+        kind: "path",
+        default: Some(""),
+        description: "Json file the result of every destination probe is written to.",
+    },
+    Setting {
+        key: "TEMPLATE_SHADOWING",
+        kind: "warn|error",
+        default: Some("warn"),
+        description: "Templates that are a prefix of another under HEADER_MATCH_MODE prefix are warned about, or refused.",
+    },
+    Setting {
+        key: "REMOTE_MKDIR_ONCE",
+        kind: "bool",
+        default: Some("false"),
+        description: "Create each remote table directory once per run instead of running mkdir -p on every transfer.",
+    },
+    Setting {
+        key: "UPLOAD_LOG_FAILURE_LIMIT",
+        kind: "integer",
+        default: Some(""),
+        description: "Stop after this many upload statuses in a row could be written neither to upload.log nor the fallback.",
+    },
+    Setting {
+        key: "SEQUENCE_FILE",
+        kind: "path",
+        default: Some(""),
+        description: "File persisting a sequence number given to every uploaded file, increasing across tables and restarts.",
+    },
+    Setting {
+        key: "SEQUENCE_IN_FILENAME",
+        kind: "bool",
+        default: Some("false"),
+        description: "Prefix remote file names with their zero padded sequence number.",
+    },
+    Setting {
+        key: "CONTENT_SNIFF",
+        kind: "bool",
+        default: Some("false"),
+        description: "Move csv files whose first bytes are clearly not csv, such as gzip data or an HTML page, to DEAD_LETTER_DIR.",
+    },
+    Setting {
+        key: "FILENAME_ROUTING_PATTERN",
+        kind: "regex",
+        default: Some(""),
+        description: "Regex applied to source file names. Its first capture group is the routing key looked up in FILENAME_ROUTES.",
+    },
+    Setting {
+        key: "FILENAME_ROUTES",
+        kind: "list of key=directory",
+        default: Some(""),
+        description: "Destination directory, used instead of DEST_DIR, for the files of each routing key.",
+    },
+    Setting {
+        key: "TEMPLATE_RELOAD_DEBOUNCE_MS",
+        kind: "integer",
+        default: Some("0"),
+        description: "Reload templates once template directory changes have paused for this many milliseconds. 0 reloads on every change.",
+    },
+    Setting {
+        key: "UPLOAD_LOG_RSYNC_COMMAND",
+        kind: "bool",
+        default: Some("false"),
+        description: "Add the failed rsync command, with key and password files redacted, to upload failure statuses.",
+    },
+    Setting {
+        key: "SOURCE_NAMESPACE",
+        kind: "off|dir|label",
+        default: Some("off"),
+        description: "Nest the destination of files from each SOURCE_DIR subdirectory in a folder named after it, or after its label.",
+    },
+    Setting {
+        key: "SOURCE_LABELS",
+        kind: "list of subdirectory=label",
+        default: Some(""),
+        description: "Folder names used for SOURCE_DIR subdirectories when SOURCE_NAMESPACE is label.",
+    },
+    Setting {
+        key: "EVENTS_NATS_URL",
+        kind: "url",
+        default: Some(""),
+        description: "NATS server, as nats://[user:password@|token@]host[:port] or tls://, that an event is published to for every processed file.",
+    },
+    Setting {
+        key: "EVENTS_SUBJECT",
+        kind: "string",
+        default: Some("rsync_csv.files"),
+        description: "NATS subject processed-file events are published to.",
+    },
+    Setting {
+        key: "DRY_RUN",
+        kind: "off|log|rsync",
+        default: Some("off"),
+        description: "Log transfers instead of running them, or run rsync with --dry-run. Source files are never deleted.",
+    },
+    Setting {
+        key: "HEADER_ONLY_POLICY",
+        kind: "upload|skip|deadletter",
+        default: Some(""),
+        description: "What to do with files that end right after their header line, instead of the empty file policy.",
+    },
+    Setting {
+        key: "STARTUP_SCAN_BATCH_SIZE",
+        kind: "integer",
+        default: Some(""),
+        description: "Process the files found by STARTUP_SCAN in batches of this many, taking turns with live batches.",
+    },
+    Setting {
+        key: "VERIFY_PARTIAL_CLEANUP",
+        kind: "bool",
+        default: Some("false"),
+        description: "After every successful rsync transfer, check the destination for partial files rsync left behind.",
+    },
+    Setting {
+        key: "REMOTE_EXISTS_POLICY",
+        kind: "overwrite|skip|version",
+        default: Some("overwrite"),
+        description: "What to do with files whose destination name already exists remotely.",
+    },
+    Setting {
+        key: "REMOTE_EXISTS_TABLE_POLICIES",
+        kind: "list of table=policy",
+        default: Some(""),
+        description: "Per-table overrides of REMOTE_EXISTS_POLICY.",
+    },
+    Setting {
+        key: "FRESHNESS_SLO_SECONDS",
+        kind: "integer",
+        default: Some(""),
+        description: "Freshness SLO threshold, in seconds from a file's modification to its upload.",
+    },
+    Setting {
+        key: "FRESHNESS_SLO_TABLE_SECONDS",
+        kind: "list of table=seconds",
+        default: Some(""),
+        description: "Per-table overrides of FRESHNESS_SLO_SECONDS.",
+    },
+    Setting {
+        key: "FRESHNESS_SLO_WINDOW_SECONDS",
+        kind: "integer",
+        default: Some("3600"),
+        description: "Rolling window freshness SLO compliance is computed over.",
+    },
+    Setting {
+        key: "FRESHNESS_SLO_REPORT_SECONDS",
+        kind: "integer",
+        default: Some("300"),
+        description: "How often freshness SLO compliance is logged.",
+    },
+    Setting {
+        key: "PASSTHROUGH_EXTENSIONS",
+        kind: "list of extensions",
+        default: Some(""),
+        description: "Extensions of files sent as they are, without header matching, e.g. json.",
+    },
+    Setting {
+        key: "PASSTHROUGH_TABLE",
+        kind: "string",
+        default: Some(""),
+        description: "Table whose destination passthrough files are sent to, instead of the last table matched in their batch.",
+    },
+    Setting {
+        key: "BUNDLE_WINDOW_SECONDS",
+        kind: "integer",
+        default: Some(""),
+        description: "Send files sharing a base name as one unit, once none of them was modified for this long.",
+    },
+    Setting {
+        key: "BUNDLE_KEY_PATTERN",
+        kind: "regex",
+        default: Some(""),
+        description: "Regex whose first capture group is the bundle key of a file name, instead of its base name.",
+    },
+    Setting {
+        key: "WATCHER_BACKEND",
+        kind: "auto|inotify|fsevents|kqueue|poll",
+        default: Some("auto"),
+        description: "Notify backend watching SOURCE_DIR, auto picks the platform's native one.",
+    },
+    Setting {
+        key: "SOURCE_MAX_CONCURRENT_FILES",
+        kind: "integer",
+        default: Some(""),
+        description: "Most files of one SOURCE_DIR subdirectory in a batch, the rest wait for the next batch.",
+    },
+    Setting {
+        key: "SOURCE_DIR_MAX_CONCURRENT_FILES",
+        kind: "list of directory=integer",
+        default: Some(""),
+        description: "Per-subdirectory overrides of SOURCE_MAX_CONCURRENT_FILES.",
+    },
+    Setting {
+        key: "RSYNC_SPEEDUP_STATS",
+        kind: "bool",
+        default: Some("false"),
+        description: "Run rsync with --stats and log the speedup of its delta transfer for every table transfer.",
+    },
+    Setting {
+        key: "OUTPUT_METADATA",
+        kind: "list of key=value",
+        default: Some(""),
+        description: "Static key=value fields added to log lines, upload.log, events, dead-letter sidecars and volume reports.",
+    },
+    Setting {
+        key: "TEMPLATE_STABILITY_MS",
+        kind: "integer",
+        default: Some("200"),
+        description: "On reloads, skip templates whose size or mtime changes within this many milliseconds, or with an empty header. 0 disables.",
+    },
+    Setting {
+        key: "RSYNC_TIMEOUT_SECONDS",
+        kind: "integer",
+        default: Some("10"),
+        description: "Seconds without I/O before rsync gives up on a transfer (its --timeout). 0 disables the timeout.",
+    },
+    Setting {
+        key: "RSYNC_TABLE_TIMEOUT_SECONDS",
+        kind: "list of table=seconds",
+        default: Some(""),
+        description: "Per-table overrides of RSYNC_TIMEOUT_SECONDS.",
+    },
+    Setting {
+        key: "SOURCE_FREE_SPACE_MIN_MB",
+        kind: "integer",
+        default: Some(""),
+        description: "Warn when the free space of the source directory's filesystem drops below this many MB.",
+    },
+    Setting {
+        key: "SOURCE_FREE_SPACE_CHECK_SECONDS",
+        kind: "integer",
+        default: Some("60"),
+        description: "Interval of the source free space check.",
+    },
+    Setting {
+        key: "SOURCE_FREE_SPACE_STATUS_PATH",
+        kind: "path",
+        default: Some(""),
+        description: "Json file the result of every source free space check is written to.",
+    },
+    Setting {
+        key: "CONNECTIVITY_PROBE_SECONDS",
+        kind: "integer",
+        default: Some(""),
+        description: "Hold transfers after a network unreachable failure, probing the destination this often until it is back.",
+    },
+    Setting {
+        key: "DELETE_STAGING_COMPRESSION",
+        kind: "gzip|zstd|none",
+        default: Some("none"),
+        description: "How files moved into DELETE_STAGING_DIR are compressed, independently of COMPRESSION: gzip, zstd or none.",
+    },
+    Setting {
+        key: "TRANSFER_STALL_SECONDS",
+        kind: "integer",
+        default: Some(""),
+        description: "Report an rsync transfer whose byte count from --info=progress2 hasn't moved for this many seconds.",
+    },
+    Setting {
+        key: "TRANSFER_STALL_ACTION",
+        kind: "warn|abort",
+        default: Some("warn"),
+        description: "What to do about a transfer stalled for TRANSFER_STALL_SECONDS: warn, or abort it so it is retried.",
+    },
+    Setting {
+        key: "TABLE_REMOTE_DIRS",
+        kind: "list of table=directory",
+        default: Some(""),
+        description: "Remote directory names used instead of the table name, e.g. match_scores=scores.",
+    },
+    Setting {
+        key: "LOCKED_FILE_RETRIES",
+        kind: "integer",
+        default: Some("0"),
+        description: "Extra retries of a file that was locked while rsync read it, not counted against UPLOAD_RETRIES. 0 disables.",
+    },
+    Setting {
+        key: "LOCKED_FILE_RETRY_SECONDS",
+        kind: "integer",
+        default: Some("2"),
+        description: "Delay before a locked file is retried.",
+    },
+    Setting {
+        key: "SPLIT_MAX_ROWS",
+        kind: "integer",
+        default: Some(""),
+        description: "Split csv files with more data rows than this into numbered parts, each with the header, before sending them.",
+    },
+    Setting {
+        key: "MERGE_WINDOW_SECONDS",
+        kind: "integer",
+        default: Some(""),
+        description: "Hold small csv files for up to this many seconds and merge those of the same table into one file before sending.",
+    },
+    Setting {
+        key: "MERGE_SMALL_FILE_BYTES",
+        kind: "integer",
+        default: Some("65536"),
+        description: "Files of at most this many bytes are merged with MERGE_WINDOW_SECONDS.",
+    },
+    Setting {
+        key: "MERGE_MAX_BYTES",
+        kind: "integer",
+        default: Some("10485760"),
+        description: "Size a merged file grows to before the next one is started.",
+    },
+    Setting {
+        key: "VERIFY_REMOTE_WRITABLE",
+        kind: "bool",
+        default: Some("false"),
+        description: "Check that a destination directory is writable with a probe file before the first transfer to it.",
+    },
+    Setting {
+        key: "HEADER_ENCODING",
+        kind: "auto|encoding",
+        default: Some("auto"),
+        description: "Encoding the header lines of csv files are decoded from before they are matched, detected per file with auto.",
+    },
+    Setting {
+        key: "TEMPLATE_ENCODING",
+        kind: "auto|encoding",
+        default: Some("auto"),
+        description: "Encoding template files are decoded from, detected per file with auto.",
+    },
+    Setting {
+        key: "FAILURE_RATE_ALERT_PERCENT",
+        kind: "integer",
+        default: Some(""),
+        description: "Log one alert when more than this percentage of upload attempts fail within FAILURE_RATE_WINDOW_SECONDS.",
+    },
+    Setting {
+        key: "FAILURE_RATE_WINDOW_SECONDS",
+        kind: "integer",
+        default: Some("300"),
+        description: "Rolling window the failure rate is measured over.",
+    },
+    Setting {
+        key: "FAILURE_RATE_MIN_ATTEMPTS",
+        kind: "integer",
+        default: Some("5"),
+        description: "Fewest upload attempts in the window before the failure rate can raise an alert.",
+    },
+    Setting {
+        key: "NO_TEMPLATES_POLICY",
+        kind: "warn|error",
+        default: Some("warn"),
+        description: "Whether starting without any usable template is warned about or refused.",
+    },
+];
+
+#[derive(PartialEq)]
+pub enum EmptyFilePolicy {
+    Upload,
+    Skip,
+    DeadLetter,
+}
+
+impl FromStr for EmptyFilePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "upload" => Ok(EmptyFilePolicy::Upload),
+            "skip" => Ok(EmptyFilePolicy::Skip),
+            "deadletter" | "dead-letter" => Ok(EmptyFilePolicy::DeadLetter),
+            _ => Err(format!("expected upload, skip or deadletter, got {:?}", s)),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+pub enum UnmatchedAction {
+    Delete,
+    Quarantine,
+}
+
+impl FromStr for UnmatchedAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "delete" => Ok(UnmatchedAction::Delete),
+            "quarantine" => Ok(UnmatchedAction::Quarantine),
+            _ => Err(format!("expected delete or quarantine, got {:?}", s)),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+pub enum LocalDestMode {
+    Rsync,
+    Copy,
+    Ssh,
+}
+
+impl FromStr for LocalDestMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "rsync" => Ok(LocalDestMode::Rsync),
+            "copy" => Ok(LocalDestMode::Copy),
+            "ssh" => Ok(LocalDestMode::Ssh),
+            _ => Err(format!("expected rsync, copy or ssh, got {:?}", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Compression {
+    Rsync,
+    Gzip,
+    Zstd,
+    Off,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "rsync" => Ok(Compression::Rsync),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            "none" | "off" => Ok(Compression::Off),
+            _ => Err(format!("expected rsync, gzip, zstd or none, got {:?}", s)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum SyslogSeverity {
+    Emerg,
+    Alert,
+    Crit,
+    Error,
+    Warning,
+    Notice,
+    Info,
+    Debug,
+}
+
+impl FromStr for SyslogSeverity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "emerg" => Ok(SyslogSeverity::Emerg),
+            "alert" => Ok(SyslogSeverity::Alert),
+            "crit" => Ok(SyslogSeverity::Crit),
+            "err" | "error" => Ok(SyslogSeverity::Error),
+            "warning" | "warn" => Ok(SyslogSeverity::Warning),
+            "notice" => Ok(SyslogSeverity::Notice),
+            "info" => Ok(SyslogSeverity::Info),
+            "debug" => Ok(SyslogSeverity::Debug),
+            _ => Err(format!("expected a syslog severity, got {:?}", s)),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+pub enum Transport {
+    Rsync,
+    Sftp,
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "rsync" => Ok(Transport::Rsync),
+            "sftp" => Ok(Transport::Sftp),
+            _ => Err(format!("expected rsync or sftp, got {:?}", s)),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+pub enum DestAuth {
+    // Whatever ssh uses by default, the agent and keys in ~/.ssh
+    Agent,
+    Key,
+    Password,
+}
+
+impl FromStr for DestAuth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "agent" => Ok(DestAuth::Agent),
+            "key" => Ok(DestAuth::Key),
+            "password" => Ok(DestAuth::Password),
+            _ => Err(format!("expected agent, key or password, got {:?}", s)),
+        }
+    }
+}
+
+pub enum ChecksumAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sha256" => Ok(ChecksumAlgorithm::Sha256),
+            "sha512" => Ok(ChecksumAlgorithm::Sha512),
+            _ => Err(format!("expected sha256 or sha512, got {:?}", s)),
+        }
+    }
+}
+
+pub enum NameCase {
+    Upper,
+    Lower,
+}
+
+impl FromStr for NameCase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "upper" | "uppercase" => Ok(NameCase::Upper),
+            "lower" | "lowercase" => Ok(NameCase::Lower),
+            _ => Err(format!("expected upper or lower, got {:?}", s)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SymlinkPolicy {
+    Follow,
+    Skip,
+    Within,
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "skip" => Ok(SymlinkPolicy::Skip),
+            "within" => Ok(SymlinkPolicy::Within),
+            _ => Err(format!("expected follow, skip or within, got {:?}", s)),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+pub enum MkdirFailurePolicy {
+    // Fail the transfer
+    Fail,
+    // Assume the directory already exists and retry once without creating it
+    Retry,
+}
+
+impl FromStr for MkdirFailurePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fail" => Ok(MkdirFailurePolicy::Fail),
+            "retry" => Ok(MkdirFailurePolicy::Retry),
+            _ => Err(format!("expected fail or retry, got {:?}", s)),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+pub enum HeaderMatchMode {
+    // The csv headers must equal the template headers
+    Exact,
+    // The template headers must be the leading csv headers, further csv columns are allowed
+    Prefix,
+}
+
+impl FromStr for HeaderMatchMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "exact" => Ok(HeaderMatchMode::Exact),
+            "prefix" => Ok(HeaderMatchMode::Prefix),
+            _ => Err(format!("expected exact or prefix, got {:?}", s)),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub enum ShadowingPolicy {
+    Warn,
+    // Refuse to start, or keep the previous templates on reload
+    Error,
+}
+
+impl FromStr for ShadowingPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "warn" => Ok(ShadowingPolicy::Warn),
+            "error" => Ok(ShadowingPolicy::Error),
+            _ => Err(format!("expected warn or error, got {:?}", s)),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+pub enum NoTemplatesPolicy {
+    Warn,
+    // Refuse to start
+    Error,
+}
+
+impl FromStr for NoTemplatesPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "warn" => Ok(NoTemplatesPolicy::Warn),
+            "error" => Ok(NoTemplatesPolicy::Error),
+            _ => Err(format!("expected warn or error, got {:?}", s)),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+pub enum XlsxMode {
+    Off,
+    // Upload the first sheet as csv
+    Convert,
+    // Upload the workbook itself
+    Asis,
+}
+
+impl FromStr for XlsxMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(XlsxMode::Off),
+            "convert" => Ok(XlsxMode::Convert),
+            "asis" => Ok(XlsxMode::Asis),
+            _ => Err(format!("expected off, convert or asis, got {:?}", s)),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub enum UnicodeForm {
+    // Composed characters, e.g. é as one code point
+    Nfc,
+    // Decomposed characters, e.g. é as e and a combining acute accent
+    Nfd,
+    Off,
+}
+
+impl FromStr for UnicodeForm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "nfc" => Ok(UnicodeForm::Nfc),
+            "nfd" => Ok(UnicodeForm::Nfd),
+            "off" => Ok(UnicodeForm::Off),
+            _ => Err(format!("expected nfc, nfd or off, got {:?}", s)),
+        }
+    }
+}
+
+// Any encoding label of the WHATWG Encoding Standard, e.g. utf-8, windows-1252 or shift_jis
+pub struct SourceEncoding(pub &'static Encoding);
+
+impl FromStr for SourceEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Encoding::for_label(s.trim().as_bytes())
+            .map(SourceEncoding)
+            .ok_or_else(|| format!("expected an encoding label such as utf-8 or windows-1252, got {:?}", s))
+    }
+}
+
+// `auto`, or any encoding label of the WHATWG Encoding Standard
+#[derive(Clone)]
+pub enum HeaderEncoding {
+    // From the file's BOM, else UTF-8 if it is valid UTF-8, else windows-1252
+    Auto,
+    Label(&'static Encoding),
+}
+
+impl FromStr for HeaderEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().eq_ignore_ascii_case("auto") {
+            return Ok(HeaderEncoding::Auto);
+        }
+        Encoding::for_label(s.trim().as_bytes())
+            .map(HeaderEncoding::Label)
+            .ok_or_else(|| format!("expected auto or an encoding label such as utf-8 or windows-1252, got {:?}", s))
+    }
+}
+
+#[derive(PartialEq)]
+pub enum InFlightPolicy {
+    // Process the file once more after the current processing completes
+    Coalesce,
+    // Ignore the event
+    Drop,
+}
+
+impl FromStr for InFlightPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "coalesce" => Ok(InFlightPolicy::Coalesce),
+            "drop" => Ok(InFlightPolicy::Drop),
+            _ => Err(format!("expected coalesce or drop, got {:?}", s)),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+pub enum OverlapPolicy {
+    // Refuse to start
+    Error,
+    // Ignore files under the output directory
+    Exclude,
+}
+
+impl FromStr for OverlapPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(OverlapPolicy::Error),
+            "exclude" => Ok(OverlapPolicy::Exclude),
+            _ => Err(format!("expected error or exclude, got {:?}", s)),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+pub enum DryRun {
+    Off,
+    // Log the transfers instead of running them
+    Log,
+    // Run rsync with --dry-run, connecting to the destination without transferring anything
+    Rsync,
+}
+
+impl FromStr for DryRun {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(DryRun::Off),
+            "log" => Ok(DryRun::Log),
+            "rsync" => Ok(DryRun::Rsync),
+            _ => Err(format!("expected off, log or rsync, got {:?}", s)),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+pub enum RemoteExistsPolicy {
+    Overwrite,
+    // Leave the file in place instead of sending it
+    Skip,
+    // Send the file under its name with the first free _v<N> counter
+    Version,
+}
+
+impl FromStr for RemoteExistsPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "overwrite" => Ok(RemoteExistsPolicy::Overwrite),
+            "skip" => Ok(RemoteExistsPolicy::Skip),
+            "version" => Ok(RemoteExistsPolicy::Version),
+            _ => Err(format!("expected overwrite, skip or version, got {:?}", s)),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+pub enum WatcherBackend {
+    Auto,
+    Inotify,
+    Fsevents,
+    Kqueue,
+    Poll,
+}
+
+impl FromStr for WatcherBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(WatcherBackend::Auto),
+            "inotify" => Ok(WatcherBackend::Inotify),
+            "fsevents" => Ok(WatcherBackend::Fsevents),
+            "kqueue" => Ok(WatcherBackend::Kqueue),
+            "poll" | "polling" => Ok(WatcherBackend::Poll),
+            _ => Err(format!("expected auto, inotify, fsevents, kqueue or poll, got {:?}", s)),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+pub enum StallAction {
+    // Log the stall and let rsync carry on
+    Warn,
+    // Kill rsync, failing the transfer so it is retried
+    Abort,
+}
+
+impl FromStr for StallAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "warn" => Ok(StallAction::Warn),
+            "abort" => Ok(StallAction::Abort),
+            _ => Err(format!("expected warn or abort, got {:?}", s)),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+pub enum SourceNamespace {
+    // One remote table directory for all sources
+    Off,
+    // Named after the SOURCE_DIR subdirectory the file arrived in
+    Dir,
+    // The subdirectory's SOURCE_LABELS label, or its name if it has none
+    Label,
+}
+
+impl FromStr for SourceNamespace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(SourceNamespace::Off),
+            "dir" => Ok(SourceNamespace::Dir),
+            "label" => Ok(SourceNamespace::Label),
+            _ => Err(format!("expected off, dir or label, got {:?}", s)),
+        }
+    }
+}
+
+// A daily window of wall clock time, wrapping past midnight when it ends before it starts
+pub struct TimeWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_time = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").ok();
+        match s.split_once('-').map(|(start, end)| (parse_time(start), parse_time(end))) {
+            Some((Some(start), Some(end))) if start != end => Ok(TimeWindow { start, end }),
+            _ => Err(format!("expected HH:MM-HH:MM, got {:?}", s)),
+        }
+    }
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+pub struct AppConfig {
+    pub src_dir: String,
+    pub dest_user: String,
+    pub dest_host: String,
+    pub dest_dir: String,
+    pub template_dirs: Vec<String>,
+    pub file_suffix: String,
+    pub csv_event_wait_seconds: u64,
+    pub csv_event_upper_limit: u64,
+    pub header_aliases: HashMap<String, String>,
+    pub log_level: LevelFilter,
+    pub dest_date_folder: Option<String>,
+    pub dest_date_tz: Option<Tz>,
+    pub template_hot_reload: bool,
+    pub verify_templates_before_upload: bool,
+    pub template_subdir_routing: bool,
+    pub rsync_success_codes: Vec<i32>,
+    pub startup_scan: bool,
+    pub table_name_case: Option<NameCase>,
+    pub table_name_prefix: String,
+    pub table_name_suffix: String,
+    pub otel_endpoint: Option<String>,
+    pub dead_letter_dir: Option<String>,
+    pub empty_file_policy: EmptyFilePolicy,
+    pub empty_file_table_policies: HashMap<String, EmptyFilePolicy>,
+    pub upload_log_mtime: bool,
+    pub unmatched_max_age: Option<u64>,
+    pub unmatched_action: UnmatchedAction,
+    pub unmatched_check_interval_seconds: u64,
+    pub schema_sample_rows: usize,
+    pub rsync_binary: String,
+    pub local_dest_mode: LocalDestMode,
+    // Whether DEST_HOST is this host
+    pub dest_is_local: bool,
+    pub compression: Compression,
+    pub table_compression: HashMap<String, Compression>,
+    pub table_dest_filename_case: HashMap<String, NameCase>,
+    // Extensions without the leading dot
+    pub table_dest_filename_extension: HashMap<String, String>,
+    pub template_name_regex: Regex,
+    // Matches a whole name made only of the allowed characters
+    pub template_name_chars: Option<Regex>,
+    pub syslog_facility: Option<Facility>,
+    pub syslog_tag: String,
+    // Lowercase log level name mapped to syslog severity
+    pub syslog_severities: HashMap<String, SyslogSeverity>,
+    pub log_console: bool,
+    pub checksum_algorithm: Option<ChecksumAlgorithm>,
+    pub checksum_suffix: Option<String>,
+    pub upload_retries: u32,
+    pub upload_retry_backoff_seconds: u64,
+    pub header_scan_lines: usize,
+    pub csv_delimiters: Vec<char>,
+    pub transport: Transport,
+    #[cfg_attr(not(feature = "sftp"), allow(dead_code))]
+    pub sftp: SftpSettings,
+    pub volume_report_dir: Option<String>,
+    pub slow_file_warning_seconds: Option<u64>,
+    pub upload_log_fallback: Option<String>,
+    pub delete_staging_dir: Option<String>,
+    pub delete_confirmation_seconds: u64,
+    pub header_wrapper_chars: Vec<char>,
+    pub reconcile_interval_seconds: Option<u64>,
+    pub reconcile_min_age_seconds: u64,
+    pub transfer_concurrency_min: usize,
+    pub transfer_concurrency_max: usize,
+    pub upload_marker_path: Option<String>,
+    pub symlink_policy: SymlinkPolicy,
+    pub template_load_threads: usize,
+    pub remote_mkdir_failure: MkdirFailurePolicy,
+    pub quiet_hours: Vec<TimeWindow>,
+    pub quiet_hours_tz: Option<Tz>,
+    pub header_match_mode: HeaderMatchMode,
+    pub queue_age_report_seconds: Option<u64>,
+    pub remote_mkdir_retries: u32,
+    pub xlsx_mode: XlsxMode,
+    pub in_flight_event_policy: InFlightPolicy,
+    pub trigger_file_name: Option<String>,
+    pub trigger_file_dir: Option<String>,
+    pub header_unicode_form: UnicodeForm,
+    pub encoding_check: bool,
+    pub source_encoding: SourceEncoding,
+    pub encoding_check_bytes: Option<u64>,
+    pub catchall_table: Option<String>,
+    pub dest_auth: DestAuth,
+    pub dest_identity_file: Option<String>,
+    pub dest_password_file: Option<String>,
+    pub ack_file_path: Option<String>,
+    pub ack_tables: Vec<String>,
+    pub ack_poll_seconds: u64,
+    pub ack_timeout_seconds: u64,
+    pub output_dir_overlap: OverlapPolicy,
+    // Output directories inside SOURCE_DIR, by setting, as paths under SOURCE_DIR
+    pub excluded_source_dirs: Vec<(&'static str, PathBuf)>,
+    pub transform_temp_dir: Option<String>,
+    pub field_count_check: bool,
+    pub rsync_protocol: Option<u32>,
+    pub compression_stats: bool,
+    pub dead_letter_by_reason: bool,
+    pub dest_probe_seconds: Option<u64>,
+    pub dest_probe_status_path: Option<String>,
+    pub template_shadowing: ShadowingPolicy,
+    pub remote_mkdir_once: bool,
+    pub upload_log_failure_limit: Option<u32>,
+    pub sequence_file: Option<String>,
+    pub sequence_in_filename: bool,
+    pub content_sniff: bool,
+    pub filename_routing_pattern: Option<Regex>,
+    pub filename_routes: HashMap<String, String>,
+    pub template_reload_debounce_ms: u64,
+    pub upload_log_rsync_command: bool,
+    pub source_namespace: SourceNamespace,
+    pub source_labels: HashMap<String, String>,
+    pub events_nats_url: Option<String>,
+    pub events_subject: String,
+    pub dry_run: DryRun,
+    pub header_only_policy: Option<EmptyFilePolicy>,
+    pub startup_scan_batch_size: Option<usize>,
+    pub verify_partial_cleanup: bool,
+    pub remote_exists_policy: RemoteExistsPolicy,
+    pub remote_exists_table_policies: HashMap<String, RemoteExistsPolicy>,
+    pub freshness_slo_seconds: Option<u64>,
+    pub freshness_slo_table_seconds: HashMap<String, u64>,
+    pub freshness_slo_window_seconds: u64,
+    pub freshness_slo_report_seconds: u64,
+    pub passthrough_extensions: Vec<String>,
+    pub passthrough_table: Option<String>,
+    pub bundle_window_seconds: Option<u64>,
+    pub bundle_key_pattern: Option<Regex>,
+    pub watcher_backend: WatcherBackend,
+    pub source_max_concurrent_files: Option<usize>,
+    pub source_dir_max_concurrent_files: HashMap<String, usize>,
+    pub rsync_speedup_stats: bool,
+    pub output_metadata: HashMap<String, String>,
+    pub template_stability_ms: u64,
+    pub rsync_timeout_seconds: u64,
+    pub rsync_table_timeout_seconds: HashMap<String, u64>,
+    pub source_free_space_min_mb: Option<u64>,
+    pub source_free_space_check_seconds: u64,
+    pub source_free_space_status_path: Option<String>,
+    pub connectivity_probe_seconds: Option<u64>,
+    pub delete_staging_compression: Compression,
+    pub transfer_stall_seconds: Option<u64>,
+    pub transfer_stall_action: StallAction,
+    pub table_remote_dirs: HashMap<String, String>,
+    pub locked_file_retries: u32,
+    pub locked_file_retry_seconds: u64,
+    pub split_max_rows: Option<usize>,
+    pub merge_window_seconds: Option<u64>,
+    pub merge_small_file_bytes: u64,
+    pub merge_max_bytes: u64,
+    pub verify_remote_writable: bool,
+    pub header_encoding: HeaderEncoding,
+    pub template_encoding: HeaderEncoding,
+    pub failure_rate_alert_percent: Option<u64>,
+    pub failure_rate_window_seconds: u64,
+    pub failure_rate_min_attempts: usize,
+    pub no_templates_policy: NoTemplatesPolicy,
+}
+
+// Only read by the sftp transport, which is behind the "sftp" cargo feature
+#[cfg_attr(not(feature = "sftp"), allow(dead_code))]
+pub struct SftpSettings {
+    pub port: u16,
+    pub identity_file: Option<String>,
+    pub pool_size: usize,
+    pub idle_timeout_seconds: u64,
+    // Compress while uploading instead of staging a compressed copy on disk
+    pub stream_uploads: bool,
+}
+
+impl AppConfig {
+    pub fn from_layers(layers: &Layers) -> AppConfig {
+        // Resolve every setting from the layered sources and set rsync src and dest paths
+        AppConfig {
+            src_dir: layers.required_var("SOURCE_DIR"),
+            dest_user: layers.required_var("DEST_USER"),
+            dest_host: layers.required_var("DEST_HOST"),
+            dest_dir: layers.required_var("DEST_DIR"),
+            template_dirs: layers.list_var("TEMPLATE_DIR"),
+            file_suffix: layers.required_var("FILE_SUFFIX"),
+            csv_event_wait_seconds: layers.parse_var("CSV_EVENT_WAIT_SECONDS"),
+            csv_event_upper_limit: layers.parse_var("CSV_EVENT_UPPER_LIMIT"),
+            header_aliases: layers.map_var("HEADER_ALIASES"),
+            log_level: layers.parse_var("LOG_LEVEL"),
+            dest_date_folder: layers.optional_var("DEST_DATE_FOLDER"),
+            dest_date_tz: layers.optional_parse_var("DEST_DATE_TZ"),
+            template_hot_reload: layers.flag_var("TEMPLATE_HOT_RELOAD"),
+            verify_templates_before_upload: layers.flag_var("VERIFY_TEMPLATES_BEFORE_UPLOAD"),
+            template_subdir_routing: layers.flag_var("TEMPLATE_SUBDIR_ROUTING"),
+            rsync_success_codes: layers.list_var("RSYNC_SUCCESS_CODES"),
+            startup_scan: layers.flag_var("STARTUP_SCAN"),
+            table_name_case: layers.optional_parse_var("TABLE_NAME_CASE"),
+            table_name_prefix: layers.optional_var("TABLE_NAME_PREFIX").unwrap_or_default(),
+            table_name_suffix: layers.optional_var("TABLE_NAME_SUFFIX").unwrap_or_default(),
+            otel_endpoint: layers.optional_var("OTEL_EXPORTER_OTLP_ENDPOINT"),
+            dead_letter_dir: layers.optional_var("DEAD_LETTER_DIR"),
+            empty_file_policy: layers.parse_var("EMPTY_FILE_POLICY"),
+            empty_file_table_policies: layers.parsed_map_var("EMPTY_FILE_TABLE_POLICIES"),
+            upload_log_mtime: layers.flag_var("UPLOAD_LOG_MTIME"),
+            unmatched_max_age: layers.optional_parse_var("UNMATCHED_MAX_AGE"),
+            unmatched_action: layers.parse_var("UNMATCHED_ACTION"),
+            unmatched_check_interval_seconds: layers.parse_var("UNMATCHED_CHECK_INTERVAL_SECONDS"),
+            schema_sample_rows: layers.parse_var("SCHEMA_SAMPLE_ROWS"),
+            rsync_binary: layers.required_var("RSYNC_BINARY"),
+            local_dest_mode: layers.parse_var("LOCAL_DEST_MODE"),
+            dest_is_local: is_local_host(&layers.required_var("DEST_HOST")),
+            compression: layers.parse_var("COMPRESSION"),
+            table_compression: layers.parsed_map_var("TABLE_COMPRESSION"),
+            table_dest_filename_case: layers.parsed_map_var("TABLE_DEST_FILENAME_CASE"),
+            table_dest_filename_extension: layers.map_var("TABLE_DEST_FILENAME_EXTENSION")
+                .into_iter()
+                .map(|(table_name, extension)| (table_name, extension.trim_start_matches('.').to_string()))
+                .collect(),
+            template_name_regex: layers.parse_var("TEMPLATE_NAME_REGEX"),
+            template_name_chars: layers.optional_var("TEMPLATE_NAME_CHARS").map(|chars| {
+                Regex::new(&format!("^[{chars}]+$"))
+                    .unwrap_or_else(|e| panic!("Invalid value {:?} for TEMPLATE_NAME_CHARS: {:?}", chars, e))
+            }),
+            syslog_facility: layers.optional_parse_var("SYSLOG_FACILITY"),
+            syslog_tag: layers.required_var("SYSLOG_TAG"),
+            syslog_severities: layers.parsed_map_var::<SyslogSeverity>("SYSLOG_SEVERITIES")
+                .into_iter()
+                .map(|(level, severity)| (level.to_lowercase(), severity))
+                .collect(),
+            log_console: layers.flag_var("LOG_CONSOLE"),
+            checksum_algorithm: layers.optional_parse_var("CHECKSUM_ALGORITHM"),
+            checksum_suffix: layers.optional_var("CHECKSUM_SUFFIX"),
+            upload_retries: layers.parse_var("UPLOAD_RETRIES"),
+            upload_retry_backoff_seconds: layers.parse_var("UPLOAD_RETRY_BACKOFF_SECONDS"),
+            header_scan_lines: layers.parse_var("HEADER_SCAN_LINES"),
+            csv_delimiters: layers.required_var("CSV_DELIMITERS").replace("\\t", "\t").chars().collect(),
+            transport: layers.parse_var("TRANSPORT"),
+            sftp: SftpSettings {
+                port: layers.parse_var("SFTP_PORT"),
+                identity_file: layers.optional_var("SFTP_IDENTITY_FILE"),
+                pool_size: layers.parse_var("SFTP_POOL_SIZE"),
+                idle_timeout_seconds: layers.parse_var("SFTP_IDLE_TIMEOUT_SECONDS"),
+                stream_uploads: layers.flag_var("SFTP_STREAM_UPLOADS"),
+            },
+            volume_report_dir: layers.optional_var("VOLUME_REPORT_DIR"),
+            slow_file_warning_seconds: layers.optional_parse_var("SLOW_FILE_WARNING_SECONDS"),
+            upload_log_fallback: layers.optional_var("UPLOAD_LOG_FALLBACK"),
+            delete_staging_dir: layers.optional_var("DELETE_STAGING_DIR"),
+            delete_confirmation_seconds: layers.parse_var("DELETE_CONFIRMATION_SECONDS"),
+            header_wrapper_chars: layers.optional_var("HEADER_WRAPPER_CHARS").unwrap_or_default().chars().collect(),
+            reconcile_interval_seconds: layers.optional_parse_var("RECONCILE_INTERVAL_SECONDS"),
+            reconcile_min_age_seconds: layers.parse_var("RECONCILE_MIN_AGE_SECONDS"),
+            transfer_concurrency_min: layers.parse_var("TRANSFER_CONCURRENCY_MIN"),
+            transfer_concurrency_max: layers.parse_var("TRANSFER_CONCURRENCY_MAX"),
+            upload_marker_path: layers.optional_var("UPLOAD_MARKER_PATH"),
+            symlink_policy: layers.parse_var("SYMLINK_POLICY"),
+            template_load_threads: layers.parse_var("TEMPLATE_LOAD_THREADS"),
+            remote_mkdir_failure: layers.parse_var("REMOTE_MKDIR_FAILURE"),
+            quiet_hours: layers.list_var("QUIET_HOURS"),
+            quiet_hours_tz: layers.optional_parse_var("QUIET_HOURS_TZ"),
+            header_match_mode: layers.parse_var("HEADER_MATCH_MODE"),
+            queue_age_report_seconds: layers.optional_parse_var("QUEUE_AGE_REPORT_SECONDS"),
+            remote_mkdir_retries: layers.parse_var("REMOTE_MKDIR_RETRIES"),
+            xlsx_mode: layers.parse_var("XLSX_MODE"),
+            in_flight_event_policy: layers.parse_var("IN_FLIGHT_EVENT_POLICY"),
+            trigger_file_name: layers.optional_var("TRIGGER_FILE_NAME"),
+            trigger_file_dir: layers.optional_var("TRIGGER_FILE_DIR"),
+            header_unicode_form: layers.parse_var("HEADER_UNICODE_FORM"),
+            encoding_check: layers.flag_var("ENCODING_CHECK"),
+            source_encoding: layers.parse_var("SOURCE_ENCODING"),
+            encoding_check_bytes: layers.optional_parse_var("ENCODING_CHECK_BYTES"),
+            catchall_table: layers.optional_var("CATCHALL_TABLE"),
+            dest_auth: layers.parse_var("DEST_AUTH"),
+            dest_identity_file: layers.optional_var("DEST_IDENTITY_FILE"),
+            dest_password_file: layers.optional_var("DEST_PASSWORD_FILE"),
+            ack_file_path: layers.optional_var("ACK_FILE_PATH"),
+            ack_tables: layers.list_var("ACK_TABLES"),
+            ack_poll_seconds: layers.parse_var("ACK_POLL_SECONDS"),
+            ack_timeout_seconds: layers.parse_var("ACK_TIMEOUT_SECONDS"),
+            output_dir_overlap: layers.parse_var("OUTPUT_DIR_OVERLAP"),
+            excluded_source_dirs: Vec::new(),
+            transform_temp_dir: layers.optional_var("TRANSFORM_TEMP_DIR"),
+            field_count_check: layers.flag_var("FIELD_COUNT_CHECK"),
+            rsync_protocol: layers.optional_parse_var("RSYNC_PROTOCOL"),
+            compression_stats: layers.flag_var("COMPRESSION_STATS"),
+            dead_letter_by_reason: layers.flag_var("DEAD_LETTER_BY_REASON"),
+            dest_probe_seconds: layers.optional_parse_var("DEST_PROBE_SECONDS"),
+            dest_probe_status_path: layers.optional_var("DEST_PROBE_STATUS_PATH"),
+            template_shadowing: layers.parse_var("TEMPLATE_SHADOWING"),
+            remote_mkdir_once: layers.flag_var("REMOTE_MKDIR_ONCE"),
+            upload_log_failure_limit: layers.optional_parse_var("UPLOAD_LOG_FAILURE_LIMIT"),
+            sequence_file: layers.optional_var("SEQUENCE_FILE"),
+            sequence_in_filename: layers.flag_var("SEQUENCE_IN_FILENAME"),
+            content_sniff: layers.flag_var("CONTENT_SNIFF"),
+            filename_routing_pattern: layers.optional_parse_var("FILENAME_ROUTING_PATTERN"),
+            filename_routes: layers.map_var("FILENAME_ROUTES"),
+            template_reload_debounce_ms: layers.parse_var("TEMPLATE_RELOAD_DEBOUNCE_MS"),
+            upload_log_rsync_command: layers.flag_var("UPLOAD_LOG_RSYNC_COMMAND"),
+            source_namespace: layers.parse_var("SOURCE_NAMESPACE"),
+            source_labels: layers.map_var("SOURCE_LABELS"),
+            events_nats_url: layers.optional_var("EVENTS_NATS_URL"),
+            events_subject: layers.parse_var("EVENTS_SUBJECT"),
+            dry_run: layers.parse_var("DRY_RUN"),
+            header_only_policy: layers.optional_parse_var("HEADER_ONLY_POLICY"),
+            startup_scan_batch_size: layers.optional_parse_var("STARTUP_SCAN_BATCH_SIZE"),
+            verify_partial_cleanup: layers.flag_var("VERIFY_PARTIAL_CLEANUP"),
+            remote_exists_policy: layers.parse_var("REMOTE_EXISTS_POLICY"),
+            remote_exists_table_policies: layers.parsed_map_var("REMOTE_EXISTS_TABLE_POLICIES"),
+            freshness_slo_seconds: layers.optional_parse_var("FRESHNESS_SLO_SECONDS"),
+            freshness_slo_table_seconds: layers.parsed_map_var("FRESHNESS_SLO_TABLE_SECONDS"),
+            freshness_slo_window_seconds: layers.parse_var("FRESHNESS_SLO_WINDOW_SECONDS"),
+            freshness_slo_report_seconds: layers.parse_var("FRESHNESS_SLO_REPORT_SECONDS"),
+            passthrough_extensions: layers
+                .list_var::<String>("PASSTHROUGH_EXTENSIONS")
+                .into_iter()
+                .map(|extension| extension.trim_start_matches('.').to_string())
+                .collect(),
+            passthrough_table: layers.optional_var("PASSTHROUGH_TABLE"),
+            bundle_window_seconds: layers.optional_parse_var("BUNDLE_WINDOW_SECONDS"),
+            bundle_key_pattern: layers.optional_parse_var("BUNDLE_KEY_PATTERN"),
+            watcher_backend: layers.parse_var("WATCHER_BACKEND"),
+            source_max_concurrent_files: layers.optional_parse_var("SOURCE_MAX_CONCURRENT_FILES"),
+            source_dir_max_concurrent_files: layers.parsed_map_var("SOURCE_DIR_MAX_CONCURRENT_FILES"),
+            rsync_speedup_stats: layers.flag_var("RSYNC_SPEEDUP_STATS"),
+            output_metadata: layers.map_var("OUTPUT_METADATA"),
+            template_stability_ms: layers.parse_var("TEMPLATE_STABILITY_MS"),
+            rsync_timeout_seconds: layers.parse_var("RSYNC_TIMEOUT_SECONDS"),
+            rsync_table_timeout_seconds: layers.parsed_map_var("RSYNC_TABLE_TIMEOUT_SECONDS"),
+            source_free_space_min_mb: layers.optional_parse_var("SOURCE_FREE_SPACE_MIN_MB"),
+            source_free_space_check_seconds: layers.parse_var("SOURCE_FREE_SPACE_CHECK_SECONDS"),
+            source_free_space_status_path: layers.optional_var("SOURCE_FREE_SPACE_STATUS_PATH"),
+            connectivity_probe_seconds: layers.optional_parse_var("CONNECTIVITY_PROBE_SECONDS"),
+            delete_staging_compression: layers.parse_var("DELETE_STAGING_COMPRESSION"),
+            transfer_stall_seconds: layers.optional_parse_var("TRANSFER_STALL_SECONDS"),
+            transfer_stall_action: layers.parse_var("TRANSFER_STALL_ACTION"),
+            table_remote_dirs: layers.map_var("TABLE_REMOTE_DIRS"),
+            locked_file_retries: layers.parse_var("LOCKED_FILE_RETRIES"),
+            locked_file_retry_seconds: layers.parse_var("LOCKED_FILE_RETRY_SECONDS"),
+            split_max_rows: layers.optional_parse_var("SPLIT_MAX_ROWS"),
+            merge_window_seconds: layers.optional_parse_var("MERGE_WINDOW_SECONDS"),
+            merge_small_file_bytes: layers.parse_var("MERGE_SMALL_FILE_BYTES"),
+            merge_max_bytes: layers.parse_var("MERGE_MAX_BYTES"),
+            verify_remote_writable: layers.flag_var("VERIFY_REMOTE_WRITABLE"),
+            header_encoding: layers.parse_var("HEADER_ENCODING"),
+            template_encoding: layers.parse_var("TEMPLATE_ENCODING"),
+            failure_rate_alert_percent: layers.optional_parse_var("FAILURE_RATE_ALERT_PERCENT"),
+            failure_rate_window_seconds: layers.parse_var("FAILURE_RATE_WINDOW_SECONDS"),
+            failure_rate_min_attempts: layers.parse_var("FAILURE_RATE_MIN_ATTEMPTS"),
+            no_templates_policy: layers.parse_var("NO_TEMPLATES_POLICY"),
+        }
+        .validated()
+    }
+
+    fn validated(mut self) -> AppConfig {
+        // Reject combinations of settings that cannot work together
+        if self.template_dirs.is_empty() {
+            panic!("Missing required setting TEMPLATE_DIR");
+        }
+        let uses_dead_letter = self.empty_file_policy == EmptyFilePolicy::DeadLetter
+            || self
+                .empty_file_table_policies
+                .values()
+                .any(|policy| *policy == EmptyFilePolicy::DeadLetter)
+            || self.header_only_policy == Some(EmptyFilePolicy::DeadLetter);
+        if uses_dead_letter && self.dead_letter_dir.is_none() {
+            panic!("DEAD_LETTER_DIR must be set when an empty file policy is deadletter");
+        }
+        if self.unmatched_max_age.is_some()
+            && self.unmatched_action == UnmatchedAction::Quarantine
+            && self.dead_letter_dir.is_none()
+        {
+            panic!("DEAD_LETTER_DIR must be set when UNMATCHED_ACTION is quarantine");
+        }
+        if self.encoding_check && self.dead_letter_dir.is_none() {
+            panic!("DEAD_LETTER_DIR must be set when ENCODING_CHECK is enabled");
+        }
+        if self.content_sniff && self.dead_letter_dir.is_none() {
+            panic!("DEAD_LETTER_DIR must be set when CONTENT_SNIFF is enabled");
+        }
+        if self.field_count_check && self.dead_letter_dir.is_none() {
+            panic!("DEAD_LETTER_DIR must be set when FIELD_COUNT_CHECK is enabled");
+        }
+        if let Some(level) = self
+            .syslog_severities
+            .keys()
+            .find(|level| level.parse::<log::Level>().is_err())
+        {
+            panic!("Invalid log level {:?} in SYSLOG_SEVERITIES", level);
+        }
+        if let Some(pattern) = &self.filename_routing_pattern {
+            if pattern.captures_len() < 2 {
+                panic!("FILENAME_ROUTING_PATTERN must have a capture group for the routing key");
+            }
+        } else if !self.filename_routes.is_empty() {
+            panic!("FILENAME_ROUTING_PATTERN must be set when FILENAME_ROUTES is");
+        }
+        if !self.source_labels.is_empty() && self.source_namespace != SourceNamespace::Label {
+            panic!("SOURCE_NAMESPACE must be label when SOURCE_LABELS is set");
+        }
+        if self.template_name_regex.captures_len() < 2 {
+            panic!("TEMPLATE_NAME_REGEX must have a capture group for the table name");
+        }
+        if self.transfer_concurrency_min == 0 || self.transfer_concurrency_min > self.transfer_concurrency_max {
+            panic!("TRANSFER_CONCURRENCY_MIN must be at least 1 and at most TRANSFER_CONCURRENCY_MAX");
+        }
+        if self.sequence_in_filename && self.sequence_file.is_none() {
+            panic!("SEQUENCE_FILE must be set when SEQUENCE_IN_FILENAME is enabled");
+        }
+        if self.startup_scan_batch_size == Some(0) {
+            panic!("STARTUP_SCAN_BATCH_SIZE must be at least 1");
+        }
+        let (backend, supported) = match self.watcher_backend {
+            WatcherBackend::Auto | WatcherBackend::Poll => ("", true),
+            WatcherBackend::Inotify => ("inotify", cfg!(any(target_os = "linux", target_os = "android"))),
+            WatcherBackend::Fsevents => ("fsevents", cfg!(target_os = "macos")),
+            WatcherBackend::Kqueue => (
+                "kqueue",
+                cfg!(any(target_os = "freebsd", target_os = "openbsd", target_os = "netbsd")),
+            ),
+        };
+        if !supported {
+            panic!("WATCHER_BACKEND {} is not supported on {}", backend, env::consts::OS);
+        }
+        if let Some(pattern) = &self.bundle_key_pattern {
+            if pattern.captures_len() < 2 {
+                panic!("BUNDLE_KEY_PATTERN must have a capture group for the bundle key");
+            }
+            if self.bundle_window_seconds.is_none() {
+                panic!("BUNDLE_WINDOW_SECONDS must be set when BUNDLE_KEY_PATTERN is");
+            }
+        }
+        if self.source_max_concurrent_files == Some(0) || self.source_dir_max_concurrent_files.values().any(|limit| *limit == 0) {
+            panic!("SOURCE_MAX_CONCURRENT_FILES and SOURCE_DIR_MAX_CONCURRENT_FILES must be at least 1");
+        }
+        for (key, value) in &self.output_metadata {
+            // Simple strings only, so the fields read the same in log lines, upload.log and json
+            if !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.') {
+                panic!("OUTPUT_METADATA key {:?} may only contain letters, digits, '_', '-' and '.'", key);
+            }
+            if value.chars().any(|c| c.is_control() || c == '"') {
+                panic!("OUTPUT_METADATA value {:?} of {} may not contain control characters or quotes", value, key);
+            }
+        }
+        if matches!(self.delete_staging_compression, Compression::Rsync) {
+            panic!("DELETE_STAGING_COMPRESSION must be gzip, zstd or none, rsync compression only applies to transfers");
+        }
+        for (table_name, alias) in &self.table_remote_dirs {
+            if alias.contains(['/', '\\']) || alias == "." || alias == ".." {
+                panic!("TABLE_REMOTE_DIRS alias {:?} of table {} must be a single directory name", alias, table_name);
+            }
+        }
+        if self.split_max_rows == Some(0) {
+            panic!("SPLIT_MAX_ROWS must be at least 1");
+        }
+        if self.transfer_stall_seconds == Some(0) {
+            panic!("TRANSFER_STALL_SECONDS must be at least 1");
+        }
+        if self.connectivity_probe_seconds == Some(0) {
+            panic!("CONNECTIVITY_PROBE_SECONDS must be at least 1");
+        }
+        if self.source_free_space_check_seconds == 0 {
+            panic!("SOURCE_FREE_SPACE_CHECK_SECONDS must be at least 1");
+        }
+        if self.freshness_slo_window_seconds == 0 || self.freshness_slo_report_seconds == 0 {
+            panic!("FRESHNESS_SLO_WINDOW_SECONDS and FRESHNESS_SLO_REPORT_SECONDS must be at least 1");
+        }
+        if self.failure_rate_alert_percent.is_some_and(|percent| percent >= 100) {
+            panic!("FAILURE_RATE_ALERT_PERCENT must be below 100");
+        }
+        if self.failure_rate_window_seconds == 0 || self.failure_rate_min_attempts == 0 {
+            panic!("FAILURE_RATE_WINDOW_SECONDS and FAILURE_RATE_MIN_ATTEMPTS must be at least 1");
+        }
+        if self.upload_log_failure_limit == Some(0) {
+            panic!("UPLOAD_LOG_FAILURE_LIMIT must be at least 1");
+        }
+        if self.dest_probe_seconds == Some(0) {
+            panic!("DEST_PROBE_SECONDS must be at least 1");
+        }
+        if self.template_load_threads == 0 {
+            panic!("TEMPLATE_LOAD_THREADS must be at least 1");
+        }
+        if self.rsync_protocol.is_some_and(|protocol| !RSYNC_PROTOCOLS.contains(&protocol)) {
+            panic!(
+                "RSYNC_PROTOCOL must be a protocol version from {} to {}",
+                RSYNC_PROTOCOLS.start(),
+                RSYNC_PROTOCOLS.end()
+            );
+        }
+        self.validate_dest_auth();
+        if self.trigger_file_name.as_ref().is_some_and(|name| name.contains('/')) {
+            panic!("TRIGGER_FILE_NAME must be a file name, not a path");
+        }
+        self.excluded_source_dirs = self.output_dirs_in_source();
+        self
+    }
+
+    fn output_dirs_in_source(&self) -> Vec<(&'static str, PathBuf)> {
+        // Output directories and files under SOURCE_DIR would have the tool pick up its own output. An output
+        // directory that is or contains SOURCE_DIR can't be excluded, so it is always refused.
+        let output_dirs = [
+            ("DEAD_LETTER_DIR", &self.dead_letter_dir),
+            ("DELETE_STAGING_DIR", &self.delete_staging_dir),
+            ("VOLUME_REPORT_DIR", &self.volume_report_dir),
+            ("DEST_DIR", &Some(self.dest_dir.clone()).filter(|_| self.dest_is_local)),
+            ("TRANSFORM_TEMP_DIR", &self.transform_temp_dir),
+        ];
+        // A file in a parent of SOURCE_DIR is not watched, only files inside it matter
+        let output_files = [
+            ("UPLOAD_LOG_FALLBACK", &self.upload_log_fallback),
+            ("UPLOAD_MARKER_PATH", &self.upload_marker_path),
+            ("DEST_PROBE_STATUS_PATH", &self.dest_probe_status_path),
+            ("SEQUENCE_FILE", &self.sequence_file),
+            ("SOURCE_FREE_SPACE_STATUS_PATH", &self.source_free_space_status_path),
+        ];
+        let outputs = output_dirs.iter().map(|(key, path)| (key, path, false));
+        let outputs = outputs.chain(output_files.iter().map(|(key, path)| (key, path, true)));
+        let mut inside = Vec::new();
+        for (key, path, is_file) in outputs {
+            let Some(path) = path.as_ref().filter(|path| !path.is_empty()) else {
+                continue;
+            };
+            match source_overlap(Path::new(&self.src_dir), Path::new(path)) {
+                Overlap::Apart => (),
+                Overlap::Contains if is_file => (),
+                Overlap::Contains => panic!("{} {:?} must not be SOURCE_DIR or contain it", key, path),
+                Overlap::Inside(_) if self.output_dir_overlap == OverlapPolicy::Error => {
+                    panic!("{} {:?} is inside SOURCE_DIR, which OUTPUT_DIR_OVERLAP error refuses", key, path)
+                }
+                Overlap::Inside(relative) => inside.push((*key, Path::new(&self.src_dir).join(relative))),
+            }
+        }
+        inside
+    }
+
+    fn validate_dest_auth(&self) {
+        // Fail at startup rather than on the first transfer when DEST_AUTH can't work
+        let (key, mode, file) = match self.dest_auth {
+            DestAuth::Agent => return,
+            DestAuth::Key => ("DEST_IDENTITY_FILE", "key", &self.dest_identity_file),
+            DestAuth::Password => ("DEST_PASSWORD_FILE", "password", &self.dest_password_file),
+        };
+        let Some(file) = file else {
+            panic!("{} must be set when DEST_AUTH is {}", key, mode);
+        };
+        if let Err(e) = fs::File::open(file) {
+            panic!("{} {:?} can't be read: {}", key, file, e);
+        }
+        let uses_ssh = !self.dest_is_local || self.local_dest_mode == LocalDestMode::Ssh;
+        if self.dest_auth == DestAuth::Password && self.transport == Transport::Rsync && uses_ssh {
+            // ssh only reads passwords from a terminal, so rsync goes through sshpass
+            let sshpass = Command::new("sshpass").arg("-V").output();
+            if !sshpass.is_ok_and(|output| output.status.success()) {
+                panic!("DEST_AUTH password with the rsync transport needs sshpass installed");
+            }
+        }
+    }
+}
+
+// How a local output directory relates to SOURCE_DIR
+#[derive(Debug, PartialEq)]
+enum Overlap {
+    Apart,
+    // The directory is SOURCE_DIR or one of its parents
+    Contains,
+    // The directory is under SOURCE_DIR, at this path relative to it
+    Inside(PathBuf),
+}
+
+fn source_overlap(src_dir: &Path, dir: &Path) -> Overlap {
+    let (src_dir, dir) = (resolved_path(src_dir), resolved_path(dir));
+    if src_dir.starts_with(&dir) {
+        Overlap::Contains
+    } else if let Ok(relative) = dir.strip_prefix(&src_dir) {
+        Overlap::Inside(relative.to_path_buf())
+    } else {
+        Overlap::Apart
+    }
+}
+
+fn resolved_path(path: &Path) -> PathBuf {
+    // The canonical path, or for a directory that is only created later that of its closest existing ancestor
+    if let Ok(path) = fs::canonicalize(path) {
+        return path;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => resolved_path(parent).join(name),
+        _ => env::current_dir().unwrap_or_default().join(path),
+    }
+}
+
+fn is_local_host(host: &str) -> bool {
+    // Loopback names and addresses, or the name of this machine
+    if matches!(host.to_lowercase().as_str(), "localhost" | "127.0.0.1" | "::1") {
+        return true;
+    }
+    match Command::new("hostname").output() {
+        Ok(output) if output.status.success() => {
+            let hostname = String::from_utf8_lossy(&output.stdout).trim().to_lowercase();
+            !hostname.is_empty() && hostname == host.to_lowercase()
+        }
+        _ => false,
+    }
+}
+
+pub fn print_config(layers: &Layers) {
+    // Print the resolved value of every setting and the source it came from as json
+    let resolved: Vec<serde_json::Value> = SETTINGS
+        .iter()
+        .map(|setting| {
+            let (value, source) = match layers.resolve(setting.key) {
+                Some((value, source)) => (Some(value), Some(format!("{:?}", source).to_lowercase())),
+                None => (None, None),
+            };
+            serde_json::json!({
+                "key": setting.key,
+                "value": value,
+                "source": source,
+            })
+        })
+        .collect();
+    println!("{}", serde_json::to_string_pretty(&resolved).unwrap());
+}
+
+pub fn print_config_schema() {
+    // Print every supported setting as json
+    let schema: Vec<serde_json::Value> = SETTINGS
+        .iter()
+        .map(|setting| {
+            serde_json::json!({
+                "key": setting.key,
+                "type": setting.kind,
+                "required": setting.default.is_none(),
+                "default": setting.default,
+                "description": setting.description,
+            })
+        })
+        .collect();
+    println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+}
+
+impl Layers {
+    pub fn load(args: &[String]) -> Layers {
+        // Collect settings from command line flags, environment variables (including .env) and the config file
+        dotenv().ok();
+        let cli = parse_cli_args(args);
+        let env: HashMap<String, String> = env::vars().collect();
+        let config_file = [&cli, &env]
+            .iter()
+            .find_map(|layer| layer.get("CONFIG_FILE").filter(|path| !path.trim().is_empty()))
+            .cloned();
+        let file = match config_file {
+            Some(path) => match fs::read_to_string(path.trim()) {
+                Ok(contents) => parse_config_file(&contents),
+                Err(e) => panic!("Failed to read config file {}: {}", path.trim(), e),
+            },
+            None => HashMap::new(),
+        };
+        Layers { cli, env, file }
+    }
+
+    #[cfg(test)]
+    pub fn from_settings(settings: &[(&str, &str)]) -> Layers {
+        // Layers holding only the given settings, as if they were command line flags
+        Layers {
+            cli: settings.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            env: HashMap::new(),
+            file: HashMap::new(),
+        }
+    }
+
+    fn resolve(&self, key: &str) -> Option<(String, Source)> {
+        // Find the value of a setting and where it came from. Empty values count as unset and fall through.
+        let setting = SETTINGS
+            .iter()
+            .find(|setting| setting.key == key)
+            .unwrap_or_else(|| panic!("Setting {} is not defined in SETTINGS", key));
+        [(&self.cli, Source::Cli), (&self.env, Source::Env), (&self.file, Source::File)]
+            .into_iter()
+            .find_map(|(layer, source)| {
+                layer
+                    .get(key)
+                    .map(|value| value.trim())
+                    .filter(|value| !value.is_empty())
+                    .map(|value| (value.to_string(), source))
+            })
+            .or_else(|| {
+                setting
+                    .default
+                    .filter(|default| !default.is_empty())
+                    .map(|default| (default.to_string(), Source::Default))
+            })
+    }
+
+    fn optional_var(&self, key: &str) -> Option<String> {
+        self.resolve(key).map(|(value, _)| value)
+    }
+
+    fn required_var(&self, key: &str) -> String {
+        self.optional_var(key).unwrap_or_else(|| panic!("Missing required setting {}", key))
+    }
+
+    fn parse_var<T: FromStr>(&self, key: &str) -> T
+    where
+        T::Err: Debug,
+    {
+        let value = self.required_var(key);
+        value
+            .parse::<T>()
+            .unwrap_or_else(|e| panic!("Invalid value {:?} for {}: {:?}", value, key, e))
+    }
+
+    fn optional_parse_var<T: FromStr>(&self, key: &str) -> Option<T>
+    where
+        T::Err: Debug,
+    {
+        self.optional_var(key).map(|value| {
+            value
+                .parse::<T>()
+                .unwrap_or_else(|e| panic!("Invalid value {:?} for {}: {:?}", value, key, e))
+        })
+    }
+
+    fn list_var<T: FromStr>(&self, key: &str) -> Vec<T>
+    where
+        T::Err: Debug,
+    {
+        // Read an optional comma separated setting, parsing each entry
+        self.optional_var(key)
+            .unwrap_or_default()
+            .split(',')
+            .map(|entry| entry.trim())
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                entry
+                    .parse::<T>()
+                    .unwrap_or_else(|e| panic!("Invalid entry {:?} in {}: {:?}", entry, key, e))
+            })
+            .collect()
+    }
+
+    fn flag_var(&self, key: &str) -> bool {
+        // Read an optional boolean setting, defaulting to false
+        match self.optional_var(key) {
+            Some(value) => matches!(value.to_lowercase().as_str(), "true" | "1" | "yes"),
+            None => false,
+        }
+    }
+
+    fn parsed_map_var<T: FromStr>(&self, key: &str) -> HashMap<String, T>
+    where
+        T::Err: Debug,
+    {
+        // Parse comma separated "key=value" pairs, parsing every value
+        self.map_var(key)
+            .into_iter()
+            .map(|(k, v)| {
+                let value = v
+                    .parse::<T>()
+                    .unwrap_or_else(|e| panic!("Invalid value for {} in {}: {:?}", k, key, e));
+                (k, value)
+            })
+            .collect()
+    }
+
+    fn map_var(&self, key: &str) -> HashMap<String, String> {
+        // Parse comma separated "key=value" pairs into a hashmap
+        let mut pairs: HashMap<String, String> = HashMap::new();
+        for pair in self.optional_var(key)
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+        {
+            match pair.split_once('=') {
+                Some((k, v)) if !k.trim().is_empty() && !v.trim().is_empty() => {
+                    pairs.insert(k.trim().to_string(), v.trim().to_string());
+                }
+                _ => panic!("Invalid {} entry {:?}. Expected format: key=value", key, pair),
+            }
+        }
+        pairs
+    }
+}
+
+// Command line options that run a command instead of setting a setting, and take a value
+const COMMANDS_WITH_VALUE: &[&str] = &["dump-templates"];
+
+pub fn command_arg(args: &[String], name: &str) -> Option<String> {
+    // Value of a command option given as --name=value or --name value
+    let flag = format!("--{name}");
+    args.iter().enumerate().skip(1).find_map(|(i, arg)| match arg.strip_prefix(&flag) {
+        Some("") => Some(
+            args.get(i + 1)
+                .cloned()
+                .unwrap_or_else(|| panic!("Missing value for {}", flag)),
+        ),
+        Some(value) => value.strip_prefix('=').map(String::from),
+        None => None,
+    })
+}
+
+fn parse_cli_args(args: &[String]) -> HashMap<String, String> {
+    // Turn --some-setting=value or --some-setting value into SOME_SETTING=value. --config is CONFIG_FILE.
+    let mut values: HashMap<String, String> = HashMap::new();
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--print-config" || arg == "--print-config-schema" || arg == "--once" {
+            continue;
+        }
+        let Some(flag) = arg.strip_prefix("--") else {
+            panic!("Unexpected argument {:?}. Settings are passed as --setting-name=value", arg);
+        };
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name, value.to_string()),
+            None => match args.next() {
+                Some(value) => (flag, value.clone()),
+                None => panic!("Missing value for --{}", flag),
+            },
+        };
+        if COMMANDS_WITH_VALUE.contains(&name) {
+            continue;
+        }
+        let key = match name {
+            "config" => "CONFIG_FILE".to_string(),
+            _ => name.replace('-', "_").to_uppercase(),
+        };
+        if !SETTINGS.iter().any(|setting| setting.key == key) {
+            panic!("Unknown option --{}", name);
+        }
+        values.insert(key, value);
+    }
+    values
+}
+
+fn parse_config_file(contents: &str) -> HashMap<String, String> {
+    // Parse KEY=value lines, the same format as .env. Blank lines and # comments are skipped.
+    let mut values: HashMap<String, String> = HashMap::new();
+    for (line_index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                let value = value.trim();
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|value| value.strip_suffix('"'))
+                    .unwrap_or(value);
+                values.insert(key.trim().to_string(), value.to_string());
+            }
+            _ => panic!("Invalid config file line {}: {:?}. Expected format: KEY=value", line_index + 1, line),
+        }
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layers(cli: &[(&str, &str)], env: &[(&str, &str)], file: &[(&str, &str)]) -> Layers {
+        let to_map = |pairs: &[(&str, &str)]| pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        Layers {
+            cli: to_map(cli),
+            env: to_map(env),
+            file: to_map(file),
+        }
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        std::iter::once("rsync_csv").chain(args.iter().copied()).map(String::from).collect()
+    }
+
+    #[test]
+    fn cli_beats_env_file_and_default() {
+        let layers = layers(
+            &[("CSV_EVENT_WAIT_SECONDS", "1")],
+            &[("CSV_EVENT_WAIT_SECONDS", "2")],
+            &[("CSV_EVENT_WAIT_SECONDS", "3")],
+        );
+        assert_eq!(layers.resolve("CSV_EVENT_WAIT_SECONDS"), Some(("1".to_string(), Source::Cli)));
+    }
+
+    #[test]
+    fn env_beats_file_and_default() {
+        let layers = layers(&[], &[("CSV_EVENT_WAIT_SECONDS", "2")], &[("CSV_EVENT_WAIT_SECONDS", "3")]);
+        assert_eq!(layers.resolve("CSV_EVENT_WAIT_SECONDS"), Some(("2".to_string(), Source::Env)));
+    }
+
+    #[test]
+    fn file_beats_default() {
+        let layers = layers(&[], &[], &[("CSV_EVENT_WAIT_SECONDS", "3")]);
+        assert_eq!(layers.resolve("CSV_EVENT_WAIT_SECONDS"), Some(("3".to_string(), Source::File)));
+    }
+
+    #[test]
+    fn default_used_when_unset() {
+        let layers = layers(&[], &[], &[]);
+        assert_eq!(layers.resolve("CSV_EVENT_WAIT_SECONDS"), Some(("5".to_string(), Source::Default)));
+    }
+
+    #[test]
+    fn empty_value_falls_through() {
+        let layers = layers(&[("DEST_HOST", "")], &[("DEST_HOST", "")], &[("DEST_HOST", "from-file")]);
+        assert_eq!(layers.resolve("DEST_HOST"), Some(("from-file".to_string(), Source::File)));
+    }
+
+    #[test]
+    fn required_setting_without_value_is_unset() {
+        let layers = layers(&[], &[], &[]);
+        assert_eq!(layers.resolve("SOURCE_DIR"), None);
+    }
+
+    #[test]
+    fn cli_args_become_setting_keys() {
+        let values = parse_cli_args(&args(&["--dest-host=a", "--dest-user", "b", "--config", "rsync.conf", "--print-config"]));
+        assert_eq!(values.get("DEST_HOST").map(String::as_str), Some("a"));
+        assert_eq!(values.get("DEST_USER").map(String::as_str), Some("b"));
+        assert_eq!(values.get("CONFIG_FILE").map(String::as_str), Some("rsync.conf"));
+        assert_eq!(values.len(), 3);
+    }
+
+    #[test]
+    fn command_options_are_not_settings() {
+        let cli_args = args(&["--dump-templates", "manifest.json", "--dest-host=a"]);
+        assert_eq!(parse_cli_args(&cli_args).len(), 1);
+        assert_eq!(command_arg(&cli_args, "dump-templates").as_deref(), Some("manifest.json"));
+        assert_eq!(command_arg(&args(&["--dump-templates=m.json"]), "dump-templates").as_deref(), Some("m.json"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown option --not-a-setting")]
+    fn unknown_cli_option_panics() {
+        parse_cli_args(&args(&["--not-a-setting=1"]));
+    }
+
+    #[test]
+    fn config_file_skips_comments_and_strips_quotes() {
+        let values = parse_config_file("# comment\n\nDEST_HOST=\"example.com\"\n  DEST_USER = csv \n");
+        assert_eq!(values.get("DEST_HOST").map(String::as_str), Some("example.com"));
+        assert_eq!(values.get("DEST_USER").map(String::as_str), Some("csv"));
+        assert_eq!(values.len(), 2);
+    }
+
+    #[test]
+    fn time_windows_wrap_past_midnight() {
+        let time = |time: &str| NaiveTime::parse_from_str(time, "%H:%M").unwrap();
+        let evening: TimeWindow = "18:00-22:00".parse().unwrap();
+        assert!(evening.contains(time("18:00")));
+        assert!(!evening.contains(time("22:00")));
+        let overnight: TimeWindow = "22:00-06:00".parse().unwrap();
+        assert!(overnight.contains(time("23:30")));
+        assert!(overnight.contains(time("05:59")));
+        assert!(!overnight.contains(time("12:00")));
+        assert!("22:00".parse::<TimeWindow>().is_err());
+        assert!("10:00-10:00".parse::<TimeWindow>().is_err());
+    }
+
+    #[test]
+    fn output_dirs_overlap_source_dir() {
+        let src_dir = Path::new("/nonexistent/export");
+        let overlap = |dir: &str| source_overlap(src_dir, Path::new(dir));
+        assert_eq!(overlap("/nonexistent/export/dead"), Overlap::Inside(PathBuf::from("dead")));
+        assert_eq!(overlap("/nonexistent/export"), Overlap::Contains);
+        assert_eq!(overlap("/nonexistent"), Overlap::Contains);
+        assert_eq!(overlap("/nonexistent/export-dead"), Overlap::Apart);
+    }
+}
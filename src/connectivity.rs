@@ -0,0 +1,87 @@
+// Connectivity waits for destinations behind links that drop, such as a VPN. A transfer that fails because the network
+// is unreachable puts transfers on hold instead of using up a retry: files keep queueing, and the destination is
+// probed every CONNECTIVITY_PROBE_SECONDS until it answers, when the queued and failed files resume.
+// Without CONNECTIVITY_PROBE_SECONDS, every function here is a no-op and network failures are retried like any other.
+use log::{debug, info, warn};
+use std::{
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+// rsync and ssh errors meaning the destination could not be reached at all, compared in lowercase
+const NETWORK_FAILURES: &[&str] = &[
+    "network is unreachable",
+    "no route to host",
+    "could not resolve hostname",
+    "name or service not known",
+    "temporary failure in name resolution",
+    "connection timed out",
+    "operation timed out",
+];
+
+struct Connectivity {
+    interval: Duration,
+    // When the network was lost and the destination last probed, None while it is reachable
+    lost: Option<(Instant, Instant)>,
+}
+
+static CONNECTIVITY: OnceLock<Mutex<Connectivity>> = OnceLock::new();
+
+pub fn init(probe_seconds: Option<u64>) {
+    if let Some(probe_seconds) = probe_seconds {
+        let _ = CONNECTIVITY.set(Mutex::new(Connectivity { interval: Duration::from_secs(probe_seconds), lost: None }));
+    }
+}
+
+pub fn is_network_failure(err_msg: &str) -> bool {
+    // Whether a failed transfer should wait for connectivity rather than use a retry
+    if CONNECTIVITY.get().is_none() {
+        return false;
+    }
+    let err_msg = err_msg.to_lowercase();
+    NETWORK_FAILURES.iter().any(|failure| err_msg.contains(failure))
+}
+
+pub fn lost(err_msg: &str) {
+    let Some(connectivity) = CONNECTIVITY.get() else {
+        return;
+    };
+    let mut connectivity = connectivity.lock().unwrap();
+    if connectivity.lost.is_none() {
+        warn!(
+            "Destination unreachable, holding transfers and probing every {} second(s) until it is back: {}",
+            connectivity.interval.as_secs(),
+            err_msg
+        );
+        connectivity.lost = Some((Instant::now(), Instant::now()));
+    }
+}
+
+pub fn is_online(probe: impl FnOnce() -> Result<(), String>) -> bool {
+    // Whether transfers may run. While on hold, the destination is probed once the interval has passed.
+    let Some(connectivity) = CONNECTIVITY.get() else {
+        return true;
+    };
+    let mut connectivity = connectivity.lock().unwrap();
+    let Some((lost_at, last_probe)) = connectivity.lost else {
+        return true;
+    };
+    if last_probe.elapsed() < connectivity.interval {
+        return false;
+    }
+    match probe() {
+        Ok(_) => {
+            info!(
+                "Destination reachable again after {} second(s), resuming transfers",
+                lost_at.elapsed().as_secs()
+            );
+            connectivity.lost = None;
+            true
+        }
+        Err(e) => {
+            debug!("Destination still unreachable: {}", e);
+            connectivity.lost = Some((lost_at, Instant::now()));
+            false
+        }
+    }
+}
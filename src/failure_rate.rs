@@ -0,0 +1,80 @@
+// Failure spike alerting. Every attempt to upload a file is recorded as it finishes, and when more than
+// FAILURE_RATE_ALERT_PERCENT of the attempts over the last FAILURE_RATE_WINDOW_SECONDS failed, a single alert is
+// logged for the incident instead of one line per failure, followed by a recovery line once the rate drops back.
+// Without FAILURE_RATE_ALERT_PERCENT, every function here is a no-op.
+use log::{debug, error, info};
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+struct FailureRate {
+    threshold_percent: u64,
+    window: Duration,
+    min_attempts: usize,
+    // Outcomes of the attempts in the window, oldest first, true for failures
+    attempts: VecDeque<(Instant, bool)>,
+    alerting: bool,
+}
+
+static FAILURE_RATE: OnceLock<Mutex<FailureRate>> = OnceLock::new();
+
+pub fn init(threshold_percent: Option<u64>, window_seconds: u64, min_attempts: usize) {
+    let Some(threshold_percent) = threshold_percent else {
+        return;
+    };
+    info!(
+        "Alerting when more than {}% of at least {} upload attempt(s) fail within {} second(s)",
+        threshold_percent, min_attempts, window_seconds
+    );
+    let _ = FAILURE_RATE.set(Mutex::new(FailureRate {
+        threshold_percent,
+        window: Duration::from_secs(window_seconds),
+        min_attempts,
+        attempts: VecDeque::new(),
+        alerting: false,
+    }));
+}
+
+pub fn record(succeeded: usize, failed: usize) {
+    // Record the outcomes of a batch and alert or recover when the rate crosses the threshold
+    let Some(failure_rate) = FAILURE_RATE.get() else {
+        return;
+    };
+    if succeeded + failed == 0 {
+        return;
+    }
+    let mut failure_rate = failure_rate.lock().unwrap();
+    let now = Instant::now();
+    failure_rate.attempts.extend((0..succeeded).map(|_| (now, false)).chain((0..failed).map(|_| (now, true))));
+    let window = failure_rate.window;
+    while failure_rate.attempts.front().is_some_and(|(recorded, _)| recorded.elapsed() > window) {
+        failure_rate.attempts.pop_front();
+    }
+    let attempts = failure_rate.attempts.len();
+    let failures = failure_rate.attempts.iter().filter(|(_, failed)| *failed).count();
+    let percent = failures as f64 * 100.0 / attempts as f64;
+    let above = attempts >= failure_rate.min_attempts && percent > failure_rate.threshold_percent as f64;
+    if above && !failure_rate.alerting {
+        error!(
+            "Failure rate alert: {:.1}% of {} upload attempt(s) failed in the last {}s, above {}%",
+            percent,
+            attempts,
+            window.as_secs(),
+            failure_rate.threshold_percent
+        );
+        failure_rate.alerting = true;
+    } else if above {
+        debug!("Failure rate still at {:.1}% of {} upload attempt(s)", percent, attempts);
+    } else if failure_rate.alerting && percent <= failure_rate.threshold_percent as f64 {
+        info!(
+            "Failure rate recovered: {:.1}% of {} upload attempt(s) failed in the last {}s, at or below {}%",
+            percent,
+            attempts,
+            window.as_secs(),
+            failure_rate.threshold_percent
+        );
+        failure_rate.alerting = false;
+    }
+}
@@ -0,0 +1,110 @@
+// Per-table freshness SLO compliance: the time from a file's modification to its successful upload, compared with
+// its table's FRESHNESS_SLO_SECONDS threshold. Every FRESHNESS_SLO_REPORT_SECONDS, the fraction of each table's
+// uploads within the threshold over the last FRESHNESS_SLO_WINDOW_SECONDS is logged.
+// Without a threshold, every function here is a no-op, and tables without one are not tracked.
+use log::{info, warn};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+struct Freshness {
+    default_threshold: Option<Duration>,
+    table_thresholds: HashMap<String, Duration>,
+    window: Duration,
+    interval: Duration,
+    last_report: Instant,
+    // Latencies of each table's uploads in the window, oldest first, with when they were recorded
+    latencies: HashMap<String, VecDeque<(Instant, Duration)>>,
+}
+
+static FRESHNESS: OnceLock<Mutex<Freshness>> = OnceLock::new();
+
+pub fn init(
+    default_seconds: Option<u64>,
+    table_seconds: &HashMap<String, u64>,
+    window_seconds: u64,
+    report_seconds: u64,
+) {
+    if default_seconds.is_none() && table_seconds.is_empty() {
+        return;
+    }
+    info!("Tracking freshness SLO compliance over a {} second window", window_seconds);
+    let _ = FRESHNESS.set(Mutex::new(Freshness {
+        default_threshold: default_seconds.map(Duration::from_secs),
+        table_thresholds: table_seconds
+            .iter()
+            .map(|(table_name, seconds)| (table_name.clone(), Duration::from_secs(*seconds)))
+            .collect(),
+        window: Duration::from_secs(window_seconds),
+        interval: Duration::from_secs(report_seconds),
+        last_report: Instant::now(),
+        latencies: HashMap::new(),
+    }));
+}
+
+pub fn record(table_name: &str, modified: SystemTime) {
+    // Record an uploaded file's latency, from its modification time until now
+    let Some(freshness) = FRESHNESS.get() else {
+        return;
+    };
+    let mut freshness = freshness.lock().unwrap();
+    if freshness.threshold(table_name).is_none() {
+        return;
+    }
+    // A modification time in the future, e.g. from clock skew, counts as no latency
+    let latency = modified.elapsed().unwrap_or_default();
+    freshness
+        .latencies
+        .entry(table_name.to_string())
+        .or_default()
+        .push_back((Instant::now(), latency));
+}
+
+pub fn report() {
+    // Called from the watch loop, logs once the interval has passed for every table with uploads in the window
+    let Some(freshness) = FRESHNESS.get() else {
+        return;
+    };
+    let mut freshness = freshness.lock().unwrap();
+    if freshness.last_report.elapsed() < freshness.interval {
+        return;
+    }
+    freshness.last_report = Instant::now();
+    let window = freshness.window;
+    freshness.latencies.retain(|_, latencies| {
+        while latencies.front().is_some_and(|(recorded, _)| recorded.elapsed() > window) {
+            latencies.pop_front();
+        }
+        !latencies.is_empty()
+    });
+    let mut table_names: Vec<&String> = freshness.latencies.keys().collect();
+    table_names.sort();
+    for table_name in table_names {
+        let threshold = freshness.threshold(table_name).unwrap();
+        let latencies = &freshness.latencies[table_name];
+        let within = latencies.iter().filter(|(_, latency)| *latency <= threshold).count();
+        let slowest = latencies.iter().map(|(_, latency)| *latency).max().unwrap_or_default();
+        let message = format!(
+            "Freshness SLO for table {:?}: {:.1}% of {} upload(s) in the last {}s within {}s (slowest {:.1}s)",
+            table_name,
+            within as f64 * 100.0 / latencies.len() as f64,
+            latencies.len(),
+            window.as_secs(),
+            threshold.as_secs(),
+            slowest.as_secs_f64()
+        );
+        if within < latencies.len() {
+            warn!("{}", message);
+        } else {
+            info!("{}", message);
+        }
+    }
+}
+
+impl Freshness {
+    fn threshold(&self, table_name: &str) -> Option<Duration> {
+        self.table_thresholds.get(table_name).copied().or(self.default_threshold)
+    }
+}
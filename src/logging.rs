@@ -0,0 +1,153 @@
+// Log sinks: the console logger, and optionally the system syslog.
+// Every line is tagged with the run ID, generated once per process start, and the OUTPUT_METADATA fields.
+use crate::config::{AppConfig, SyslogSeverity};
+use log::{error, info, Level, LevelFilter, Log, Metadata, Record};
+use simple_logger::SimpleLogger;
+use std::{
+    collections::HashMap,
+    process,
+    sync::{Mutex, OnceLock},
+};
+use syslog::{Facility, Formatter3164, LoggerBackend};
+
+static RUN_ID: OnceLock<String> = OnceLock::new();
+// OUTPUT_METADATA fields sorted by key, set once at startup
+static OUTPUT_METADATA: OnceLock<Vec<(String, String)>> = OnceLock::new();
+
+struct SyslogLogger {
+    logger: Mutex<syslog::Logger<LoggerBackend, Formatter3164>>,
+    level: LevelFilter,
+    // Log level name (lowercase) mapped to syslog severity
+    severities: HashMap<String, SyslogSeverity>,
+}
+
+impl SyslogLogger {
+    fn severity(&self, level: Level) -> &SyslogSeverity {
+        let default = match level {
+            Level::Error => &SyslogSeverity::Error,
+            Level::Warn => &SyslogSeverity::Warning,
+            Level::Info => &SyslogSeverity::Info,
+            Level::Debug | Level::Trace => &SyslogSeverity::Debug,
+        };
+        self.severities
+            .get(&level.as_str().to_lowercase())
+            .unwrap_or(default)
+    }
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = format!("[{}] {}", record.target(), record.args());
+        let mut logger = self.logger.lock().unwrap();
+        // A failed syslog write cannot be logged anywhere useful, so it is dropped
+        let _ = match self.severity(record.level()) {
+            SyslogSeverity::Emerg => logger.emerg(message),
+            SyslogSeverity::Alert => logger.alert(message),
+            SyslogSeverity::Crit => logger.crit(message),
+            SyslogSeverity::Error => logger.err(message),
+            SyslogSeverity::Warning => logger.warning(message),
+            SyslogSeverity::Notice => logger.notice(message),
+            SyslogSeverity::Info => logger.info(message),
+            SyslogSeverity::Debug => logger.debug(message),
+        };
+    }
+
+    fn flush(&self) {}
+}
+
+// Forwards every record to each of the configured sinks
+struct Sinks(Vec<Box<dyn Log>>);
+
+impl Log for Sinks {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.iter().any(|sink| sink.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        self.0.iter().for_each(|sink| {
+            sink.log(
+                &Record::builder()
+                    .args(format_args!("[run {}{}] {}", run_id(), log_tag_metadata(), record.args()))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            )
+        });
+    }
+
+    fn flush(&self) {
+        self.0.iter().for_each(|sink| sink.flush());
+    }
+}
+
+pub fn run_id() -> &'static str {
+    // Start time and process id, short enough to read and unique enough to tell interleaved runs apart
+    RUN_ID.get_or_init(|| format!("{}-{:04x}", chrono::Local::now().format("%Y%m%d%H%M%S"), process::id() & 0xffff))
+}
+
+pub fn output_metadata() -> &'static [(String, String)] {
+    OUTPUT_METADATA.get().map_or(&[], |fields| fields.as_slice())
+}
+
+pub fn metadata_json() -> Option<serde_json::Value> {
+    // The OUTPUT_METADATA fields as a json object, for the "metadata" field of json records. None without any.
+    let fields = output_metadata();
+    (!fields.is_empty()).then(|| {
+        serde_json::Value::Object(
+            fields.iter().map(|(key, value)| (key.clone(), serde_json::Value::from(value.as_str()))).collect(),
+        )
+    })
+}
+
+fn log_tag_metadata() -> String {
+    output_metadata().iter().map(|(key, value)| format!(" {key}={value}")).collect()
+}
+
+pub fn init(cfg: &AppConfig) {
+    // Install the console and syslog sinks. Without SYSLOG_FACILITY this is the plain console logger.
+    let mut output_metadata: Vec<(String, String)> =
+        cfg.output_metadata.iter().map(|(key, value)| (key.clone(), value.clone())).collect();
+    output_metadata.sort();
+    let _ = OUTPUT_METADATA.set(output_metadata);
+    let mut sinks: Vec<Box<dyn Log>> = Vec::new();
+    let mut syslog_error = None;
+    if let Some(facility) = cfg.syslog_facility {
+        match connect_syslog(facility, &cfg.syslog_tag) {
+            Ok(logger) => sinks.push(Box::new(SyslogLogger {
+                logger: Mutex::new(logger),
+                level: cfg.log_level,
+                severities: cfg.syslog_severities.clone(),
+            })),
+            Err(e) => syslog_error = Some(e),
+        }
+    }
+    // Always keep the console if syslog is unavailable, so logs are not silently lost
+    if cfg.log_console || sinks.is_empty() {
+        sinks.insert(0, Box::new(SimpleLogger::new().with_level(cfg.log_level)));
+    }
+    log::set_boxed_logger(Box::new(Sinks(sinks))).unwrap();
+    log::set_max_level(cfg.log_level);
+    info!("Starting rsync_csv {} with run ID {}", env!("CARGO_PKG_VERSION"), run_id());
+    if let Some(e) = syslog_error {
+        error!("Failed to connect to syslog, logging to console only. Error: {}", e);
+    }
+}
+
+fn connect_syslog(facility: Facility, tag: &str) -> syslog::Result<syslog::Logger<LoggerBackend, Formatter3164>> {
+    let formatter = Formatter3164 {
+        facility,
+        hostname: None,
+        process: tag.to_string(),
+        pid: process::id(),
+    };
+    syslog::unix(formatter)
+}
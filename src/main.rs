@@ -1,67 +1,168 @@
+mod ack;
+mod bundles;
+mod concurrency;
+mod config;
+mod connectivity;
+mod events;
+mod failure_rate;
+mod freshness;
+mod logging;
+mod preambles;
+mod probe;
+mod queue_age;
+mod remote_dirs;
+mod remote_exists;
+mod remote_writable;
+mod retry;
+mod sequence;
+mod sftp;
+mod slow_files;
+mod source_space;
+mod telemetry;
+mod templates;
+mod volume;
+mod xlsx;
+
 use chrono::{self, TimeZone};
-use dotenv::dotenv;
-use log::{debug, error, info};
+use config::{
+    AppConfig, ChecksumAlgorithm, Compression, DestAuth, DryRun, EmptyFilePolicy, HeaderEncoding, HeaderMatchMode, InFlightPolicy,
+    LocalDestMode, MkdirFailurePolicy, NameCase, NoTemplatesPolicy, RemoteExistsPolicy, ShadowingPolicy, SourceEncoding, SourceNamespace, StallAction, SymlinkPolicy, Transport, UnmatchedAction, WatcherBackend, XlsxMode,
+};
+use encoding_rs::{DecoderResult, Encoding};
+use flate2::write::GzEncoder;
+use log::{debug, error, info, warn};
 use notify::{
-    event::{CreateKind, DataChange, ModifyKind},
-    Config, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+    event::{CreateKind, DataChange, ModifyKind, RenameMode},
+    Config, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher,
 };
-use simple_logger::SimpleLogger;
+use ack::AckOutcome;
+use retry::{PendingFile, RetryQueue};
+use regex::Regex;
+use sha2::{Digest, Sha256, Sha512};
+use templates::{clean_headers, decoded_lines, load_headers, watch_template_dir, ColumnType, LoadOptions, SharedTemplates};
 use std::{
-    collections::HashMap,
-    env,
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     fs::{self, File},
     io::{BufRead, BufReader, Read, Write},
     net::TcpStream,
-    os::unix::fs::MetadataExt,
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Output, Stdio},
     sync::mpsc::channel,
     sync::mpsc::TryRecvError::Empty,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    thread,
     time::Duration,
     time::Instant,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-fn watch_for_file_changes(
-    src_dir: String,
-    dest_user: String,
-    dest_host: String,
-    dest_dir: String,
-    hashmap: HashMap<String, String>,
-    file_suffix: String,
-    csv_event_wait_seconds: u64,
-    csv_event_upper_limit: u64,
-) -> notify::Result<()> {
+type RsyncHashmap = HashMap<String, HashMap<String, Vec<String>>>;
+// Directory, relative to the destination, where rsync keeps partially transferred files
+const RSYNC_PARTIAL_DIR: &str = "tmp";
+// Pause between attempts to create the remote directory, see REMOTE_MKDIR_RETRIES
+const REMOTE_MKDIR_RETRY_DELAY: Duration = Duration::from_secs(1);
+// Bytes read from the start of each file by CONTENT_SNIFF
+const CONTENT_SNIFF_BYTES: usize = 512;
+
+fn watch_for_file_changes(cfg: &AppConfig, templates: SharedTemplates) -> notify::Result<()> {
     let (tx, rx) = channel();
 
     // Initialize watcher, set poll interval and watch path
-    let mut watcher = RecommendedWatcher::new(
-        tx,
-        Config::default().with_poll_interval(Duration::from_secs(2)),
-    )
-    .unwrap();
+    let mut watcher = new_watcher(cfg, tx, Config::default().with_poll_interval(Duration::from_secs(2)))?;
 
     // If watcher errors out, log error and return
-    if let Err(err) = watcher.watch(src_dir.as_ref(), RecursiveMode::Recursive) {
+    if let Err(err) = watcher.watch(cfg.src_dir.as_ref(), RecursiveMode::Recursive) {
         error!("Failed to watch directory: {:?}", err);
         Err(err)?;
     }
 
     let mut event_vec: Vec<notify::Event> = Vec::new();
     let mut last_event_time = Instant::now();
+    // Files renamed by this tool when suffixing, so their rename events are not treated as new files
+    let mut suffixed_files: HashSet<PathBuf> = HashSet::new();
+    // Files of the last processed batch. Events for them still in the channel arrived while they were being processed.
+    let mut in_flight: HashSet<PathBuf> = HashSet::new();
+
+    let mut last_unmatched_check = Instant::now();
+    let mut last_staging_purge = Instant::now();
+    let mut last_reconcile = Instant::now();
+    let mut quiet = false;
+    let mut retry_queue = RetryQueue::new(
+        cfg.upload_retries,
+        Duration::from_secs(cfg.upload_retry_backoff_seconds),
+    );
+
+    // Startup scan files not yet handed to a batch, with STARTUP_SCAN_BATCH_SIZE
+    let mut startup_backlog: VecDeque<notify::Event> = VecDeque::new();
+    if cfg.startup_scan {
+        info!("Scanning source directory for existing csv files...");
+        scan_source_dir(cfg, Path::new(&cfg.src_dir), &mut event_vec);
+        info!("Startup scan queued {} csv file(s)", event_vec.len());
+        for event in &event_vec {
+            queue_age::enqueued(&event.paths[0]);
+        }
+        if let Some(batch_size) = cfg.startup_scan_batch_size.filter(|batch_size| event_vec.len() > *batch_size) {
+            info!("Processing the startup backlog in batches of {} file(s) between live batches", batch_size);
+            startup_backlog.extend(event_vec.drain(..));
+        }
+    }
 
     loop {
         match rx.try_recv() {
             Ok(res) => match res {
+                Ok(event) if event.need_rescan() => {
+                    // Events were dropped (e.g. inotify queue overflow), so look for files that may have been missed
+                    warn!("Watcher requested a rescan, events may have been missed. Rescanning source directory...");
+                    let queued_count = event_vec.len();
+                    scan_source_dir(cfg, Path::new(&cfg.src_dir), &mut event_vec);
+                    info!("Rescan queued {} csv file(s)", event_vec.len() - queued_count);
+                    for event in &event_vec[queued_count..] {
+                        queue_age::enqueued(&event.paths[0]);
+                    }
+                    last_event_time = Instant::now();
+                }
                 Ok(event) => match event.kind {
-                    EventKind::Create(CreateKind::File)
-                    | EventKind::Modify(ModifyKind::Data(DataChange::Any)) => {
-                        if event.paths[0].extension().and_then(|s| s.to_str()) == Some("csv") {
+                    // The polling backend reports renames as a removal and a creation
+                    EventKind::Modify(ModifyKind::Name(RenameMode::To | RenameMode::Any))
+                    | EventKind::Create(CreateKind::Any)
+                        if suffixed_files.remove(&event.paths[0]) =>
+                    {
+                        debug!("Ignoring rename event for suffixed file: {:?}", event.paths[0]);
+                    }
+                    EventKind::Create(CreateKind::File | CreateKind::Any)
+                    | EventKind::Modify(ModifyKind::Data(DataChange::Any))
+                    | EventKind::Modify(ModifyKind::Name(RenameMode::To | RenameMode::Any)) => {
+                        if is_rsync_temp_file(Path::new(&cfg.src_dir), &event.paths[0]) {
+                            debug!("Ignoring rsync temporary file: {:?}", event.paths[0]);
+                        } else if is_excluded(cfg, &event.paths[0]) {
+                            debug!("Ignoring event in excluded output directory: {:?}", event.paths[0]);
+                        } else if in_flight.contains(&event.paths[0]) {
+                            match cfg.in_flight_event_policy {
+                                InFlightPolicy::Coalesce if !event_vec.iter().any(|queued| queued.paths[0] == event.paths[0]) => {
+                                    info!("File {:?} changed while it was being processed, processing it again", event.paths[0]);
+                                    queue_age::enqueued(&event.paths[0]);
+                                    event_vec.push(event.clone());
+                                    last_event_time = Instant::now();
+                                }
+                                InFlightPolicy::Coalesce => {
+                                    debug!("Coalescing event for file already queued to process again: {:?}", event.paths[0]);
+                                }
+                                InFlightPolicy::Drop => {
+                                    info!("Dropping event for {:?}, which arrived while the file was being processed", event.paths[0]);
+                                }
+                            }
+                        } else if is_source_file(cfg, &event.paths[0]) {
                             info!("CSV file event detected: {:?}", event);
+                            queue_age::enqueued(&event.paths[0]);
                             event_vec.push(event.clone());
                             last_event_time = Instant::now();
                         }
                         if let Ok(metadata) = fs::symlink_metadata(&event.paths[0]) {
-                            if metadata.file_type().is_symlink() {
+                            if metadata.file_type().is_symlink() && symlink_allowed(cfg, &event.paths[0]) {
                                 info!("Detected symlink creation, adding it to watcher...");
                                 if let Err(e) = watcher.watch(&event.paths[0], RecursiveMode::NonRecursive) {
                                     error!("Error watching symlink file: {:?}", e);
@@ -74,318 +175,3401 @@ fn watch_for_file_changes(
                 Err(e) => error!("Watch error: {:?}", e),
             },
             Err(e) => {
-                if e == Empty {
-                    ();
-                } else {
+                if e != Empty {
                     error!("Error receiving event: {:?}", e);
                 }
+                // Every event from while the last batch was processed has been received
+                in_flight.clear();
+            }
+        }
+        if in_quiet_hours(cfg) != quiet {
+            quiet = !quiet;
+            if quiet {
+                info!("Quiet hours started, queueing csv files until they end");
+            } else {
+                let queued_files: HashSet<&PathBuf> = event_vec.iter().map(|event| &event.paths[0]).collect();
+                info!("Quiet hours ended, transferring {} queued csv file(s)", queued_files.len());
+            }
+        }
+        // Without connectivity, files queue like in quiet hours
+        let on_hold = quiet || !connectivity::is_online(|| probe_target(cfg).probe());
+        if !on_hold {
+            retry_due_files(cfg, &templates, &mut retry_queue);
+        }
+        volume::rotate();
+        queue_age::report();
+        freshness::report();
+        poll_acks(cfg);
+        if let Some(interval) = cfg.reconcile_interval_seconds.filter(|_| startup_backlog.is_empty()) {
+            if last_reconcile.elapsed().as_secs() >= interval {
+                if reconcile_source_dir(cfg, &templates, &mut retry_queue, &mut event_vec) > 0 {
+                    last_event_time = Instant::now();
+                }
+                last_reconcile = Instant::now();
+            }
+        }
+        if let Some(staging_dir) = &cfg.delete_staging_dir {
+            if last_staging_purge.elapsed().as_secs() >= 60 {
+                purge_deletion_staging(cfg, staging_dir);
+                last_staging_purge = Instant::now();
+            }
+        }
+        if let Some(max_age) = cfg.unmatched_max_age {
+            if last_unmatched_check.elapsed().as_secs() > cfg.unmatched_check_interval_seconds {
+                clean_unmatched_files(cfg, &templates, max_age);
+                last_unmatched_check = Instant::now();
+            }
+        }
+        if (last_event_time.elapsed().as_secs() > cfg.csv_event_wait_seconds
+            || event_vec.len() > cfg.csv_event_upper_limit as usize)
+            && !event_vec.is_empty()
+            && !on_hold
+        {
+            let (batch, held) = split_held_bundles(cfg, std::mem::take(&mut event_vec));
+            let (batch, deferred) = split_source_overflow(cfg, batch);
+            let (batch, merging) = split_held_small_files(cfg, batch);
+            event_vec = deferred.into_iter().chain(held).chain(merging).collect();
+            if batch.is_empty() {
+                // Every queued file waits for its bundle, check again after the next wait
+                last_event_time = Instant::now();
+                continue;
+            }
+            // Mark the batch in flight whether or not handling it succeeds, in_flight is cleared once the channel is drained
+            in_flight.extend(batch.iter().map(|event| event.paths[0].clone()));
+            if let Err(e) = handle_csv_file_event(cfg, &templates, &batch, &mut suffixed_files, &mut retry_queue) {
+                error!("Error handling csv file event: {:?}", e);
+                event_vec.extend(batch);
+            }
+        } else if !startup_backlog.is_empty() && !on_hold {
+            // Backlog batches go through the same pipeline as live ones, taking turns with them.
+            // Files that were handled or removed since the scan are dropped.
+            let batch_size = cfg.startup_scan_batch_size.unwrap_or(usize::MAX);
+            let mut batch: Vec<notify::Event> = Vec::new();
+            while batch.len() < batch_size {
+                let Some(event) = startup_backlog.pop_front() else {
+                    break;
+                };
+                if event.paths[0].exists() && !retry_queue.is_pending(&event.paths[0].to_string_lossy()) {
+                    batch.push(event);
+                }
+            }
+            in_flight.extend(batch.iter().map(|event| event.paths[0].clone()));
+            if !batch.is_empty() {
+                if let Err(e) = handle_csv_file_event(cfg, &templates, &batch, &mut suffixed_files, &mut retry_queue) {
+                    error!("Error handling startup backlog batch: {:?}", e);
+                }
+            }
+            info!("Startup backlog: {} file(s) processed, {} remaining", batch.len(), startup_backlog.len());
+        }
+    }
+}
+
+type EventSender = std::sync::mpsc::Sender<notify::Result<notify::Event>>;
+
+fn new_watcher(cfg: &AppConfig, tx: EventSender, config: Config) -> notify::Result<Box<dyn Watcher>> {
+    // The notify backend WATCHER_BACKEND asks for. AppConfig::validated refuses backends this platform lacks.
+    match cfg.watcher_backend {
+        WatcherBackend::Auto => boxed_watcher::<RecommendedWatcher>(tx, config),
+        WatcherBackend::Poll => boxed_watcher::<PollWatcher>(tx, config),
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        WatcherBackend::Inotify => boxed_watcher::<notify::INotifyWatcher>(tx, config),
+        #[cfg(target_os = "macos")]
+        WatcherBackend::Fsevents => boxed_watcher::<notify::FsEventWatcher>(tx, config),
+        #[cfg(any(target_os = "freebsd", target_os = "openbsd", target_os = "netbsd"))]
+        WatcherBackend::Kqueue => boxed_watcher::<notify::KqueueWatcher>(tx, config),
+        #[allow(unreachable_patterns)]
+        _ => Err(notify::Error::generic("WATCHER_BACKEND is not supported on this platform")),
+    }
+}
+
+fn boxed_watcher<W: Watcher + 'static>(tx: EventSender, config: Config) -> notify::Result<Box<dyn Watcher>> {
+    let watcher = W::new(tx, config)?;
+    info!("Watching for file changes with the {:?} notify backend", W::kind());
+    Ok(Box::new(watcher))
+}
+
+fn reconcile_source_dir(
+    cfg: &AppConfig,
+    templates: &SharedTemplates,
+    retry_queue: &mut RetryQueue,
+    event_vec: &mut Vec<notify::Event>,
+) -> usize {
+    // Queue matching csv files that have sat unprocessed for RECONCILE_MIN_AGE_SECONDS, in case their events were missed.
+    // Files already queued, waiting for a retry or that recently ran out of retries are skipped.
+    let mut found: Vec<notify::Event> = Vec::new();
+    scan_source_dir(cfg, Path::new(&cfg.src_dir), &mut found);
+    let min_age = Duration::from_secs(cfg.reconcile_min_age_seconds);
+    let current_templates = templates.read().unwrap();
+    let mut queued_count = 0;
+    for event in found {
+        let csv_path = event.paths[0].to_str().unwrap();
+        let old_enough = fs::metadata(csv_path)
+            .and_then(|attr| attr.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age >= min_age);
+        if !old_enough
+            || event_vec.iter().any(|queued| queued.paths[0] == event.paths[0])
+            || retry_queue.is_pending(csv_path)
+            || retry_queue.gave_up_within(csv_path, min_age)
+            || ack::is_held(csv_path)
+        {
+            continue;
+        }
+        // Files that match no template are left to UNMATCHED_MAX_AGE, unless they go to the catch-all table
+        if cfg.catchall_table.is_none()
+            && !matches!(find_header(csv_path, &current_templates.headers, cfg), Ok(Some(_)))
+        {
+            continue;
+        }
+        warn!("Reconciliation found unprocessed file {}, queueing it", csv_path);
+        queue_age::enqueued(&event.paths[0]);
+        event_vec.push(event);
+        queued_count += 1;
+    }
+    if queued_count > 0 {
+        info!("Reconciliation queued {} missed csv file(s)", queued_count);
+    } else {
+        debug!("Reconciliation found no missed csv files");
+    }
+    queued_count
+}
+
+fn run_once(cfg: &AppConfig, templates: &SharedTemplates) -> std::io::Result<()> {
+    // Transfer the csv files already in the source directory, waiting out their retries and acks.
+    // Then send the trigger file.
+    let mut event_vec: Vec<notify::Event> = Vec::new();
+    scan_source_dir(cfg, Path::new(&cfg.src_dir), &mut event_vec);
+    info!("Processing {} csv file(s) once", event_vec.len());
+    let mut suffixed_files: HashSet<PathBuf> = HashSet::new();
+    let mut retry_queue = RetryQueue::new(
+        cfg.upload_retries,
+        Duration::from_secs(cfg.upload_retry_backoff_seconds),
+    );
+    let mut uploaded_tables = if event_vec.is_empty() {
+        HashSet::new()
+    } else {
+        handle_csv_file_event(cfg, templates, &event_vec, &mut suffixed_files, &mut retry_queue)?
+    };
+    while !retry_queue.is_empty() {
+        thread::sleep(Duration::from_secs(1));
+        if connectivity::is_online(|| probe_target(cfg).probe()) {
+            uploaded_tables.extend(retry_due_files(cfg, templates, &mut retry_queue));
+        }
+    }
+    while ack::is_waiting() {
+        thread::sleep(Duration::from_secs(1));
+        poll_acks(cfg);
+    }
+    if let Some(trigger_file_name) = &cfg.trigger_file_name {
+        let failed_files = retry_queue.gave_up_files();
+        if failed_files.is_empty() {
+            send_trigger_file(cfg, templates, trigger_file_name, &uploaded_tables)?;
+        } else {
+            warn!(
+                "Withholding trigger file {}, {} file(s) failed to transfer: {:?}",
+                trigger_file_name,
+                failed_files.len(),
+                failed_files
+            );
+        }
+    }
+    Ok(())
+}
+
+fn send_trigger_file(
+    cfg: &AppConfig,
+    templates: &SharedTemplates,
+    trigger_file_name: &str,
+    uploaded_tables: &HashSet<String>,
+) -> std::io::Result<()> {
+    // Send an empty trigger file to TRIGGER_FILE_DIR, or to every table directory that received files
+    let remote_dirs: Vec<PathBuf> = match &cfg.trigger_file_dir {
+        Some(trigger_file_dir) => vec![PathBuf::from(trigger_file_dir)],
+        None => {
+            let mut table_names: Vec<&String> = uploaded_tables.iter().collect();
+            table_names.sort();
+            table_names
+                .into_iter()
+                .map(|table_name| remote_table_dir(cfg, templates, table_name))
+                .collect()
+        }
+    };
+    if remote_dirs.is_empty() {
+        info!("No files were transferred, not sending trigger file {}", trigger_file_name);
+        return Ok(());
+    }
+    let trigger_dir = temp_dir(cfg).join(format!("rsync_csv-trigger-{}", std::process::id()));
+    fs::create_dir_all(&trigger_dir)?;
+    let trigger_path = trigger_dir.join(trigger_file_name).to_string_lossy().to_string();
+    fs::write(&trigger_path, "")?;
+    for remote_dir in remote_dirs {
+        match send_files(cfg, None, &[&trigger_path], &remote_dir, false) {
+            Ok(Transfer::Succeeded(..)) => info!("Sent trigger file {} to {:?}", trigger_file_name, remote_dir),
+            Ok(Transfer::Failed(e)) => error!("Failed to send trigger file {} to {:?}: {}", trigger_file_name, remote_dir, e),
+            Err(e) => error!("Failed to send trigger file {} to {:?}. Error: {}", trigger_file_name, remote_dir, e),
+        }
+    }
+    fs::remove_dir_all(&trigger_dir)
+}
+
+fn in_quiet_hours(cfg: &AppConfig) -> bool {
+    if cfg.quiet_hours.is_empty() {
+        return false;
+    }
+    let now = match cfg.quiet_hours_tz {
+        Some(tz) => chrono::Utc::now().with_timezone(&tz).time(),
+        None => chrono::Local::now().time(),
+    };
+    cfg.quiet_hours.iter().any(|window| window.contains(now))
+}
+
+fn symlink_allowed(cfg: &AppConfig, path: &Path) -> bool {
+    // Apply SYMLINK_POLICY to a path reached through a symlink, either the file itself or a linked directory
+    if cfg.symlink_policy == SymlinkPolicy::Follow {
+        return true;
+    }
+    let (Ok(relative_path), Ok(src_root), Ok(target)) = (
+        path.strip_prefix(&cfg.src_dir),
+        fs::canonicalize(&cfg.src_dir),
+        fs::canonicalize(path),
+    ) else {
+        return true;
+    };
+    if target == src_root.join(relative_path) {
+        // Not a symlink
+        return true;
+    }
+    let allowed = cfg.symlink_policy == SymlinkPolicy::Within && target.starts_with(&src_root);
+    if !allowed {
+        info!("Skipping {:?}, a symlink to {:?} (SYMLINK_POLICY {:?})", path, target, cfg.symlink_policy);
+    }
+    allowed
+}
+
+fn is_source_file(cfg: &AppConfig, path: &Path) -> bool {
+    // Csv files, .xlsx workbooks when XLSX_MODE is enabled, and PASSTHROUGH_EXTENSIONS files
+    path.extension().and_then(|s| s.to_str()) == Some("csv") || xlsx::accepts(path) || is_passthrough_file(cfg, path)
+}
+
+fn is_passthrough_file(cfg: &AppConfig, path: &Path) -> bool {
+    // Files sent as they are, without header matching
+    path.extension()
+        .and_then(|s| s.to_str())
+        .is_some_and(|extension| cfg.passthrough_extensions.iter().any(|allowed| allowed.eq_ignore_ascii_case(extension)))
+}
+
+fn scan_source_dir(cfg: &AppConfig, dir: &Path, event_vec: &mut Vec<notify::Event>) {
+    // Queue a create event for every csv file under the directory that is not already queued
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Failed to scan directory {:?}. Error: {}", dir, e);
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if is_rsync_temp_file(dir, &path) {
+            debug!("Ignoring rsync temporary file: {:?}", path);
+        } else if is_excluded(cfg, &path) {
+            debug!("Skipping excluded output directory: {:?}", path);
+        } else if path.is_dir() {
+            scan_source_dir(cfg, &path, event_vec);
+        } else if is_source_file(cfg, &path) && !event_vec.iter().any(|event| event.paths[0] == path)
+        {
+            debug!("Queueing csv file found by scan: {:?}", path);
+            event_vec.push(notify::Event::new(EventKind::Create(CreateKind::File)).add_path(path));
+        }
+    }
+}
+
+fn is_excluded(cfg: &AppConfig, path: &Path) -> bool {
+    // Whether a path is under one of the output directories that OUTPUT_DIR_OVERLAP excluded from SOURCE_DIR
+    cfg.excluded_source_dirs.iter().any(|(_, dir)| path.starts_with(dir))
+}
+
+fn is_rsync_temp_file(src_dir: &Path, path: &Path) -> bool {
+    // Recognise rsync's own artifacts, should a destination ever end up under the source directory:
+    // the --delay-updates .~tmp~ directory, our --partial-dir, and in-progress .name.XXXXXX files
+    let relative_path = path.strip_prefix(src_dir).unwrap_or(path);
+    if relative_path
+        .components()
+        .any(|component| component.as_os_str() == ".~tmp~" || component.as_os_str() == RSYNC_PARTIAL_DIR)
+    {
+        return true;
+    }
+    let file_name = relative_path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    match file_name.strip_prefix('.').and_then(|name| name.rsplit_once('.')) {
+        Some((original_name, random)) => {
+            !original_name.is_empty() && random.len() == 6 && random.chars().all(|c| c.is_ascii_alphanumeric())
+        }
+        None => false,
+    }
+}
+
+fn handle_csv_file_event(
+    cfg: &AppConfig,
+    templates: &SharedTemplates,
+    event_vec: &[notify::Event],
+    suffixed_files: &mut HashSet<PathBuf>,
+    retry_queue: &mut RetryQueue,
+) -> std::io::Result<HashSet<String>> {
+    // Handle csv file events, returning the tables that had files uploaded
+    info!(
+        "Handling CSV file events. Total event count: {:?}",
+        event_vec.len()
+    );
+    // debug!("Event Vec: {:?}", event_vec);
+    /*
+    Rsync hashmap structure:
+    {
+        "table_name": {
+            "src_files": [src_file...],
+            "metadata_files": [metadata_file...]
+            "uploaded_by": [username...]
+        }
+    }
+     */
+    let mut rsync_hashmap: RsyncHashmap = HashMap::new();
+    // Match every file against the same template snapshot
+    let current_templates = templates.read().unwrap();
+    let match_generation = current_templates.generation;
+    // Several events for the same file in one batch are handled once
+    let mut batch_files: HashSet<&PathBuf> = HashSet::new();
+    // Files with a PASSTHROUGH_EXTENSIONS extension, sent after the batch's csv files are matched
+    let mut passthrough_files: Vec<PathBuf> = Vec::new();
+    let mut last_matched_table: Option<String> = None;
+    let mut vanished_count = 0;
+    // Files to queue, with the event path they were found by and their table
+    let mut matched_files: Vec<(PathBuf, String, String)> = Vec::new();
+    // Lines before the header of matched files whose header is not their first line
+    let mut preamble_lines: HashMap<String, usize> = HashMap::new();
+    for event in event_vec.iter() {
+        queue_age::processed(&event.paths[0]);
+        if !batch_files.insert(&event.paths[0]) {
+            debug!("Skipping repeated event for file already handled in this batch: {:?}", event.paths[0]);
+            continue;
+        }
+        let src_file_path = event.paths[0].to_str().unwrap();
+        if !event.paths[0].exists() {
+            // Deleted or moved since its event, common with exporters that write through temporary files
+            debug!("File vanished before processing: {}", src_file_path);
+            telemetry::start_file(src_file_path);
+            telemetry::finish_file(src_file_path, "vanished");
+            vanished_count += 1;
+            continue;
+        }
+        if !symlink_allowed(cfg, &event.paths[0]) {
+            continue;
+        }
+        let src_file_basename = event.paths[0].file_name().unwrap().to_str().unwrap();
+        telemetry::start_file(src_file_path);
+        slow_files::start(src_file_path);
+        if is_passthrough_file(cfg, &event.paths[0]) {
+            passthrough_files.push(event.paths[0].clone());
+            continue;
+        }
+        if cfg.content_sniff && !xlsx::accepts(&event.paths[0]) && event.paths[0].exists() {
+            match mislabeled_content(src_file_path) {
+                Ok(Some(detected)) => {
+                    warn!("File {} is not csv, its content looks like {}", src_file_path, detected);
+                    telemetry::finish_file(src_file_path, "mislabeled_content");
+                    slow_files::finish(src_file_path, "mislabeled_content");
+                    let reason = format!("Mislabeled content: looks like {detected}");
+                    dead_letter_file(cfg, src_file_path, "mislabeled_content", None, &reason);
+                    continue;
+                }
+                Ok(None) => (),
+                Err(e) => error!("Failed to sniff the content of {}. Error: {}", src_file_path, e),
+            }
+        }
+        if cfg.encoding_check && !xlsx::accepts(&event.paths[0]) && event.paths[0].exists() {
+            match encoding_error(src_file_path, &cfg.source_encoding, cfg.encoding_check_bytes) {
+                Ok(Some(reason)) => {
+                    warn!("File {} failed the encoding check: {}", src_file_path, reason);
+                    telemetry::finish_file(src_file_path, "invalid_encoding");
+                    slow_files::finish(src_file_path, "invalid_encoding");
+                    let reason = format!("Invalid encoding: {reason}");
+                    dead_letter_file(cfg, src_file_path, "invalid_encoding", None, &reason);
+                    continue;
+                }
+                Ok(None) => (),
+                Err(e) => error!("Failed to check the encoding of {}. Error: {}", src_file_path, e),
+            }
+        }
+        let match_stage = telemetry::start_stage(src_file_path, "match");
+        let match_result = match_col_headers(src_file_path, &current_templates.headers, cfg);
+        match match_result {
+            Ok(None) => {
+                match_stage.end("no_match");
+                telemetry::finish_file(src_file_path, "no_match");
+                slow_files::finish(src_file_path, "no_match");
+            }
+            Ok(Some(HeaderMatch { table_name, delimiter, line_index })) => {
+                let converted_csv;
+                let src_file_path = if cfg.xlsx_mode == XlsxMode::Convert && xlsx::accepts(&event.paths[0]) {
+                    match xlsx::convert_to_csv(src_file_path) {
+                        Ok(csv_path) => {
+                            info!("Converted the first sheet of {} to {}", src_file_path, csv_path);
+                            telemetry::rename_file(src_file_path, &csv_path);
+                            slow_files::rename(src_file_path, &csv_path);
+                            converted_csv = csv_path;
+                            converted_csv.as_str()
+                        }
+                        Err(e) => {
+                            error!("Failed to convert {} to csv. Error: {}", src_file_path, e);
+                            match_stage.end("error");
+                            telemetry::finish_file(src_file_path, "conversion_error");
+                            slow_files::finish(src_file_path, "conversion_error");
+                            continue;
+                        }
+                    }
+                } else {
+                    src_file_path
+                };
+                // Workbooks uploaded as is have no lines to check for data rows or column types
+                let workbook = xlsx::accepts(Path::new(src_file_path));
+                if !workbook
+                    && !has_data_rows(src_file_path, &cfg.header_encoding, line_index).unwrap_or(true)
+                    && !apply_empty_file_policy(cfg, src_file_path, &table_name, line_index)
+                {
+                    match_stage.end("empty");
+                    telemetry::finish_file(src_file_path, "empty");
+                    slow_files::finish(src_file_path, "empty");
+                } else if let Some(reason) = (cfg.field_count_check && !workbook)
+                    .then(|| field_count_mismatch(src_file_path, &cfg.header_encoding, delimiter, line_index))
+                    .flatten()
+                {
+                    warn!("File {} failed the field count check: {}", src_file_path, reason);
+                    match_stage.end("field_count_mismatch");
+                    telemetry::finish_file(src_file_path, "field_count_mismatch");
+                    slow_files::finish(src_file_path, "field_count_mismatch");
+                    dead_letter_file(
+                        cfg,
+                        src_file_path,
+                        "field_count_mismatch",
+                        Some(&table_name),
+                        &format!("Field count mismatch: {reason}"),
+                    );
+                } else if let Some(reason) = current_templates
+                    .tables
+                    .get(&table_name)
+                    .filter(|_| !workbook)
+                    .and_then(|template| template.column_types.as_ref())
+                    .and_then(|column_types| {
+                        schema_violation(
+                            src_file_path,
+                            &cfg.header_encoding,
+                            column_types,
+                            cfg.schema_sample_rows,
+                            delimiter,
+                            line_index,
+                            cfg.header_match_mode == HeaderMatchMode::Prefix,
+                        )
+                    })
+                {
+                    info!("File {} violates the schema of table {:?}: {}", src_file_path, table_name, reason);
+                    match_stage.end("schema_violation");
+                    telemetry::finish_file(src_file_path, "schema_violation");
+                    slow_files::finish(src_file_path, "schema_violation");
+                    dead_letter_file(
+                        cfg,
+                        src_file_path,
+                        "schema_violation",
+                        Some(&table_name),
+                        &format!("Schema violation for table {table_name}: {reason}"),
+                    );
+                } else {
+                    match_stage.end("matched");
+                    telemetry::set_table(src_file_path, &table_name);
+                    last_matched_table = Some(table_name.clone());
+                    if line_index > 0 {
+                        preamble_lines.insert(src_file_path.to_string(), line_index);
+                    }
+                    matched_files.push((event.paths[0].clone(), table_name, src_file_path.to_string()));
+                }
+            },
+            Err(e) => {
+                match_stage.end("error");
+                telemetry::finish_file(src_file_path, "match_error");
+                slow_files::finish(src_file_path, "match_error");
+                error!("Error matching column headers: {:?}", e);
+                match &event.paths[0].parent() {
+                    Some(log_dir) => log_upload_status(
+                        cfg,
+                        log_dir.to_str().unwrap(),
+                        format!("Upload failed! File: {src_file_basename} Reason: {e}").to_string(),
+                    ),
+                    None => error!("Failed to get parent directory of source file."),
+                }
+            }
+        }
+    }
+    drop(current_templates);
+    if vanished_count > 0 {
+        debug!("{} file(s) of the batch vanished before processing", vanished_count);
+    }
+    for path in passthrough_files {
+        let src_file_path = path.to_str().unwrap();
+        // A sidecar goes with the csv of its bundle
+        let bundle_table = bundle_key(cfg, &path).and_then(|key| {
+            matched_files
+                .iter()
+                .find(|(matched_path, _, _)| bundle_key(cfg, matched_path).as_ref() == Some(&key))
+                .map(|(_, table_name, _)| table_name.clone())
+        });
+        match bundle_table.or_else(|| cfg.passthrough_table.clone()).or_else(|| last_matched_table.clone()) {
+            Some(table_name) if path.exists() => {
+                info!("Passing {} through to table {:?} without header matching", src_file_path, table_name);
+                telemetry::set_table(src_file_path, &table_name);
+                matched_files.push((path.clone(), table_name, src_file_path.to_string()));
+            }
+            Some(_) => debug!("Skipping passthrough file {}, it no longer exists", src_file_path),
+            None => {
+                info!("No csv matched a table in the batch of passthrough file {}, leaving it in place", src_file_path);
+                telemetry::finish_file(src_file_path, "no_table");
+                slow_files::finish(src_file_path, "no_table");
+                if let Some(log_dir) = path.parent() {
+                    let src_file_basename = path.file_name().unwrap().to_string_lossy();
+                    log_upload_status(
+                        cfg,
+                        log_dir.to_str().unwrap(),
+                        format!("Upload failed! File: {src_file_basename} Reason: No table to send the passthrough file with"),
+                    );
+                }
+            }
+        }
+    }
+    if cfg.bundle_window_seconds.is_some() {
+        hold_back_incomplete_bundles(cfg, &batch_files, &mut matched_files);
+    }
+    for (path, table_name, src_file_path) in matched_files {
+        let src_file_with_suffix = queue_table_file(cfg, &mut rsync_hashmap, suffixed_files, table_name, &src_file_path)?;
+        if let Some(key) = bundle_key(cfg, &path) {
+            bundles::assign(&src_file_with_suffix, &key);
+        }
+        if let Some(lines) = preamble_lines.get(&src_file_path) {
+            preambles::assign(&src_file_with_suffix, *lines);
+        }
+    }
+    if cfg.verify_templates_before_upload {
+        rsync_hashmap = verify_matched_tables(cfg, templates, match_generation, rsync_hashmap);
+    }
+    Ok(upload_batch(cfg, templates, rsync_hashmap, retry_queue))
+}
+
+fn queue_table_file(
+    cfg: &AppConfig,
+    rsync_hashmap: &mut RsyncHashmap,
+    suffixed_files: &mut HashSet<PathBuf>,
+    table_name: String,
+    src_file_path: &str,
+) -> std::io::Result<String> {
+    // Suffix a file, create its metadata file and add both to its table's entry of the batch, returning its new path
+    let username = get_file_owner(src_file_path).unwrap();
+    let src_file_with_suffix =
+        suffix_file_name(src_file_path, &cfg.file_suffix)?;
+    info!("Source file with suffix: {:?}", src_file_with_suffix);
+    telemetry::rename_file(src_file_path, &src_file_with_suffix);
+    slow_files::rename(src_file_path, &src_file_with_suffix);
+    suffixed_files.insert(PathBuf::from(&src_file_with_suffix));
+    let metadata_file = match create_metadata_file(&src_file_with_suffix) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Error creating metadata file: {:?}", e);
+            String::new()
+        }
+    };
+    let table_entry = rsync_hashmap.entry(table_name).or_default();
+    table_entry
+        .entry("src_files".to_string())
+        .or_default()
+        .push(src_file_with_suffix.clone());
+    table_entry
+        .entry("metadata_files".to_string())
+        .or_default()
+        .push(metadata_file);
+    table_entry
+        .entry("uploaded_by".to_string())
+        .or_default()
+        .push(username);
+    Ok(src_file_with_suffix)
+}
+
+fn bundle_key(cfg: &AppConfig, path: &Path) -> Option<String> {
+    // The bundle of a file: what BUNDLE_KEY_PATTERN captures from its name, or its name up to the first dot
+    cfg.bundle_window_seconds?;
+    let file_name = path.file_name()?.to_string_lossy();
+    let key = match &cfg.bundle_key_pattern {
+        Some(pattern) => pattern.captures(&file_name)?.get(1)?.as_str(),
+        None => file_name.split('.').next()?,
+    };
+    (!key.is_empty()).then(|| key.to_string())
+}
+
+fn split_held_bundles(cfg: &AppConfig, events: Vec<notify::Event>) -> (Vec<notify::Event>, Vec<notify::Event>) {
+    // Split queued events into those ready to process and those of bundles a file was added to or modified in
+    // within BUNDLE_WINDOW_SECONDS, which wait for the rest of their bundle
+    let Some(window) = cfg.bundle_window_seconds.map(Duration::from_secs) else {
+        return (events, Vec::new());
+    };
+    let mut last_modified: HashMap<String, SystemTime> = HashMap::new();
+    for event in &events {
+        let (Some(key), Ok(modified)) = (
+            bundle_key(cfg, &event.paths[0]),
+            fs::metadata(&event.paths[0]).and_then(|metadata| metadata.modified()),
+        ) else {
+            continue;
+        };
+        let newest = last_modified.entry(key).or_insert(modified);
+        *newest = (*newest).max(modified);
+    }
+    let (held, ready): (Vec<notify::Event>, Vec<notify::Event>) = events.into_iter().partition(|event| {
+        bundle_key(cfg, &event.paths[0])
+            .and_then(|key| last_modified.get(&key))
+            .is_some_and(|modified| modified.elapsed().unwrap_or_default() < window)
+    });
+    if !held.is_empty() {
+        debug!("Holding {} file(s) of bundles modified in the last {} second(s)", held.len(), window.as_secs());
+    }
+    (ready, held)
+}
+
+fn split_held_small_files(cfg: &AppConfig, events: Vec<notify::Event>) -> (Vec<notify::Event>, Vec<notify::Event>) {
+    // Split queued events into those ready to process and the files of at most MERGE_SMALL_FILE_BYTES, which wait
+    // together until the oldest of them was modified MERGE_WINDOW_SECONDS ago, so they go in one batch to be merged
+    let Some(window) = cfg.merge_window_seconds.map(Duration::from_secs) else {
+        return (events, Vec::new());
+    };
+    let (small, ready): (Vec<notify::Event>, Vec<notify::Event>) = events.into_iter().partition(|event| {
+        fs::metadata(&event.paths[0]).is_ok_and(|metadata| metadata.len() <= cfg.merge_small_file_bytes)
+    });
+    let oldest = small
+        .iter()
+        .filter_map(|event| fs::metadata(&event.paths[0]).and_then(|metadata| metadata.modified()).ok())
+        .min();
+    match oldest {
+        Some(oldest) if oldest.elapsed().unwrap_or_default() < window => {
+            debug!("Holding {} small file(s) for merging for up to {} second(s)", small.len(), window.as_secs());
+            (ready, small)
+        }
+        _ => (ready.into_iter().chain(small).collect(), Vec::new()),
+    }
+}
+
+fn split_source_overflow(cfg: &AppConfig, events: Vec<notify::Event>) -> (Vec<notify::Event>, Vec<notify::Event>) {
+    // Split a batch into the files each source directory may have in it, SOURCE_MAX_CONCURRENT_FILES or its
+    // SOURCE_DIR_MAX_CONCURRENT_FILES override, and the rest, which wait for the next batch, oldest first.
+    // A bundle goes into the batch with its first file.
+    if cfg.source_max_concurrent_files.is_none() && cfg.source_dir_max_concurrent_files.is_empty() {
+        return (events, Vec::new());
+    }
+    let mut file_counts: HashMap<Option<String>, usize> = HashMap::new();
+    let mut admitted_bundles: HashSet<String> = HashSet::new();
+    let mut admitted_files: HashSet<PathBuf> = HashSet::new();
+    let (mut batch, mut deferred) = (Vec::new(), Vec::new());
+    for event in events {
+        // Repeated events of a file go with its first
+        if admitted_files.contains(&event.paths[0]) {
+            batch.push(event);
+            continue;
+        }
+        let source = source_subdir(cfg, &event.paths[0]);
+        let limit = source
+            .as_ref()
+            .and_then(|source| cfg.source_dir_max_concurrent_files.get(source))
+            .or(cfg.source_max_concurrent_files.as_ref());
+        let bundle = bundle_key(cfg, &event.paths[0]);
+        let file_count = file_counts.entry(source).or_default();
+        if bundle.as_ref().is_some_and(|bundle| admitted_bundles.contains(bundle))
+            || limit.is_none_or(|limit| *file_count < *limit)
+        {
+            *file_count += 1;
+            admitted_bundles.extend(bundle);
+            admitted_files.insert(event.paths[0].clone());
+            batch.push(event);
+        } else {
+            deferred.push(event);
+        }
+    }
+    let mut deferred_files: BTreeMap<String, HashSet<&PathBuf>> = BTreeMap::new();
+    for event in &deferred {
+        let source = source_subdir(cfg, &event.paths[0]).unwrap_or_else(|| cfg.src_dir.clone());
+        deferred_files.entry(source).or_default().insert(&event.paths[0]);
+    }
+    for (source, files) in deferred_files {
+        info!("Source {:?} is over its file limit, {} file(s) wait for the next batch", source, files.len());
+    }
+    (batch, deferred)
+}
+
+fn hold_back_incomplete_bundles(
+    cfg: &AppConfig,
+    batch_files: &HashSet<&PathBuf>,
+    matched_files: &mut Vec<(PathBuf, String, String)>,
+) {
+    // A bundle is sent only if every file of it in the batch was matched, all to the same table, so its files travel
+    // in one transfer. The files of other bundles are left in place.
+    let mut bundle_files: BTreeMap<String, Vec<&PathBuf>> = BTreeMap::new();
+    for path in batch_files {
+        if let Some(key) = bundle_key(cfg, path) {
+            bundle_files.entry(key).or_default().push(path);
+        }
+    }
+    for (key, paths) in bundle_files {
+        let matched: Vec<&(PathBuf, String, String)> =
+            matched_files.iter().filter(|(path, _, _)| paths.contains(&path)).collect();
+        if matched.is_empty() {
+            continue;
+        }
+        let mut unmatched: Vec<String> = paths
+            .iter()
+            .filter(|path| !matched.iter().any(|(matched_path, _, _)| matched_path == **path))
+            .map(|path| path.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        unmatched.sort();
+        let mut tables: Vec<&str> = matched.iter().map(|(_, table_name, _)| table_name.as_str()).collect();
+        tables.sort();
+        tables.dedup();
+        let reason = if !unmatched.is_empty() {
+            format!("{} of its files could not be sent: {}", unmatched.len(), unmatched.join(", "))
+        } else if tables.len() > 1 {
+            format!("its files match several tables: {}", tables.join(", "))
+        } else {
+            if matched.len() > 1 {
+                info!("Sending bundle {:?} of {} file(s) with table {:?}", key, matched.len(), tables[0]);
+            }
+            continue;
+        };
+        warn!("Holding back bundle {:?}, {}", key, reason);
+        matched_files.retain(|(path, _, src_file_path)| {
+            if !paths.contains(&path) {
+                return true;
+            }
+            telemetry::finish_file(src_file_path, "bundle_incomplete");
+            slow_files::finish(src_file_path, "bundle_incomplete");
+            if let Some(log_dir) = path.parent() {
+                let src_file_basename = path.file_name().unwrap().to_string_lossy();
+                log_upload_status(
+                    cfg,
+                    log_dir.to_str().unwrap(),
+                    format!("Upload failed! File: {src_file_basename} Reason: Bundle {key} held back, {reason}"),
+                );
+            }
+            false
+        });
+    }
+}
+
+fn upload_batch(
+    cfg: &AppConfig,
+    templates: &SharedTemplates,
+    rsync_hashmap: RsyncHashmap,
+    retry_queue: &mut RetryQueue,
+) -> HashSet<String> {
+    // Upload a batch, hand failed files to the retry queue and notify the destination of the uploaded ones.
+    // Returns the tables that had files uploaded.
+    let failed_files = match run_rsync(&rsync_hashmap, cfg, templates) {
+        Ok(failed_files) => failed_files,
+        Err(e) => {
+            error!("Error uploading files: {}", e);
+            return HashSet::new();
+        }
+    };
+    let failed_src_files: HashSet<String> = failed_files.iter().map(|(file, _)| file.src_file.clone()).collect();
+    let uploaded_files: Vec<PendingFile> = rsync_hashmap
+        .iter()
+        .flat_map(|(table_name, table_entry)| pending_table_files(table_name, table_entry))
+        .filter(|file| !failed_src_files.contains(&file.src_file))
+        .collect();
+    uploaded_files.iter().for_each(|file| retry_queue.forget(&file.src_file));
+    failure_rate::record(uploaded_files.len(), failed_files.len());
+    for (mut file, err_msg) in failed_files {
+        let src_file = file.src_file.clone();
+        // A retry is timed as a new attempt, the backoff in between does not count as processing
+        slow_files::finish(&src_file, "failed");
+        if cfg.locked_file_retries > 0 && is_lock_failure(&err_msg, &src_file) {
+            let delay = Duration::from_secs(cfg.locked_file_retry_seconds);
+            match retry_queue.locked(file, delay, cfg.locked_file_retries) {
+                Ok(lock_retry) => {
+                    info!(
+                        "{} was locked while it was sent, retrying in {} second(s) (lock retry {} of {})",
+                        src_file,
+                        delay.as_secs(),
+                        lock_retry,
+                        cfg.locked_file_retries
+                    );
+                    continue;
+                }
+                Err(locked_file) => {
+                    warn!("{} is still locked after {} lock retries, handling it as a failure", src_file, cfg.locked_file_retries);
+                    file = locked_file;
+                }
+            }
+        }
+        if connectivity::is_network_failure(&err_msg) {
+            connectivity::lost(&err_msg);
+            info!("Holding {} until the destination is reachable again", src_file);
+            retry_queue.requeue(file);
+            continue;
+        }
+        match retry_queue.failed(file) {
+            Ok(delay) => info!("Retrying upload of {} in {} second(s)", src_file, delay.as_secs()),
+            Err(file) => {
+                telemetry::finish_file(&src_file, "failed");
+                events::publish(
+                    "failed",
+                    &src_file,
+                    serde_json::json!({"table": file.table_name, "reason": err_msg}),
+                );
+                sequence::forget(&src_file);
+                remote_exists::forget(&src_file);
+                bundles::forget(&src_file);
+                preambles::forget(&src_file);
+                let binding = PathBuf::from(&src_file);
+                let src_file_basename = binding.file_name().unwrap().to_str().unwrap();
+                match binding.parent() {
+                    Some(log_dir) => log_upload_status(
+                        cfg,
+                        log_dir.to_str().unwrap(),
+                        format!("Upload failed! File: {src_file_basename} Reason: {err_msg}"),
+                    ),
+                    None => error!("Failed to get source file parent directory"),
+                }
+            }
+        }
+    }
+    if uploaded_files.is_empty() || cfg.dry_run != DryRun::Off {
+        return HashSet::new();
+    }
+    let uploaded_tables: HashSet<String> = uploaded_files.iter().map(|file| file.table_name.clone()).collect();
+    let msg = serde_json::to_string(&pending_to_hashmap(uploaded_files)).unwrap();
+    debug!("Notifying the destination host of uploaded files: {}", msg);
+    let dest_addr = format!("{}:50000", cfg.dest_host);
+    if let Ok(mut stream) = TcpStream::connect(dest_addr) {
+        let _ = stream.write(&msg.into_bytes());
+    } else {
+        error!("Failed to connect to destination host ({}) on port 50000", cfg.dest_host);
+    }
+    uploaded_tables
+}
+
+// Errors rsync reports for a file it could not read because another process had it locked, compared in lowercase
+const LOCK_FAILURES: &[&str] = &[
+    "resource temporarily unavailable",
+    "device or resource busy",
+    "text file busy",
+    "being used by another process",
+];
+
+fn is_lock_failure(err_msg: &str, src_file: &str) -> bool {
+    // A read failure that no longer happens is a transient lock, one that still does is a permanent permission
+    // problem. Remote write failures are reported as permission denied too, so that only counts on a line about
+    // the sender opening the local file.
+    let locked = err_msg.lines().any(|line| {
+        let lowercase = line.to_lowercase();
+        LOCK_FAILURES.iter().any(|failure| lowercase.contains(failure))
+            || (lowercase.contains("permission denied")
+                && (line.contains(src_file) || lowercase.contains("send_files failed to open")))
+    });
+    locked && File::open(src_file).is_ok()
+}
+
+fn retry_due_files(cfg: &AppConfig, templates: &SharedTemplates, retry_queue: &mut RetryQueue) -> HashSet<String> {
+    // Upload files whose retry backoff has elapsed, skipping any that were removed in the meantime
+    let due_files: Vec<PendingFile> = retry_queue
+        .take_due()
+        .into_iter()
+        .filter(|file| {
+            let exists = Path::new(&file.src_file).exists();
+            if !exists {
+                warn!("File {} disappeared while waiting for retry, dropping it", file.src_file);
+                retry_queue.forget(&file.src_file);
+                telemetry::finish_file(&file.src_file, "missing");
+            }
+            exists
+        })
+        .collect();
+    if !due_files.is_empty() {
+        info!("Retrying upload of {} file(s)", due_files.len());
+        due_files.iter().for_each(|file| slow_files::start(&file.src_file));
+        return upload_batch(cfg, templates, pending_to_hashmap(due_files), retry_queue);
+    }
+    HashSet::new()
+}
+
+fn pending_table_files<'a>(
+    table_name: &'a str,
+    table_entry: &'a HashMap<String, Vec<String>>,
+) -> impl Iterator<Item = PendingFile> + 'a {
+    // Split a table entry of the rsync hashmap into one item per file
+    table_entry["src_files"].iter().enumerate().map(move |(i, src_file)| PendingFile {
+        table_name: table_name.to_string(),
+        src_file: src_file.clone(),
+        metadata_file: table_entry["metadata_files"][i].clone(),
+        uploaded_by: table_entry["uploaded_by"][i].clone(),
+    })
+}
+
+fn pending_to_hashmap(files: Vec<PendingFile>) -> RsyncHashmap {
+    let mut rsync_hashmap: RsyncHashmap = HashMap::new();
+    for file in files {
+        let table_entry = rsync_hashmap.entry(file.table_name).or_default();
+        table_entry.entry("src_files".to_string()).or_default().push(file.src_file);
+        table_entry.entry("metadata_files".to_string()).or_default().push(file.metadata_file);
+        table_entry.entry("uploaded_by".to_string()).or_default().push(file.uploaded_by);
+    }
+    rsync_hashmap
+}
+
+fn verify_matched_tables(
+    cfg: &AppConfig,
+    templates: &SharedTemplates,
+    match_generation: u64,
+    rsync_hashmap: RsyncHashmap,
+) -> RsyncHashmap {
+    // Re-match files against the current templates if templates were reloaded after the files were matched
+    let current_templates = templates.read().unwrap();
+    if current_templates.generation == match_generation {
+        return rsync_hashmap;
+    }
+    info!(
+        "Templates reloaded since matching (generation {} -> {}). Re-matching files before upload.",
+        match_generation, current_templates.generation
+    );
+    let mut verified_hashmap: RsyncHashmap = HashMap::new();
+    for (table_name, table_entry) in rsync_hashmap {
+        for (i, src_file) in table_entry["src_files"].iter().enumerate() {
+            let verified_table =
+                match match_col_headers(src_file, &current_templates.headers, cfg) {
+                    Ok(header_match) => header_match.map(|header_match| header_match.table_name).unwrap_or_default(),
+                    Err(e) => {
+                        error!("Error re-matching column headers: {:?}", e);
+                        String::new()
+                    }
+                };
+            if verified_table.is_empty() {
+                info!("File no longer matches any template, skipping upload: {}", src_file);
+                telemetry::finish_file(src_file, "no_match");
+                slow_files::finish(src_file, "no_match");
+                continue;
+            }
+            if verified_table != table_name {
+                info!(
+                    "File re-matched from table {:?} to {:?}: {}",
+                    table_name, verified_table, src_file
+                );
             }
+            let verified_entry = verified_hashmap.entry(verified_table).or_default();
+            for key in ["src_files", "metadata_files", "uploaded_by"] {
+                verified_entry
+                    .entry(key.to_string())
+                    .or_default()
+                    .push(table_entry[key][i].clone());
+            }
+        }
+    }
+    verified_hashmap
+}
+
+struct HeaderMatch {
+    table_name: String,
+    // Delimiter the header matched under
+    delimiter: char,
+    // Zero based line number of the header
+    line_index: usize,
+}
+
+fn match_col_headers(
+    csv_path: &str,
+    hashmap: &HashMap<String, String>,
+    cfg: &AppConfig,
+) -> std::io::Result<Option<HeaderMatch>> {
+    // Match column header templates and returns the matching table name, or None if nothing matches
+    if Path::new(csv_path).exists() {
+        let binding = PathBuf::from(csv_path);
+        let csv_file_basename = binding.file_name().unwrap().to_str().unwrap();
+        match find_header(csv_path, hashmap, cfg)? {
+            Some(header_match) => {
+                info!("Matching table headers found, table name: {:?}", header_match.table_name);
+                return Ok(Some(header_match));
+            }
+            None if cfg.catchall_table.is_some() => {
+                let table_name = cfg.catchall_table.clone().unwrap();
+                info!("No matching table headers found, routing {} to catch-all table {:?}", csv_path, table_name);
+                return Ok(Some(HeaderMatch { table_name, delimiter: cfg.csv_delimiters[0], line_index: 0 }));
+            }
+            None => {
+                info!("No matching table headers found. Ignoring csv file.");
+                match PathBuf::from(csv_path).parent() {
+                    Some(log_dir) => log_upload_status(cfg, log_dir.to_str().unwrap(), format!("Upload failed! File: {csv_file_basename} Reason: No matching table headers found.").to_string()),
+                    None => error!("Failed to get parent directory of source file."),
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn find_header(csv_path: &str, hashmap: &HashMap<String, String>, cfg: &AppConfig) -> std::io::Result<Option<HeaderMatch>> {
+    // Look for the header in the first HEADER_SCAN_LINES lines, taking the first line that matches a template
+    if xlsx::accepts(Path::new(csv_path)) {
+        // Rows of the first sheet stand in for lines
+        for (line_index, row) in xlsx::rows(csv_path, Some(cfg.header_scan_lines.max(1)))?.into_iter().enumerate() {
+            if let Some((table_name, delimiter)) = lookup_table(&row.join(","), hashmap, cfg) {
+                info!("Header of {} found on sheet row {}: {:?}", csv_path, line_index + 1, row);
+                return Ok(Some(HeaderMatch { table_name: table_name.to_string(), delimiter, line_index }));
+            }
+        }
+        return Ok(None);
+    }
+    // Decoded per HEADER_ENCODING rather than read as UTF-8, so the headers compare equal to the decoded templates
+    let mut lines = decoded_lines(csv_path, &cfg.header_encoding)?;
+    for line_index in 0..cfg.header_scan_lines.max(1) {
+        let Some(csv_headers) = lines.next().transpose()? else {
+            break;
+        };
+        if line_index == 0 {
+            debug!("Decoding the header lines of {} as {}", csv_path, lines.encoding().name());
+            info!("CSV Headers: {:?}", csv_headers);
+        }
+        if let Some((table_name, delimiter)) = lookup_table(&csv_headers, hashmap, cfg) {
+            if line_index > 0 {
+                info!("Header of {} found on line {}: {:?}", csv_path, line_index + 1, csv_headers);
+            }
+            return Ok(Some(HeaderMatch { table_name: table_name.to_string(), delimiter, line_index }));
+        }
+    }
+    Ok(None)
+}
+
+fn lookup_table<'a>(
+    csv_headers: &str,
+    hashmap: &'a HashMap<String, String>,
+    cfg: &AppConfig,
+) -> Option<(&'a String, char)> {
+    // Find the table whose template headers match the normalized csv headers, trying every configured delimiter.
+    // If several delimiters match, the one splitting the header into the most fields wins, then the earliest listed.
+    let mut matches: Vec<(&String, char, usize)> = Vec::new();
+    for &delimiter in &cfg.csv_delimiters {
+        let fields: Vec<&str> = csv_headers.trim_end_matches(delimiter).split(delimiter).collect();
+        let normalized = normalize_headers(
+            &clean_headers(&fields.join(","), &cfg.header_wrapper_chars, &cfg.header_unicode_form),
+            &cfg.header_aliases,
+        );
+        let table_name = match cfg.header_match_mode {
+            HeaderMatchMode::Exact => hashmap.get(&normalized),
+            HeaderMatchMode::Prefix => longest_prefix_template(&normalized, hashmap),
+        };
+        if let Some(table_name) = table_name {
+            matches.push((table_name, delimiter, fields.len()));
+        }
+    }
+    let mut best = *matches.first()?;
+    for &candidate in &matches[1..] {
+        if candidate.2 > best.2 {
+            best = candidate;
+        }
+    }
+    if matches.len() > 1 {
+        info!(
+            "Header {:?} matches under delimiters {:?}, using {:?} ({} fields) for table {:?}",
+            csv_headers,
+            matches.iter().map(|(_, delimiter, _)| *delimiter).collect::<Vec<char>>(),
+            best.1,
+            best.2,
+            best.0
+        );
+    }
+    Some((best.0, best.1))
+}
+
+fn longest_prefix_template<'a>(csv_headers: &str, hashmap: &'a HashMap<String, String>) -> Option<&'a String> {
+    // Find the template whose headers are the leading csv headers in the same order, the one with most columns winning
+    hashmap
+        .iter()
+        .filter(|(template_headers, _)| {
+            csv_headers
+                .strip_prefix(template_headers.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(','))
+        })
+        .max_by_key(|(template_headers, _)| template_headers.split(',').count())
+        .map(|(_, table_name)| table_name)
+}
+
+fn clean_unmatched_files(cfg: &AppConfig, templates: &SharedTemplates, max_age: u64) {
+    // Delete or dead-letter csv files older than max_age seconds that match no template
+    let mut event_vec: Vec<notify::Event> = Vec::new();
+    scan_source_dir(cfg, Path::new(&cfg.src_dir), &mut event_vec);
+    let current_templates = templates.read().unwrap();
+    for event in event_vec {
+        if !symlink_allowed(cfg, &event.paths[0]) {
+            continue;
+        }
+        let csv_path = event.paths[0].to_str().unwrap();
+        let age = fs::metadata(csv_path)
+            .and_then(|attr| attr.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .map(|elapsed| elapsed.as_secs());
+        if age.is_none_or(|age| age <= max_age) {
+            continue;
+        }
+        let matched = match find_header(csv_path, &current_templates.headers, cfg) {
+            Ok(header_match) => header_match.is_some(),
+            Err(e) => {
+                error!("Failed to read headers of {}. Error: {}", csv_path, e);
+                continue;
+            }
+        };
+        if matched {
+            continue;
+        }
+        let reason = format!("No matching table headers found for over {max_age} seconds.");
+        match cfg.unmatched_action {
+            UnmatchedAction::Delete => match fs::remove_file(csv_path) {
+                Ok(_) => {
+                    info!("Removed unmatched file {}", csv_path);
+                    if let Some(log_dir) = event.paths[0].parent() {
+                        let csv_file_basename = event.paths[0].file_name().unwrap().to_string_lossy();
+                        log_upload_status(
+                            cfg,
+                            log_dir.to_str().unwrap(),
+                            format!("Removed unmatched file! File: {csv_file_basename} Reason: {reason}"),
+                        );
+                    }
+                }
+                Err(e) => error!("Failed to remove unmatched file {}. Error: {}", csv_path, e),
+            },
+            UnmatchedAction::Quarantine => dead_letter_file(cfg, csv_path, "unmatched", None, &reason),
+        }
+    }
+}
+
+fn csv_records(
+    lines: impl Iterator<Item = std::io::Result<String>>,
+    first_index: usize,
+) -> impl Iterator<Item = (usize, std::io::Result<String>)> {
+    // Join decoded lines into csv records, continuing a record while one of its quoted fields is still open.
+    // Yields each record with the index of its first line, counting the first line given as first_index.
+    let mut lines = lines.enumerate();
+    std::iter::from_fn(move || {
+        let (index, line) = lines.next()?;
+        let mut record = match line {
+            Ok(line) => line,
+            Err(e) => return Some((first_index + index, Err(e))),
+        };
+        // Escaped quotes come in pairs, so an odd count leaves a field open
+        while record.matches('"').count() % 2 == 1 {
+            match lines.next() {
+                Some((_, Ok(line))) => {
+                    record.push('\n');
+                    record.push_str(&line);
+                }
+                Some((_, Err(e))) => return Some((first_index + index, Err(e))),
+                None => break,
+            }
+        }
+        Some((first_index + index, Ok(record)))
+    })
+}
+
+fn csv_fields(record: &str, delimiter: char) -> Vec<String> {
+    // Split a csv record into its unquoted fields. Inside quotes, the delimiter and line breaks are part of the value
+    // and a doubled quote is a literal one.
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = record.chars().peekable();
+    while let Some(c) = chars.next() {
+        let field = fields.last_mut().unwrap();
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            c if c == delimiter && !quoted => fields.push(String::new()),
+            c => field.push(c),
+        }
+    }
+    fields
+}
+
+fn has_data_rows(csv_path: &str, encoding: &HeaderEncoding, header_index: usize) -> std::io::Result<bool> {
+    // Check whether a csv file has at least one non-empty line after the header
+    for line in decoded_lines(csv_path, encoding)?.skip(header_index + 1) {
+        if !line?.trim().is_empty() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn field_count_mismatch(
+    csv_path: &str,
+    encoding: &HeaderEncoding,
+    delimiter: char,
+    header_index: usize,
+) -> Option<String> {
+    // Compare the field count of the first data row with that of the header, describing a mismatch.
+    // Read errors are logged and let the file through, files without data rows have nothing to compare.
+    let lines = match decoded_lines(csv_path, encoding) {
+        Ok(lines) => lines,
+        Err(e) => {
+            error!("Failed to check the field count of {}. Error: {}", csv_path, e);
+            return None;
+        }
+    };
+    let mut records = csv_records(lines.skip(header_index), header_index).filter(|(_, record)| {
+        record.as_ref().map(|record| !record.trim().is_empty()).unwrap_or(true)
+    });
+    let (Some((_, Ok(header))), Some((index, Ok(row)))) = (records.next(), records.next()) else {
+        return None;
+    };
+    let header_fields = csv_fields(&header, delimiter).len();
+    let row_fields = csv_fields(&row, delimiter).len();
+    if row_fields == header_fields {
+        info!("Field count check passed for {}: {} fields", csv_path, header_fields);
+        None
+    } else {
+        Some(format!("line {} has {} fields, the header has {}", index + 1, row_fields, header_fields))
+    }
+}
+
+fn mislabeled_content(csv_path: &str) -> std::io::Result<Option<String>> {
+    // Sniff the first bytes of a csv file for content that clearly isn't csv, naming what it looks like
+    let mut head = Vec::with_capacity(CONTENT_SNIFF_BYTES);
+    File::open(csv_path)?.take(CONTENT_SNIFF_BYTES as u64).read_to_end(&mut head)?;
+    for (magic, detected) in [
+        (&b"\x1f\x8b"[..], "gzip data"),
+        (b"PK\x03\x04", "a zip archive"),
+        (b"%PDF-", "a PDF document"),
+    ] {
+        if head.starts_with(magic) {
+            return Ok(Some(detected.to_string()));
+        }
+    }
+    // Markup is only recognised at the start of the file, after any BOM and whitespace
+    let text = String::from_utf8_lossy(head.strip_prefix(b"\xef\xbb\xbf").unwrap_or(&head)).to_lowercase();
+    let text = text.trim_start();
+    let detected = if ["<!doctype html", "<html", "<head", "<body"].iter().any(|tag| text.starts_with(tag)) {
+        Some("an HTML page")
+    } else if text.starts_with("<?xml") {
+        Some("an XML document")
+    } else {
+        None
+    };
+    Ok(detected.map(str::to_string))
+}
+
+fn encoding_error(csv_path: &str, encoding: &SourceEncoding, sample_bytes: Option<u64>) -> std::io::Result<Option<String>> {
+    // Check that the file, or its first sample_bytes bytes, decodes as encoding, describing the first malformed sequence
+    let mut reader = File::open(csv_path)?.take(sample_bytes.unwrap_or(u64::MAX));
+    let mut decoder = encoding.0.new_decoder_without_bom_handling();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut decoded = String::new();
+    // Offset in the file of the first byte not yet given to the decoder
+    let mut offset = 0;
+    loop {
+        let read = reader.read(&mut buffer)?;
+        // An unfinished character at the end of the file is malformed, one cut off by the sample limit is not
+        let last = read == 0 && sample_bytes.is_none_or(|sample_bytes| offset < sample_bytes);
+        let mut input = &buffer[..read];
+        loop {
+            decoded.clear();
+            let (result, consumed) = decoder.decode_to_string_without_replacement(input, &mut decoded, last);
+            input = &input[consumed..];
+            offset += consumed as u64;
+            match result {
+                DecoderResult::InputEmpty => break,
+                DecoderResult::OutputFull => decoded.reserve(64 * 1024),
+                DecoderResult::Malformed(malformed, after) => {
+                    return Ok(Some(format!(
+                        "malformed {} sequence at offset {}",
+                        encoding.0.name(),
+                        offset.saturating_sub(malformed as u64 + after as u64)
+                    )));
+                }
+            }
+        }
+        if read == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+fn schema_violation(
+    csv_path: &str,
+    encoding: &HeaderEncoding,
+    column_types: &[ColumnType],
+    sample_rows: usize,
+    delimiter: char,
+    header_index: usize,
+    extra_columns: bool,
+) -> Option<String> {
+    // Check the first sample_rows data rows against the template column types, describing the first violation.
+    // With extra_columns, rows may have further values after the typed columns, which are not checked.
+    let lines = match decoded_lines(csv_path, encoding) {
+        Ok(lines) => lines,
+        Err(e) => return Some(format!("Failed to read file: {e}")),
+    };
+    let rows = csv_records(lines.skip(header_index + 1), header_index + 1).filter(|(_, record)| {
+        record.as_ref().map(|record| !record.trim().is_empty()).unwrap_or(true)
+    });
+    for (index, record) in rows.take(sample_rows) {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => return Some(format!("Failed to read line {}: {e}", index + 1)),
+        };
+        let values = csv_fields(&record, delimiter);
+        if values.len() < column_types.len() || (!extra_columns && values.len() > column_types.len()) {
+            return Some(format!(
+                "Line {} has {} values, expected {}{}",
+                index + 1,
+                values.len(),
+                if extra_columns { "at least " } else { "" },
+                column_types.len()
+            ));
+        }
+        for (column, (value, column_type)) in values.iter().zip(column_types).enumerate() {
+            if !column_type.accepts(value) {
+                return Some(format!(
+                    "Line {} column {} value {:?} is not {:?}",
+                    index + 1,
+                    column + 1,
+                    value,
+                    column_type
+                ));
+            }
+        }
+    }
+    None
+}
+
+fn is_header_only(csv_path: &str, encoding: &HeaderEncoding, header_index: usize) -> std::io::Result<bool> {
+    // Whether a file ends right after its header line, with at most that line's own newline
+    let mut lines = decoded_lines(csv_path, encoding)?.skip(header_index + 1);
+    Ok(lines.next().transpose()?.is_none())
+}
+
+fn apply_empty_file_policy(cfg: &AppConfig, src_file: &str, table_name: &str, header_index: usize) -> bool {
+    // Apply the empty file policy of the matched table, or HEADER_ONLY_POLICY to a file that is only its header.
+    // Returns true if the file should still be uploaded.
+    let header_only_policy = cfg.header_only_policy.as_ref().filter(|_| {
+        is_header_only(src_file, &cfg.header_encoding, header_index).unwrap_or_else(|e| {
+            error!("Failed to check whether {} is only a header. Error: {}", src_file, e);
+            false
+        })
+    });
+    let (policy, description, reason_code) = match header_only_policy {
+        Some(policy) => (policy, "only its header line", "header_only"),
+        None => (
+            cfg.empty_file_table_policies.get(table_name).unwrap_or(&cfg.empty_file_policy),
+            "0 data rows",
+            "empty_file",
+        ),
+    };
+    let binding = PathBuf::from(src_file);
+    let src_file_basename = binding.file_name().unwrap().to_string_lossy();
+    match policy {
+        EmptyFilePolicy::Upload => {
+            info!("File {} matched table {:?} with {}, uploading anyway", src_file, table_name, description);
+            true
+        }
+        EmptyFilePolicy::Skip => {
+            info!("File {} matched table {:?} with {}, skipping upload", src_file, table_name, description);
+            if let Some(log_dir) = binding.parent() {
+                log_upload_status(
+                    cfg,
+                    log_dir.to_str().unwrap(),
+                    format!("Upload skipped! File: {src_file_basename} Reason: Matched table {table_name} but has {description}."),
+                );
+            }
+            false
+        }
+        EmptyFilePolicy::DeadLetter => {
+            info!("File {} matched table {:?} with {}, moving to dead-letter", src_file, table_name, description);
+            dead_letter_file(
+                cfg,
+                src_file,
+                reason_code,
+                Some(table_name),
+                &format!("Matched table {table_name} but has {description}."),
+            );
+            false
+        }
+    }
+}
+
+fn dead_letter_file(cfg: &AppConfig, src_file: &str, reason_code: &str, table_name: Option<&str>, reason: &str) {
+    // Move a file that will not be uploaded into the dead-letter directory and record why, in upload.log and a json sidecar
+    let dead_letter_dir = match &cfg.dead_letter_dir {
+        Some(dir) if cfg.dead_letter_by_reason => Path::new(dir).join(reason_code),
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            error!("DEAD_LETTER_DIR is not set, leaving {} in place", src_file);
+            return;
+        }
+    };
+    let binding = PathBuf::from(src_file);
+    let src_file_basename = binding.file_name().unwrap().to_string_lossy().to_string();
+    let dead_letter_path = dead_letter_dir.join(&src_file_basename);
+    // Read before the move so the sidecar shows what the matcher saw
+    let header_line = File::open(src_file)
+        .ok()
+        .and_then(|file| BufReader::new(file).lines().next())
+        .and_then(|line| line.ok())
+        .unwrap_or_default();
+    let moved = fs::create_dir_all(&dead_letter_dir).and_then(|_| fs::rename(src_file, &dead_letter_path));
+    let log_msg = match moved {
+        Ok(_) => {
+            info!("Moved {} to dead-letter {:?}", src_file, dead_letter_path);
+            let mut sidecar = serde_json::json!({
+                "file": src_file_basename,
+                "reason_code": reason_code,
+                "reason": reason,
+                "header_line": header_line,
+                "table": table_name,
+                "timestamp": chrono::Local::now().to_rfc3339(),
+                "run_id": logging::run_id(),
+            });
+            if let Some(metadata) = logging::metadata_json() {
+                sidecar["metadata"] = metadata;
+            }
+            if let Err(e) = write_dead_letter_sidecar(&dead_letter_path, &sidecar) {
+                error!("Failed to write dead-letter sidecar for {:?}. Error: {}", dead_letter_path, e);
+            }
+            events::publish("dead_lettered", src_file, sidecar);
+            format!("Upload failed! File: {src_file_basename} Reason: {reason} Moved to dead-letter.")
+        }
+        Err(e) => {
+            error!("Failed to move {} to dead-letter. Error: {}", src_file, e);
+            format!("Upload failed! File: {src_file_basename} Reason: {reason} Dead-letter move failed: {e}")
+        }
+    };
+    if let Some(log_dir) = binding.parent() {
+        log_upload_status(cfg, log_dir.to_str().unwrap(), log_msg);
+    }
+}
+
+fn write_dead_letter_sidecar(dead_letter_path: &Path, sidecar: &serde_json::Value) -> std::io::Result<()> {
+    // Write <file>.json next to a dead-lettered file, renaming into place so triage tools never read a partial file
+    let sidecar_path = PathBuf::from(format!("{}.json", dead_letter_path.display()));
+    let partial_path = PathBuf::from(format!("{}.partial", sidecar_path.display()));
+    fs::write(&partial_path, serde_json::to_string_pretty(sidecar)?)?;
+    fs::rename(&partial_path, &sidecar_path)
+}
+
+fn normalize_headers(csv_headers: &str, header_aliases: &HashMap<String, String>) -> String {
+    // Replace aliased source field names with their canonical names so renamed columns match existing templates
+    if header_aliases.is_empty() {
+        return csv_headers.to_string();
+    }
+    let fields: Vec<&str> = csv_headers
+        .split(',')
+        .map(|field| match header_aliases.get(field.trim()) {
+            Some(canonical) => {
+                info!("Header alias applied: {:?} -> {:?}", field.trim(), canonical);
+                canonical.as_str()
+            }
+            None => field,
+        })
+        .collect();
+    fields.join(",")
+}
+
+fn remove_uploaded_file(cfg: &AppConfig, src_file: &str, src_file_metadata: &str) {
+    match &cfg.delete_staging_dir {
+        Some(staging_dir) => stage_for_deletion(cfg, staging_dir, src_file, src_file_metadata),
+        None => delete_src_file_and_metadata(src_file, src_file_metadata),
+    }
+}
+
+fn remote_file_name(cfg: &AppConfig, table_name: &str, src_file: &str, version: Option<u32>) -> String {
+    // Name a file is stored under on the destination, with its table's compression extension
+    let file_name = dest_file_name(
+        cfg,
+        table_name,
+        &Path::new(src_file).file_name().unwrap().to_string_lossy(),
+        sequence::of(src_file),
+        version,
+    );
+    match cfg.table_compression.get(table_name).unwrap_or(&cfg.compression) {
+        compression @ (Compression::Gzip | Compression::Zstd) => file_name + compressed_extension(compression),
+        _ => file_name,
+    }
+}
+
+fn ack_path(cfg: &AppConfig, table_name: &str, remote_dir: &Path, src_file: &str) -> Option<String> {
+    // Remote ack file an uploaded file waits for, None if its table doesn't wait for acks
+    let ack_file_path = cfg.ack_file_path.as_ref()?;
+    if !cfg.ack_tables.is_empty() && !cfg.ack_tables.iter().any(|ack_table| ack_table == table_name) {
+        return None;
+    }
+    Some(
+        ack_file_path
+            .replace("{dir}", &remote_rsync_path(remote_dir))
+            .replace("{file}", &remote_file_name(cfg, table_name, src_file, remote_exists::version(src_file))),
+    )
+}
+
+fn poll_acks(cfg: &AppConfig) {
+    // Delete the files whose ack file appeared and flag the ones that ran out of time
+    for outcome in ack::poll(|ack_paths| existing_remote_files(cfg, ack_paths)) {
+        match outcome {
+            AckOutcome::Acknowledged(pending) => {
+                info!(
+                    "Ack file {} appeared after {} second(s), deleting {}",
+                    pending.ack_path,
+                    pending.waited().as_secs(),
+                    pending.src_file
+                );
+                remove_uploaded_file(cfg, &pending.src_file, &pending.metadata_file);
+            }
+            AckOutcome::TimedOut(pending) => {
+                warn!(
+                    "No ack file {} after {} second(s), keeping {} in place",
+                    pending.ack_path,
+                    pending.waited().as_secs(),
+                    pending.src_file
+                );
+                let binding = PathBuf::from(&pending.src_file);
+                let src_file_basename = binding.file_name().unwrap().to_string_lossy();
+                if let Some(log_dir) = binding.parent() {
+                    log_upload_status(
+                        cfg,
+                        log_dir.to_str().unwrap(),
+                        format!(
+                            "Ack timed out! File: {src_file_basename} Reason: No ack file {} Kept in place.",
+                            pending.ack_path
+                        ),
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn existing_remote_files(cfg: &AppConfig, paths: &[&str]) -> std::io::Result<HashSet<String>> {
+    // Which of the paths exist on the destination, looked up over a single ssh connection
+    if cfg.dest_is_local && cfg.local_dest_mode != LocalDestMode::Ssh {
+        return Ok(paths.iter().filter(|path| Path::new(path).exists()).map(|path| path.to_string()).collect());
+    }
+    let quoted: Vec<String> = paths.iter().map(|path| shell_quote(path)).collect();
+    let remote_command = format!("for f in {}; do [ -e \"$f\" ] && echo \"$f\"; done; true", quoted.join(" "));
+    let ssh_args = ssh_args(cfg);
+    let output = Command::new(&ssh_args[0])
+        .args(&ssh_args[1..])
+        .arg(format!("{}@{}", cfg.dest_user, cfg.dest_host))
+        .arg(remote_command)
+        .output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).lines().map(str::to_string).collect())
+}
+
+fn delete_src_file_and_metadata(src_file: &str, src_file_metadata: &str) {
+    // Delete source file and metadata after rsync
+    let files_to_remove = vec![src_file, src_file_metadata];
+    info!(
+        "Attempting to delete source file and metadata: {}, {}",
+        src_file, src_file_metadata
+    );
+    for file in files_to_remove {
+        match fs::remove_file(file) {
+            Ok(_) => info!("Successfully removed {}", file),
+            Err(e) => error!("Failed to remove {}: {}", file, e),
+        }
+    }
+}
+
+fn stage_for_deletion(cfg: &AppConfig, staging_dir: &str, src_file: &str, src_file_metadata: &str) {
+    // Move an uploaded file and its metadata into a batch directory named after the current time.
+    // A file that cannot be moved is left in place rather than deleted.
+    let staged_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let batch_dir = Path::new(staging_dir).join(staged_at.to_string());
+    if let Err(e) = fs::create_dir_all(&batch_dir) {
+        error!("Failed to create deletion staging directory {:?}. Error: {}. Leaving {} in place", batch_dir, e, src_file);
+        return;
+    }
+    for file in [src_file, src_file_metadata] {
+        let staged_path = batch_dir.join(Path::new(file).file_name().unwrap());
+        // The staged copy is compressed on its own terms, whatever compression the transfer used
+        let staged = match &cfg.delete_staging_compression {
+            compression @ (Compression::Gzip | Compression::Zstd) if file == src_file => {
+                let staged_path = PathBuf::from(format!("{}{}", staged_path.display(), compressed_extension(compression)));
+                stage_compressed(file, &staged_path, compression).map(|_| (staged_path, compression))
+            }
+            _ => fs::rename(file, &staged_path).map(|_| (staged_path, &Compression::Off)),
+        };
+        match staged {
+            Ok((staged_path, compression)) => info!(
+                "Staged {} for deletion as {:?} with {:?} compression, deleting in {} second(s)",
+                file, staged_path, compression, cfg.delete_confirmation_seconds
+            ),
+            Err(e) => error!("Failed to stage {} for deletion, leaving it in place. Error: {}", file, e),
+        }
+    }
+}
+
+fn stage_compressed(file: &str, staged_path: &Path, compression: &Compression) -> std::io::Result<()> {
+    // Write a compressed copy into the staging directory, then delete the file. A failed copy is removed again.
+    let compressed = File::open(file)
+        .and_then(|mut input| compress_into(&mut input, File::create(staged_path)?, compression))
+        .and_then(|_| fs::remove_file(file));
+    if compressed.is_err() {
+        let _ = fs::remove_file(staged_path);
+    }
+    compressed
+}
+
+fn purge_deletion_staging(cfg: &AppConfig, staging_dir: &str) {
+    // Permanently delete staged batches whose confirmation delay has passed.
+    // Files moved out of a batch directory in the meantime are kept.
+    let entries = match fs::read_dir(staging_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            error!("Failed to read deletion staging directory {}. Error: {}", staging_dir, e);
+            return;
+        }
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    for entry in entries.flatten() {
+        let batch_dir = entry.path();
+        let Some(staged_at) = batch_dir.file_name().and_then(|name| name.to_str()).and_then(|name| name.parse::<u64>().ok()) else {
+            continue;
+        };
+        if now.saturating_sub(staged_at) < cfg.delete_confirmation_seconds || !batch_dir.is_dir() {
+            continue;
+        }
+        let file_count = fs::read_dir(&batch_dir).map(|files| files.count()).unwrap_or(0);
+        match fs::remove_dir_all(&batch_dir) {
+            Ok(_) => info!("Permanently deleted {} staged file(s) from {:?}", file_count, batch_dir),
+            Err(e) => error!("Failed to delete staged files in {:?}. Error: {}", batch_dir, e),
+        }
+    }
+}
+
+fn log_upload_status(cfg: &AppConfig, log_dir: &str, log_msg: String) {
+    // Create an upload log file at specified log directory, or write to the fallback log if that fails
+    let log_file_path = Path::new(log_dir).join("upload.log");
+    let log_time = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    let run = upload_log_run_fields();
+    let e = match append_upload_log(&log_file_path, &format!("{log_time} - {log_msg} {run}\n")) {
+        Ok(_) => {
+            info!("Upload log file updated successfully.");
+            LOST_UPLOAD_STATUSES.store(0, Ordering::Relaxed);
+            return;
+        }
+        Err(e) => e,
+    };
+    let Some(fallback_path) = &cfg.upload_log_fallback else {
+        error!("Failed to write to upload log file {:?}. Error: {}. Lost log message: {}", log_file_path, e, log_msg);
+        upload_status_lost(cfg);
+        return;
+    };
+    // Only the first failure of each directory is reported, a read-only mount would otherwise log on every file
+    static FALLBACK_DIRS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    let mut fallback_dirs = FALLBACK_DIRS.lock().unwrap();
+    if !fallback_dirs.iter().any(|dir| dir == log_dir) {
+        warn!(
+            "Failed to write to upload log file {:?}, writing its statuses to {} instead. Error: {}",
+            log_file_path, fallback_path, e
+        );
+        fallback_dirs.push(log_dir.to_string());
+    }
+    drop(fallback_dirs);
+    let fallback_line = format!("{log_time} - {log_dir}: {log_msg} {run}\n");
+    match append_upload_log(Path::new(fallback_path), &fallback_line) {
+        Ok(_) => LOST_UPLOAD_STATUSES.store(0, Ordering::Relaxed),
+        Err(e) => {
+            error!("Failed to write to fallback upload log {}. Error: {}. Lost log message: {}", fallback_path, e, log_msg);
+            upload_status_lost(cfg);
+        }
+    }
+}
+
+fn upload_log_run_fields() -> String {
+    // "Run: <run ID>", followed by the OUTPUT_METADATA fields in the same "Key: value" form
+    let mut fields = format!("Run: {}", logging::run_id());
+    for (key, value) in logging::output_metadata() {
+        fields.push_str(&format!(" {key}: {value}"));
+    }
+    fields
+}
+
+// Upload statuses in a row that could be written neither to upload.log nor to the fallback
+static LOST_UPLOAD_STATUSES: AtomicU32 = AtomicU32::new(0);
+
+fn upload_status_lost(cfg: &AppConfig) {
+    // Stop once UPLOAD_LOG_FAILURE_LIMIT statuses in a row were lost, rather than keep transferring untracked files
+    let lost = LOST_UPLOAD_STATUSES.fetch_add(1, Ordering::Relaxed) + 1;
+    let Some(limit) = cfg.upload_log_failure_limit else {
+        return;
+    };
+    if lost >= limit {
+        error!(
+            "Lost {} upload statuses in a row, reaching UPLOAD_LOG_FAILURE_LIMIT. Stopping to avoid untracked transfers.",
+            lost
+        );
+        telemetry::shutdown();
+        std::process::exit(1);
+    }
+    warn!("Lost {} of UPLOAD_LOG_FAILURE_LIMIT {} upload statuses in a row", lost, limit);
+}
+
+fn append_upload_log(log_file_path: &Path, line: &str) -> std::io::Result<()> {
+    if let Some(log_dir) = log_file_path.parent() {
+        fs::create_dir_all(log_dir)?;
+    }
+    fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(log_file_path)?
+        .write_all(line.as_bytes())
+}
+
+fn transform_table_name(cfg: &AppConfig, table_name: &str) -> String {
+    // Apply the configured case and prefix/suffix transform to a table name for the remote directory.
+    // A TABLE_REMOTE_DIRS alias is used as it is instead.
+    if let Some(alias) = cfg.table_remote_dirs.get(table_name) {
+        info!("Using remote directory alias {:?} for table {:?}", alias, table_name);
+        return alias.clone();
+    }
+    let mut remote_name = match cfg.table_name_case {
+        Some(NameCase::Upper) => table_name.to_uppercase(),
+        Some(NameCase::Lower) => table_name.to_lowercase(),
+        None => table_name.to_string(),
+    };
+    remote_name = format!("{}{}{}", cfg.table_name_prefix, remote_name, cfg.table_name_suffix);
+    if remote_name != table_name {
+        info!("Transformed table name {:?} to remote name {:?}", table_name, remote_name);
+    }
+    remote_name
+}
+
+fn remote_table_dir(cfg: &AppConfig, templates: &SharedTemplates, table_name: &str) -> PathBuf {
+    // Build the remote directory for a table, optionally nested in template subfolders and a dated folder
+    let mut remote_dir = PathBuf::from(&cfg.dest_dir);
+    if cfg.template_subdir_routing {
+        // A table whose template was removed since its files were matched keeps its subdirectory
+        let templates = templates.read().unwrap();
+        if let Some(rel_dir) = templates
+            .tables
+            .get(table_name)
+            .map(|template| &template.rel_dir)
+            .or_else(|| templates.retired_dirs.get(table_name))
+        {
+            remote_dir = remote_dir.join(rel_dir);
+        }
+    }
+    remote_dir = remote_dir.join(transform_table_name(cfg, table_name));
+    if let Some(date_format) = &cfg.dest_date_folder {
+        let date_folder = match cfg.dest_date_tz {
+            Some(tz) => chrono::Utc::now().with_timezone(&tz).format(date_format).to_string(),
+            None => chrono::Local::now().format(date_format).to_string(),
+        };
+        info!("Using dated destination folder {:?} for table {:?}", date_folder, table_name);
+        remote_dir = remote_dir.join(date_folder);
+    }
+    remote_dir
+}
+
+fn local_rsync_path(path: &str) -> String {
+    // Windows rsync builds (e.g. cwRsync) expect cygwin style paths, a drive letter would be read as a remote host
+    if !cfg!(windows) {
+        return path.to_string();
+    }
+    let path = path.replace('\\', "/");
+    let mut chars = path.chars();
+    match (chars.next(), chars.next()) {
+        (Some(drive), Some(':')) if drive.is_ascii_alphabetic() => {
+            format!("/cygdrive/{}{}", drive.to_ascii_lowercase(), chars.as_str())
+        }
+        _ => path,
+    }
+}
+
+fn remote_rsync_path(path: &Path) -> String {
+    // The destination is always a POSIX host, so use forward slashes whatever the local separator is
+    let path = path.to_string_lossy();
+    if cfg!(windows) {
+        path.replace('\\', "/")
+    } else {
+        path.to_string()
+    }
+}
+
+enum Transfer {
+    // Carries the tolerated non-zero rsync exit code, if any, and rsync's --stats summary if it was run with --stats
+    Succeeded(Option<i32>, Option<RsyncStats>),
+    // Carries the error output
+    Failed(String),
+}
+
+#[derive(Clone, Copy)]
+struct RsyncStats {
+    bytes_sent: u64,
+    bytes_received: u64,
+    // Size of the files rsync was given, whether or not it had to send them
+    total_file_size: u64,
+}
+
+impl RsyncStats {
+    fn speedup(&self) -> Option<f64> {
+        // rsync's own figure: how much less was sent and received than the files' size
+        let traffic = self.bytes_sent + self.bytes_received;
+        (traffic > 0).then(|| self.total_file_size as f64 / traffic as f64)
+    }
+
+    fn add(self, other: RsyncStats) -> RsyncStats {
+        RsyncStats {
+            bytes_sent: self.bytes_sent + other.bytes_sent,
+            bytes_received: self.bytes_received + other.bytes_received,
+            total_file_size: self.total_file_size + other.total_file_size,
+        }
+    }
+}
+
+fn routing_key(cfg: &AppConfig, src_file: &str) -> Option<String> {
+    // The key FILENAME_ROUTING_PATTERN captures from a file's name, if it matches
+    let pattern = cfg.filename_routing_pattern.as_ref()?;
+    let file_name = Path::new(src_file).file_name()?.to_string_lossy();
+    Some(pattern.captures(&file_name)?.get(1)?.as_str().to_string())
+}
+
+fn source_namespace(cfg: &AppConfig, src_file: &str) -> Option<String> {
+    // The SOURCE_NAMESPACE folder of a file: the SOURCE_DIR subdirectory it arrived in, or that subdirectory's label.
+    // Files directly in SOURCE_DIR have none.
+    if cfg.source_namespace == SourceNamespace::Off {
+        return None;
+    }
+    let source = source_subdir(cfg, Path::new(src_file))?;
+    match cfg.source_namespace {
+        SourceNamespace::Label => Some(cfg.source_labels.get(&source).cloned().unwrap_or(source)),
+        _ => Some(source),
+    }
+}
+
+fn source_subdir(cfg: &AppConfig, src_file: &Path) -> Option<String> {
+    // The SOURCE_DIR subdirectory a file arrived in, None for files directly in SOURCE_DIR
+    let relative_path = match src_file.strip_prefix(&cfg.src_dir) {
+        Ok(relative_path) => relative_path.to_path_buf(),
+        Err(_) => src_file.strip_prefix(fs::canonicalize(&cfg.src_dir).ok()?).ok()?.to_path_buf(),
+    };
+    let mut components = relative_path.components();
+    let source = components.next()?.as_os_str().to_string_lossy().to_string();
+    components.next()?;
+    Some(source)
+}
+
+fn routed_remote_dir(cfg: &AppConfig, remote_dir: &Path, src_file: &str) -> PathBuf {
+    // Move a table directory under DEST_DIR to the destination FILENAME_ROUTES gives the file's routing key, if any,
+    // nested in the file's SOURCE_NAMESPACE folder
+    let dest_dir = routing_key(cfg, src_file)
+        .and_then(|key| cfg.filename_routes.get(&key))
+        .unwrap_or(&cfg.dest_dir);
+    let mut routed_dir = PathBuf::from(dest_dir);
+    if let Some(namespace) = source_namespace(cfg, src_file) {
+        routed_dir.push(namespace);
+    }
+    match remote_dir.strip_prefix(&cfg.dest_dir) {
+        Ok(table_path) => routed_dir.join(table_path),
+        Err(_) => remote_dir.to_path_buf(),
+    }
+}
+
+fn transfer_routed_files(
+    cfg: &AppConfig,
+    table_name: &str,
+    src_files: &[String],
+    metadata_files: &[String],
+    remote_dir: &Path,
+) -> std::io::Result<Transfer> {
+    // Send the files of one table in one transfer per destination their file names and sources route them to.
+    // The table succeeds only if every transfer does, so a failure retries all its files.
+    let mut routes: Vec<(PathBuf, Vec<String>, Vec<String>)> = Vec::new();
+    if cfg.filename_routing_pattern.is_none() && cfg.source_namespace == SourceNamespace::Off {
+        routes.push((remote_dir.to_path_buf(), src_files.to_vec(), metadata_files.to_vec()));
+    } else {
+        for (src_file, metadata_file) in src_files.iter().zip(metadata_files) {
+            let routed_dir = routed_remote_dir(cfg, remote_dir, src_file);
+            match routing_key(cfg, src_file) {
+                Some(key) if cfg.filename_routes.contains_key(&key) => {
+                    info!("Routing {} by its filename key {:?} to {:?}", src_file, key, routed_dir)
+                }
+                Some(key) => info!("Filename key {:?} of {} has no route, sending it to {:?}", key, src_file, routed_dir),
+                None if cfg.filename_routing_pattern.is_some() => {
+                    debug!("{} doesn't match FILENAME_ROUTING_PATTERN, sending it to {:?}", src_file, routed_dir)
+                }
+                None => (),
+            }
+            if let Some(namespace) = source_namespace(cfg, src_file) {
+                info!("Namespacing {} by its source as {:?}, sending it to {:?}", src_file, namespace, routed_dir);
+            }
+            match routes.iter_mut().find(|(dir, _, _)| *dir == routed_dir) {
+                Some((_, route_src_files, route_metadata_files)) => {
+                    route_src_files.push(src_file.clone());
+                    route_metadata_files.push(metadata_file.clone());
+                }
+                None => routes.push((routed_dir, vec![src_file.clone()], vec![metadata_file.clone()])),
+            }
+        }
+    }
+    let (mut tolerated_code, mut rsync_stats): (Option<i32>, Option<RsyncStats>) = (None, None);
+    for (routed_dir, route_src_files, route_metadata_files) in routes {
+        let (route_src_files, route_metadata_files) =
+            resolve_remote_exists(cfg, table_name, route_src_files, route_metadata_files, &routed_dir)?;
+        if route_src_files.is_empty() {
+            continue;
+        }
+        match transfer_table_files(cfg, table_name, &route_src_files, &route_metadata_files, &routed_dir)? {
+            Transfer::Succeeded(code, stats) => {
+                tolerated_code = code.or(tolerated_code);
+                rsync_stats = match (rsync_stats, stats) {
+                    (Some(total), Some(stats)) => Some(total.add(stats)),
+                    (total, stats) => total.or(stats),
+                };
+            }
+            failed => return Ok(failed),
+        }
+    }
+    Ok(Transfer::Succeeded(tolerated_code, rsync_stats))
+}
+
+// Highest _v<N> counter REMOTE_EXISTS_POLICY=version tries before failing the transfer
+const MAX_REMOTE_VERSIONS: u32 = 99;
+
+fn resolve_remote_exists(
+    cfg: &AppConfig,
+    table_name: &str,
+    src_files: Vec<String>,
+    metadata_files: Vec<String>,
+    remote_dir: &Path,
+) -> std::io::Result<(Vec<String>, Vec<String>)> {
+    // Apply the table's REMOTE_EXISTS_POLICY to the files about to be sent to a directory, returning the ones to send.
+    // Skipped and versioned files are recorded for handle_transfer_result and the destination names.
+    src_files.iter().for_each(|src_file| remote_exists::forget(src_file));
+    let policy = cfg.remote_exists_table_policies.get(table_name).unwrap_or(&cfg.remote_exists_policy);
+    if *policy == RemoteExistsPolicy::Overwrite {
+        return Ok((src_files, metadata_files));
+    }
+    let dir = remote_rsync_path(remote_dir);
+    let remote_path = |src_file: &str, version: Option<u32>| {
+        format!("{}/{}", dir, remote_file_name(cfg, table_name, src_file, version))
+    };
+    let remote_paths: Vec<String> = src_files.iter().map(|src_file| remote_path(src_file, None)).collect();
+    let existing = existing_remote_files(cfg, &remote_paths.iter().map(String::as_str).collect::<Vec<_>>())?;
+    let (mut send_src_files, mut send_metadata_files) = (Vec::new(), Vec::new());
+    let existing_count = existing.len();
+    for ((src_file, metadata_file), remote_path_taken) in src_files.into_iter().zip(metadata_files).zip(remote_paths) {
+        if !existing.contains(&remote_path_taken) {
+            send_src_files.push(src_file);
+            send_metadata_files.push(metadata_file);
+            continue;
+        }
+        match policy {
+            RemoteExistsPolicy::Skip => {
+                info!("{} already exists on {}, skipping {}", remote_path_taken, cfg.dest_host, src_file);
+                remote_exists::decide(&src_file, remote_exists::Decision::Skipped(remote_path_taken));
+            }
+            _ => {
+                let candidates: Vec<String> =
+                    (1..=MAX_REMOTE_VERSIONS).map(|version| remote_path(&src_file, Some(version))).collect();
+                let taken = existing_remote_files(cfg, &candidates.iter().map(String::as_str).collect::<Vec<_>>())?;
+                let Some((version, versioned_path)) =
+                    (1..=MAX_REMOTE_VERSIONS).zip(&candidates).find(|(_, candidate)| !taken.contains(*candidate))
+                else {
+                    return Err(std::io::Error::other(format!(
+                        "{} and all its {} versions already exist on {}",
+                        remote_path_taken, MAX_REMOTE_VERSIONS, cfg.dest_host
+                    )));
+                };
+                info!("{} already exists on {}, sending {} as {}", remote_path_taken, cfg.dest_host, src_file, versioned_path);
+                remote_exists::decide(&src_file, remote_exists::Decision::Versioned(version));
+                send_src_files.push(src_file);
+                send_metadata_files.push(metadata_file);
+            }
+        }
+    }
+    info!(
+        "REMOTE_EXISTS_POLICY {} for table {:?}: {} file(s) already exist in {}",
+        if *policy == RemoteExistsPolicy::Skip { "skip" } else { "version" },
+        table_name,
+        existing_count,
+        dir
+    );
+    Ok((send_src_files, send_metadata_files))
+}
+
+fn transfer_table_files(
+    cfg: &AppConfig,
+    table_name: &str,
+    src_files: &[String],
+    metadata_files: &[String],
+    remote_dir: &Path,
+) -> std::io::Result<Transfer> {
+    // Send the files of one table, under their destination names if the table renames them
+    if !cfg.table_dest_filename_case.contains_key(table_name)
+        && !cfg.table_dest_filename_extension.contains_key(table_name)
+        && !cfg.sequence_in_filename
+        && src_files.iter().all(|src_file| remote_exists::version(src_file).is_none())
+    {
+        return send_table_files(cfg, table_name, src_files, metadata_files, remote_dir);
+    }
+    let staging_dir = temp_dir(cfg).join(format!("rsync_csv_{}_{}", std::process::id(), table_name));
+    fs::create_dir_all(&staging_dir)?;
+    let transfer = stage_dest_files(cfg, table_name, src_files, metadata_files, &staging_dir).and_then(
+        |(staged_src_files, staged_metadata_files)| {
+            let transfer = send_table_files(cfg, table_name, &staged_src_files, &staged_metadata_files, remote_dir);
+            staged_src_files.iter().for_each(|staged_src_file| preambles::forget(staged_src_file));
+            transfer
+        },
+    );
+    // Staged files are links or copies, the source files are untouched
+    if let Err(e) = fs::remove_dir_all(&staging_dir) {
+        error!("Failed to remove staging directory {:?}. Error: {}", staging_dir, e);
+    }
+    transfer
+}
+
+fn stage_dest_files(
+    cfg: &AppConfig,
+    table_name: &str,
+    src_files: &[String],
+    metadata_files: &[String],
+    staging_dir: &Path,
+) -> std::io::Result<(Vec<String>, Vec<String>)> {
+    // Link (or copy) each file into the staging directory under its destination name
+    let mut staged_src_files: Vec<String> = Vec::new();
+    let mut staged_metadata_files: Vec<String> = Vec::new();
+    for (src_file, metadata_file) in src_files.iter().zip(metadata_files) {
+        let src_file_basename = Path::new(src_file).file_name().unwrap().to_string_lossy().to_string();
+        let dest_name = dest_file_name(
+            cfg,
+            table_name,
+            &src_file_basename,
+            sequence::of(src_file),
+            remote_exists::version(src_file),
+        );
+        info!("Sending {} to table {:?} as {}", src_file, table_name, dest_name);
+        staged_src_files.push(stage_file(src_file, &staging_dir.join(&dest_name))?);
+        preambles::assign(staged_src_files.last().unwrap(), preambles::lines(src_file));
+        // The metadata file follows the csv's new name, its content still records the original name
+        staged_metadata_files.push(stage_file(metadata_file, &staging_dir.join(format!("{dest_name}.metadata")))?);
+    }
+    Ok((staged_src_files, staged_metadata_files))
+}
+
+fn stage_file(src_file: &str, staged_path: &Path) -> std::io::Result<String> {
+    if fs::hard_link(src_file, staged_path).is_err() {
+        // Hard links fail across filesystems
+        fs::copy(src_file, staged_path)?;
+    }
+    Ok(staged_path.to_string_lossy().to_string())
+}
+
+fn dest_file_name(
+    cfg: &AppConfig,
+    table_name: &str,
+    file_name: &str,
+    sequence: Option<u64>,
+    version: Option<u32>,
+) -> String {
+    // Apply the table's destination file name case and extension, the SEQUENCE_IN_FILENAME prefix and the
+    // REMOTE_EXISTS_POLICY version counter
+    let file_name = match cfg.table_dest_filename_case.get(table_name) {
+        Some(NameCase::Upper) => file_name.to_uppercase(),
+        Some(NameCase::Lower) => file_name.to_lowercase(),
+        None => file_name.to_string(),
+    };
+    let file_name = match sequence.filter(|_| cfg.sequence_in_filename) {
+        // Zero padded, so remote names sort in upload order
+        Some(sequence) => format!("{sequence:012}_{file_name}"),
+        None => file_name,
+    };
+    let file_name = match cfg.table_dest_filename_extension.get(table_name) {
+        // Passthrough files keep their own extension
+        Some(_) if is_passthrough_file(cfg, Path::new(&file_name)) => file_name,
+        Some(extension) => Path::new(&file_name).with_extension(extension).to_string_lossy().to_string(),
+        None => file_name,
+    };
+    match version {
+        // Before the extension, so the versioned file still opens like the others
+        Some(version) => match file_name.rsplit_once('.') {
+            Some((stem, extension)) if !stem.is_empty() => format!("{stem}_v{version}.{extension}"),
+            _ => format!("{file_name}_v{version}"),
+        },
+        None => file_name,
+    }
+}
+
+fn send_table_files(
+    cfg: &AppConfig,
+    table_name: &str,
+    src_files: &[String],
+    metadata_files: &[String],
+    remote_dir: &Path,
+) -> std::io::Result<Transfer> {
+    // Send the files of one table to their destination directory, compressing them and adding checksums if configured
+    let compression = cfg.table_compression.get(table_name).unwrap_or(&cfg.compression);
+    info!("Using {:?} compression for table {:?}", compression, table_name);
+    if cfg.sftp.stream_uploads
+        && uses_sftp(cfg)
+        && matches!(compression, Compression::Gzip | Compression::Zstd)
+        && cfg.dry_run == DryRun::Off
+    {
+        return Ok(stream_table_files(cfg, src_files, metadata_files, remote_dir, compression));
+    }
+    // Merged files and split parts are never written next to the source files, where they would be picked up as new csv files
+    let writes_work_files =
+        cfg.transform_temp_dir.is_some() || cfg.split_max_rows.is_some() || cfg.merge_window_seconds.is_some();
+    if !writes_work_files {
+        return transform_and_send(cfg, table_name, src_files, metadata_files, remote_dir, compression, None);
+    }
+    let work_root = temp_dir(cfg);
+    // One directory per transfer, removed afterwards whether or not the transfer succeeded
+    let work_dir = work_root.join(format!("rsync_csv_transform_{}_{}", std::process::id(), table_name));
+    fs::create_dir_all(&work_dir)?;
+    let transfer = transform_and_send(cfg, table_name, src_files, metadata_files, remote_dir, compression, Some(&work_dir));
+    if let Err(e) = fs::remove_dir_all(&work_dir) {
+        error!("Failed to remove transform directory {:?}. Error: {}", work_dir, e);
+    }
+    transfer
+}
+
+fn transform_and_send(
+    cfg: &AppConfig,
+    table_name: &str,
+    src_files: &[String],
+    metadata_files: &[String],
+    remote_dir: &Path,
+    compression: &Compression,
+    work_dir: Option<&Path>,
+) -> std::io::Result<Transfer> {
+    // Write the merged files, split parts, compressed copies and checksum sidecars of the files, into work_dir if
+    // set or else next to the source files, and send them.
+    // Files are paired with their lines before the header, which merged files and split parts keep.
+    let src_files: Vec<(String, usize)> =
+        src_files.iter().map(|src_file| (src_file.clone(), preambles::lines(src_file))).collect();
+    let merged_src_files = match (cfg.merge_window_seconds, work_dir) {
+        (Some(_), Some(work_dir)) => {
+            let (small_file_bytes, max_bytes) = (cfg.merge_small_file_bytes, cfg.merge_max_bytes);
+            merge_small_files(table_name, &src_files, &cfg.header_encoding, small_file_bytes, max_bytes, work_dir)?
+        }
+        _ => src_files,
+    };
+    let (split_src_files, split_files) = match (cfg.split_max_rows, work_dir) {
+        (Some(max_rows), Some(work_dir)) => {
+            split_oversized_files(&merged_src_files, &cfg.header_encoding, max_rows, work_dir)?
+        }
+        _ => (merged_src_files.into_iter().map(|(src_file, _)| src_file).collect(), Vec::new()),
+    };
+    let src_files = split_src_files.as_slice();
+    let mut compressed_files: Vec<String> = Vec::new();
+    if let Compression::Gzip | Compression::Zstd = compression {
+        for src_file in src_files {
+            match compress_file(src_file, compression, work_dir) {
+                Ok(compressed_file) => compressed_files.push(compressed_file),
+                Err(e) => {
+                    remove_temporary_files(&compressed_files);
+                    return Err(e);
+                }
+            }
+        }
+    }
+    let data_files = if compressed_files.is_empty() {
+        src_files
+    } else {
+        &compressed_files
+    };
+    // Checksum sidecars go in the same transfer, so a failure fails the csv and its checksum together
+    let mut checksum_files: Vec<String> = Vec::new();
+    if let Some(algorithm) = &cfg.checksum_algorithm {
+        for data_file in data_files {
+            match write_checksum_file(data_file, algorithm, &cfg.checksum_suffix, work_dir) {
+                Ok(checksum_file) => checksum_files.push(checksum_file),
+                Err(e) => {
+                    remove_temporary_files(&compressed_files);
+                    remove_temporary_files(&checksum_files);
+                    return Err(e);
+                }
+            }
+        }
+    }
+    let files: Vec<&String> = data_files.iter().chain(&checksum_files).chain(metadata_files).collect();
+    let transfer = send_files(cfg, Some(table_name), &files, remote_dir, matches!(compression, Compression::Rsync));
+    if cfg.compression_stats {
+        // Pre-compression is measured from the compressed copies, rsync compression from its --stats output
+        let sizes = match (compression, &transfer) {
+            (Compression::Gzip | Compression::Zstd, Ok(Transfer::Succeeded(..))) => {
+                Some((total_size(src_files), total_size(&compressed_files)))
+            }
+            (Compression::Rsync, Ok(Transfer::Succeeded(_, Some(stats)))) => {
+                Some((total_size(files.iter().copied()), stats.bytes_sent))
+            }
+            _ => None,
+        };
+        if let Some((uncompressed, transferred)) = sizes {
+            log_compression_savings(table_name, compression, uncompressed, transferred);
+        }
+    }
+    if let (true, Ok(Transfer::Succeeded(_, Some(stats)))) = (cfg.rsync_speedup_stats, &transfer) {
+        log_rsync_speedup(table_name, stats);
+    }
+    if let Ok(Transfer::Succeeded(..)) = &transfer {
+        for (src_file, part_count) in &split_files {
+            info!("Sent all {} part(s) of {}", part_count, src_file);
+        }
+    }
+    remove_temporary_files(&compressed_files);
+    remove_temporary_files(&checksum_files);
+    transfer
+}
+
+// A merged file being written by merge_small_files
+struct MergedFile {
+    path: String,
+    encoding: &'static Encoding,
+    // Lines before the header, those of its first file, and the header line, compared with those of the files
+    // appended to it as comparable_lines gives them
+    preamble_lines: usize,
+    preamble: Vec<Vec<u8>>,
+    header_line: Vec<Vec<u8>>,
+    size: u64,
+    // The files in it, with their lines before the header
+    parts: Vec<(String, usize)>,
+}
+
+fn merge_small_files(
+    table_name: &str,
+    src_files: &[(String, usize)],
+    encoding: &HeaderEncoding,
+    small_file_bytes: u64,
+    max_bytes: u64,
+    work_dir: &Path,
+) -> std::io::Result<Vec<(String, usize)>> {
+    // Concatenate the table's files of at most small_file_bytes into merged files in work_dir of up to max_bytes,
+    // keeping the header line, and any lines before it, of the first file only. A file whose header line or
+    // encoding differs from the merged file's starts a new one. Returns the files to send with their lines before
+    // the header.
+    let mut files: Vec<(String, usize)> = Vec::new();
+    let mut merged: Option<MergedFile> = None;
+    let mut merged_files: Vec<MergedFile> = Vec::new();
+    for (src_file, preamble_lines) in src_files {
+        let contents = fs::read(src_file)?;
+        if contents.len() as u64 > small_file_bytes {
+            files.push((src_file.clone(), *preamble_lines));
+            continue;
+        }
+        // Header lines are found by their bytes, which a UTF-16 file doesn't have one per character
+        let file_encoding = templates::file_encoding(src_file, encoding)?;
+        if !file_encoding.is_ascii_compatible() {
+            warn!("Not merging {}, its {} encoding is not ASCII compatible", src_file, file_encoding.name());
+            files.push((src_file.clone(), *preamble_lines));
+            continue;
+        }
+        let (header, header_start) = read_header_lines(&mut contents.as_slice(), *preamble_lines)?;
+        let preamble = comparable_lines(&header[..header_start]);
+        let header_line = comparable_lines(&header[header_start..]);
+        let rows = &contents[header.len()..];
+        let line_break: &[u8] = if header.ends_with(b"\r\n") { b"\r\n" } else { b"\n" };
+        if let Some(merged_file) = &merged {
+            if merged_file.header_line != header_line
+                || merged_file.encoding != file_encoding
+                || merged_file.size + rows.len() as u64 > max_bytes
+            {
+                merged_files.extend(merged.take());
+            }
+        }
+        match &merged {
+            Some(merged_file) if !preamble.is_empty() && preamble != merged_file.preamble => warn!(
+                "Dropping the {} line(s) before the header of {} merged into {}, which keeps those of {}: {:?}",
+                preamble_lines,
+                src_file,
+                Path::new(&merged_file.path).file_name().unwrap().to_string_lossy(),
+                merged_file.parts[0].0,
+                String::from_utf8_lossy(&header[..header_start])
+            ),
+            _ => (),
+        }
+        if merged.is_none() {
+            let path = Path::new(src_file);
+            let merged_path = work_dir.join(format!(
+                "{}_merged{}",
+                path.file_stem().unwrap().to_string_lossy(),
+                path.extension().map(|extension| format!(".{}", extension.to_string_lossy())).unwrap_or_default()
+            ));
+            let mut merged_file = File::create(&merged_path)?;
+            merged_file.write_all(&header)?;
+            if !header.ends_with(b"\n") {
+                merged_file.write_all(line_break)?;
+            }
+            merged = Some(MergedFile {
+                path: merged_path.to_string_lossy().to_string(),
+                encoding: file_encoding,
+                preamble_lines: *preamble_lines,
+                preamble,
+                header_line,
+                size: header.len() as u64,
+                parts: Vec::new(),
+            });
+        }
+        let merged_file = merged.as_mut().unwrap();
+        let mut file = fs::OpenOptions::new().append(true).open(&merged_file.path)?;
+        file.write_all(rows)?;
+        if !rows.is_empty() && !rows.ends_with(b"\n") {
+            file.write_all(line_break)?;
+        }
+        merged_file.size += rows.len() as u64;
+        merged_file.parts.push((src_file.clone(), *preamble_lines));
+    }
+    merged_files.extend(merged);
+    for MergedFile { path: merged_path, preamble_lines, size, parts, .. } in merged_files {
+        if parts.len() == 1 {
+            // Nothing to merge it with, sent as it is
+            files.push(parts[0].clone());
+            continue;
+        }
+        info!(
+            "Merged {} file(s) of table {:?} into {} ({} bytes): {:?}",
+            parts.len(),
+            table_name,
+            Path::new(&merged_path).file_name().unwrap().to_string_lossy(),
+            size,
+            parts.iter().map(|(part, _)| Path::new(part).file_name().unwrap().to_string_lossy()).collect::<Vec<_>>()
+        );
+        files.push((merged_path, preamble_lines));
+    }
+    Ok(files)
+}
+
+// Source files that were split, with their number of parts
+type SplitFiles = Vec<(String, usize)>;
+
+fn read_header_lines(reader: &mut impl BufRead, preamble_lines: usize) -> std::io::Result<(Vec<u8>, usize)> {
+    // Read a file up to and including its header line, after preamble_lines lines. Returns the lines read as they
+    // are, and the offset of the header line in them.
+    let (mut lines, mut header_start) = (Vec::new(), 0);
+    for _ in 0..=preamble_lines {
+        header_start = lines.len();
+        if reader.read_until(b'\n', &mut lines)? == 0 {
+            break;
+        }
+    }
+    Ok((lines, header_start))
+}
+
+fn comparable_lines(lines: &[u8]) -> Vec<Vec<u8>> {
+    // Lines without their line breaks and any UTF-8 BOM, for comparing the header lines of files
+    if lines.is_empty() {
+        return Vec::new();
+    }
+    let lines = lines.strip_prefix(b"\xef\xbb\xbf").unwrap_or(lines);
+    let lines = lines.strip_suffix(b"\n").unwrap_or(lines);
+    lines.split(|&byte| byte == b'\n').map(|line| line.strip_suffix(b"\r").unwrap_or(line).to_vec()).collect()
+}
+
+fn read_csv_record(reader: &mut impl BufRead, record: &mut Vec<u8>) -> std::io::Result<usize> {
+    // Append the next csv record, with its line break, reading on while one of its quoted fields is still open.
+    // Only for encodings where quotes and line breaks are the ASCII bytes. Returns the number of bytes read.
+    let start = record.len();
+    while reader.read_until(b'\n', record)? > 0 {
+        // Escaped quotes come in pairs, so an odd count leaves a field open
+        if record[start..].iter().filter(|&&byte| byte == b'"').count() % 2 == 0 {
+            break;
+        }
+    }
+    Ok(record.len() - start)
+}
+
+fn split_oversized_files(
+    src_files: &[(String, usize)],
+    encoding: &HeaderEncoding,
+    max_rows: usize,
+    work_dir: &Path,
+) -> std::io::Result<(Vec<String>, SplitFiles)> {
+    // Replace every file with more than max_rows data rows by numbered parts in work_dir, each starting with the
+    // file's header line and the lines before it. Rows are csv records, so a quoted field with line breaks stays in
+    // one part. Returns the files to send, and the number of parts of each split file.
+    let (mut files, mut split_files) = (Vec::new(), Vec::new());
+    for (src_file, preamble_lines) in src_files {
+        // Rows are found by their bytes, which a UTF-16 file doesn't have one per character
+        let file_encoding = templates::file_encoding(src_file, encoding)?;
+        if !file_encoding.is_ascii_compatible() {
+            warn!("Not splitting {}, its {} encoding is not ASCII compatible", src_file, file_encoding.name());
+            files.push(src_file.clone());
+            continue;
+        }
+        let mut reader = BufReader::new(File::open(src_file)?);
+        read_header_lines(&mut reader, *preamble_lines)?;
+        let (mut rows, mut row) = (0, Vec::new());
+        while read_csv_record(&mut reader, &mut row)? > 0 {
+            rows += 1;
+            row.clear();
+        }
+        if rows <= max_rows {
+            files.push(src_file.clone());
+            continue;
+        }
+        let path = Path::new(src_file);
+        let stem = path.file_stem().unwrap().to_string_lossy();
+        let extension = path.extension().map(|extension| format!(".{}", extension.to_string_lossy())).unwrap_or_default();
+        let mut reader = BufReader::new(File::open(src_file)?);
+        let (header, _) = read_header_lines(&mut reader, *preamble_lines)?;
+        let mut parts: Vec<String> = Vec::new();
+        let mut part: Option<File> = None;
+        let mut part_rows = 0;
+        while read_csv_record(&mut reader, &mut row)? > 0 {
+            if part.is_none() || part_rows == max_rows {
+                let part_path = work_dir.join(format!("{}_part{:03}{}", stem, parts.len() + 1, extension));
+                let mut part_file = File::create(&part_path)?;
+                part_file.write_all(&header)?;
+                parts.push(part_path.to_string_lossy().to_string());
+                part = Some(part_file);
+                part_rows = 0;
+            }
+            part.as_mut().unwrap().write_all(&row)?;
+            part_rows += 1;
+            row.clear();
+        }
+        info!(
+            "Split {} ({} rows) into {} part(s) of up to {} rows: {:?}",
+            src_file,
+            rows,
+            parts.len(),
+            max_rows,
+            parts.iter().map(|part| Path::new(part).file_name().unwrap().to_string_lossy()).collect::<Vec<_>>()
+        );
+        split_files.push((src_file.clone(), parts.len()));
+        files.extend(parts);
+    }
+    Ok((files, split_files))
+}
+
+fn log_rsync_speedup(table_name: &str, stats: &RsyncStats) {
+    // A speedup close to 1 means rsync's delta transfer saves nothing, e.g. for files that change completely
+    if let Some(speedup) = stats.speedup() {
+        info!(
+            "rsync speedup for table {:?}: {:.2} ({} bytes of files, {} bytes sent, {} bytes received)",
+            table_name, speedup, stats.total_file_size, stats.bytes_sent, stats.bytes_received
+        );
+    }
+    volume::record_speedup(table_name, stats.total_file_size, stats.bytes_sent + stats.bytes_received);
+}
+
+fn total_size<'a>(files: impl IntoIterator<Item = &'a String>) -> u64 {
+    files.into_iter().filter_map(|file| fs::metadata(file).ok()).map(|metadata| metadata.len()).sum()
+}
+
+fn log_compression_savings(table_name: &str, compression: &Compression, uncompressed: u64, transferred: u64) {
+    if uncompressed > 0 {
+        let saved = 100.0 - transferred as f64 * 100.0 / uncompressed as f64;
+        info!(
+            "{:?} compression for table {:?}: {} bytes uncompressed, {} bytes transferred, {:.1}% saved",
+            compression, table_name, uncompressed, transferred, saved
+        );
+    }
+    volume::record_compression(table_name, uncompressed, transferred);
+}
+
+fn stream_table_files(
+    cfg: &AppConfig,
+    src_files: &[String],
+    metadata_files: &[String],
+    remote_dir: &Path,
+    compression: &Compression,
+) -> Transfer {
+    // Compress each file while it is uploaded over sftp, so no compressed copy is written to disk.
+    // Checksums are taken from the same compressed stream and uploaded right after their data file.
+    let digests: Vec<RefCell<String>> = src_files.iter().map(|_| RefCell::new(String::new())).collect();
+    let mut streams: Vec<sftp::Stream> = Vec::new();
+    for (src_file, digest) in src_files.iter().zip(&digests) {
+        let data_name = format!(
+            "{}{}",
+            Path::new(src_file).file_name().unwrap().to_string_lossy(),
+            compressed_extension(compression)
+        );
+        let algorithm = cfg.checksum_algorithm.as_ref();
+        streams.push(sftp::Stream {
+            remote_name: data_name.clone(),
+            write: Box::new(move |remote_file| {
+                let mut input = File::open(src_file)?;
+                match algorithm {
+                    Some(algorithm) => {
+                        let mut output = HashingWriter { inner: remote_file, hasher: Hasher::new(algorithm) };
+                        compress_into(&mut input, &mut output, compression)?;
+                        *digest.borrow_mut() = output.hasher.finalize();
+                    }
+                    None => compress_into(&mut input, remote_file, compression)?,
+                }
+                debug!("Compressed {} while uploading it", src_file);
+                Ok(())
+            }),
+        });
+        if let Some(algorithm) = algorithm {
+            streams.push(sftp::Stream {
+                remote_name: format!("{data_name}{}", checksum_suffix(algorithm, &cfg.checksum_suffix)),
+                write: Box::new(move |remote_file| writeln!(remote_file, "{}  {}", digest.borrow(), data_name)),
+            });
+        }
+    }
+    streams.extend(metadata_files.iter().map(sftp::file_stream));
+    match sftp::upload_streams(cfg, streams, &remote_rsync_path(remote_dir)) {
+        Ok(_) => Transfer::Succeeded(None, None),
+        Err(e) => Transfer::Failed(e),
+    }
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    fn new(algorithm: &ChecksumAlgorithm) -> Hasher {
+        match algorithm {
+            ChecksumAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            ChecksumAlgorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finalize(self) -> String {
+        // Lowercase hex, as written by sha256sum
+        match self {
+            Hasher::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+            Hasher::Sha512(hasher) => format!("{:x}", hasher.finalize()),
+        }
+    }
+}
+
+// Passes writes through while hashing everything written
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn checksum_suffix<'a>(algorithm: &ChecksumAlgorithm, suffix: &'a Option<String>) -> &'a str {
+    suffix.as_deref().unwrap_or(match algorithm {
+        ChecksumAlgorithm::Sha256 => ".sha256",
+        ChecksumAlgorithm::Sha512 => ".sha512",
+    })
+}
+
+fn write_checksum_file(
+    data_file: &str,
+    algorithm: &ChecksumAlgorithm,
+    suffix: &Option<String>,
+    work_dir: Option<&Path>,
+) -> std::io::Result<String> {
+    // Write a sidecar in sha256sum format next to a file, returning the sidecar's path
+    let mut output = HashingWriter { inner: std::io::sink(), hasher: Hasher::new(algorithm) };
+    std::io::copy(&mut File::open(data_file)?, &mut output)?;
+    let digest = output.hasher.finalize();
+    let data_file_basename = Path::new(data_file).file_name().unwrap().to_string_lossy();
+    let checksum_file = temporary_path(data_file, work_dir, checksum_suffix(algorithm, suffix));
+    fs::write(&checksum_file, format!("{digest}  {data_file_basename}\n"))?;
+    debug!("Wrote checksum file {}", checksum_file);
+    Ok(checksum_file)
+}
+
+fn compressed_extension(compression: &Compression) -> &'static str {
+    match compression {
+        Compression::Zstd => ".zst",
+        _ => ".gz",
+    }
+}
+
+fn compress_into(input: &mut File, output: impl Write, compression: &Compression) -> std::io::Result<()> {
+    if let Compression::Zstd = compression {
+        zstd::stream::copy_encode(input, output, 0)
+    } else {
+        let mut encoder = GzEncoder::new(output, flate2::Compression::default());
+        std::io::copy(input, &mut encoder)?;
+        encoder.finish().map(|_| ())
+    }
+}
+
+fn compress_file(src_file: &str, compression: &Compression, work_dir: Option<&Path>) -> std::io::Result<String> {
+    // Write a compressed copy of a file next to it or into work_dir, returning the copy's path
+    let compressed_file = temporary_path(src_file, work_dir, compressed_extension(compression));
+    compress_into(&mut File::open(src_file)?, File::create(&compressed_file)?, compression)?;
+    debug!("Compressed {} to {}", src_file, compressed_file);
+    Ok(compressed_file)
+}
+
+fn temporary_path(file: &str, work_dir: Option<&Path>, suffix: &str) -> String {
+    // Path of a file derived from file, next to it or in work_dir
+    match work_dir {
+        Some(work_dir) => {
+            let file_name = Path::new(file).file_name().unwrap().to_string_lossy();
+            work_dir.join(format!("{file_name}{suffix}")).to_string_lossy().to_string()
+        }
+        None => format!("{file}{suffix}"),
+    }
+}
+
+fn temp_dir(cfg: &AppConfig) -> PathBuf {
+    // TRANSFORM_TEMP_DIR, or the system temporary directory
+    cfg.transform_temp_dir.as_ref().map(PathBuf::from).unwrap_or_else(std::env::temp_dir)
+}
+
+fn remove_temporary_files(temporary_files: &[String]) {
+    // Remove compressed copies and checksum sidecars created for a transfer
+    for temporary_file in temporary_files {
+        if let Err(e) = fs::remove_file(temporary_file) {
+            error!("Failed to remove temporary file {}. Error: {}", temporary_file, e);
+        }
+    }
+}
+
+fn send_files(
+    cfg: &AppConfig,
+    table_name: Option<&str>,
+    files: &[&String],
+    remote_dir: &Path,
+    rsync_compress: bool,
+) -> std::io::Result<Transfer> {
+    // Run the transfer itself, by rsync or sftp, or by plain copy for local destinations.
+    // The table, None for files that belong to no table, picks the rsync timeout.
+    let copied = cfg.dest_is_local && cfg.local_dest_mode == LocalDestMode::Copy;
+    if cfg.dry_run != DryRun::Off && (copied || uses_sftp(cfg)) {
+        // rsync's own dry run needs rsync, other transports only log
+        info!("Dry run, not sending {} file(s) to {:?}: {:?}", files.len(), remote_dir, files);
+        return Ok(Transfer::Succeeded(None, None));
+    }
+    if cfg.dry_run == DryRun::Off && !uses_sftp(cfg) {
+        if let Err(reason) = verify_remote_writable(cfg, remote_dir) {
+            return Ok(Transfer::Failed(reason));
+        }
+    }
+    if copied {
+        return Ok(copy_files_locally(files, remote_dir));
+    }
+    if uses_sftp(cfg) {
+        return Ok(match sftp::upload(cfg, files, &remote_rsync_path(remote_dir)) {
+            Ok(_) => Transfer::Succeeded(None, None),
+            Err(e) => Transfer::Failed(e),
+        });
+    }
+    let mut rsync_args = vec![
+        if rsync_compress { "-aLvz" } else { "-aLv" }.to_string(),
+        format!("--partial-dir={RSYNC_PARTIAL_DIR}"),
+        format!("--timeout={}", rsync_timeout(cfg, table_name)),
+    ];
+    if (cfg.compression_stats && rsync_compress) || cfg.rsync_speedup_stats {
+        rsync_args.push("--stats".to_string());
+    }
+    if cfg.transfer_stall_seconds.is_some() {
+        // Overall progress of the transfer, read as it is printed to tell a stalled transfer from a slow one
+        rsync_args.push("--info=progress2".to_string());
+    }
+    if let Some(protocol) = cfg.rsync_protocol {
+        rsync_args.push(format!("--protocol={protocol}"));
+    }
+    if cfg.dry_run == DryRun::Rsync {
+        rsync_args.push("--dry-run".to_string());
+    }
+    rsync_args.extend(files.iter().map(|file| local_rsync_path(file)));
+    if cfg.dest_is_local && cfg.local_dest_mode == LocalDestMode::Rsync {
+        rsync_args.push(local_rsync_path(&remote_dir.to_string_lossy()));
+        if cfg.dry_run == DryRun::Log {
+            info!("Dry run, not running rsync command: {}", redacted_rsync_command(cfg, &rsync_args));
+            return Ok(Transfer::Succeeded(None, None));
+        }
+        if cfg.dry_run == DryRun::Off {
+            fs::create_dir_all(remote_dir)?;
+        }
+        let transfer = run_rsync_command(cfg, &rsync_args).map(|output| rsync_transfer(cfg, &rsync_args, &output))?;
+        verify_partial_cleanup(cfg, files, &remote_rsync_path(remote_dir), &transfer);
+        return Ok(transfer);
+    }
+    let remote_dir = remote_rsync_path(remote_dir);
+    let destination = format!("{}@{}", cfg.dest_user, cfg.dest_host);
+    if let Some(ssh_command) = ssh_command(cfg) {
+        rsync_args.insert(3, format!("--rsh={ssh_command}"));
+    }
+    rsync_args.push(format!("{destination}:{remote_dir}"));
+    match cfg.dry_run {
+        DryRun::Log => {
+            info!("Dry run, not running rsync command: {}", redacted_rsync_command(cfg, &rsync_args));
+            return Ok(Transfer::Succeeded(None, None));
+        }
+        // Without the remote mkdir, which would run for real, so a missing parent directory shows up as an error
+        DryRun::Rsync => {
+            return run_rsync_command(cfg, &rsync_args).map(|output| rsync_transfer(cfg, &rsync_args, &output));
+        }
+        DryRun::Off => (),
+    }
+    // With REMOTE_MKDIR_ONCE, a concurrent first transfer to the same directory waits here until this one is done
+    let ensured = remote_dirs::entry(&destination, &remote_dir);
+    let mut ensured = ensured.as_ref().map(|ensured| ensured.lock().unwrap());
+    let transfer = if ensured.as_deref() == Some(&true) {
+        debug!("Remote directory {} was created earlier this run, skipping mkdir", remote_dir);
+        run_rsync_command(cfg, &rsync_args).map(|output| rsync_transfer(cfg, &rsync_args, &output))?
+    } else {
+        send_files_with_mkdir(cfg, rsync_args, &remote_dir)?
+    };
+    // A failure may mean the directory was removed since, so the next transfer creates it again
+    if let Some(ensured) = ensured.as_mut() {
+        **ensured = matches!(transfer, Transfer::Succeeded(..));
+    }
+    verify_partial_cleanup(cfg, files, &remote_dir, &transfer);
+    Ok(transfer)
+}
+
+fn verify_partial_cleanup(cfg: &AppConfig, files: &[&String], remote_dir: &str, transfer: &Transfer) {
+    // With VERIFY_PARTIAL_CLEANUP, warn about partial files rsync left in its --partial-dir after a successful transfer
+    if !cfg.verify_partial_cleanup || !matches!(transfer, Transfer::Succeeded(..)) {
+        return;
+    }
+    let partial_paths: Vec<String> = files
+        .iter()
+        .filter_map(|file| Path::new(file.as_str()).file_name())
+        .map(|file_name| format!("{}/{}/{}", remote_dir, RSYNC_PARTIAL_DIR, file_name.to_string_lossy()))
+        .collect();
+    let partial_paths: Vec<&str> = partial_paths.iter().map(String::as_str).collect();
+    match existing_remote_files(cfg, &partial_paths) {
+        Ok(leftovers) if leftovers.is_empty() => {
+            debug!("No partial files left in {}/{} after the transfer", remote_dir, RSYNC_PARTIAL_DIR)
+        }
+        Ok(leftovers) => {
+            let mut leftovers: Vec<String> = leftovers.into_iter().collect();
+            leftovers.sort();
+            for leftover in leftovers {
+                warn!("rsync left partial file {} on {} after a successful transfer", leftover, cfg.dest_host);
+            }
+        }
+        Err(e) => warn!("Failed to check {}/{} for leftover partial files. Error: {}", remote_dir, RSYNC_PARTIAL_DIR, e),
+    }
+}
+
+fn rsync_timeout(cfg: &AppConfig, table_name: Option<&str>) -> u64 {
+    // Seconds without I/O before rsync gives up on a transfer, 0 for no timeout
+    let timeout = table_name
+        .and_then(|table_name| cfg.rsync_table_timeout_seconds.get(table_name))
+        .copied()
+        .unwrap_or(cfg.rsync_timeout_seconds);
+    match table_name {
+        Some(table_name) => info!("rsync timeout for table {:?}: {}s", table_name, timeout),
+        None => info!("rsync timeout: {}s", timeout),
+    }
+    timeout
+}
+
+fn verify_remote_writable(cfg: &AppConfig, remote_dir: &Path) -> Result<(), String> {
+    // With VERIFY_REMOTE_WRITABLE, create and delete a probe file in the destination directory, creating the
+    // directory first as the transfer itself would
+    let probe_name = format!(".rsync_csv_write_probe_{}", std::process::id());
+    if cfg.dest_is_local && cfg.local_dest_mode != LocalDestMode::Ssh {
+        let destination = "localhost";
+        return remote_writable::verify(destination, &remote_dir.to_string_lossy(), || {
+            let probe_path = remote_dir.join(&probe_name);
+            fs::create_dir_all(remote_dir)
+                .and_then(|_| fs::write(&probe_path, b""))
+                .and_then(|_| fs::remove_file(&probe_path))
+                .map_err(|e| e.to_string())
+        });
+    }
+    let remote_dir = remote_rsync_path(remote_dir);
+    let destination = format!("{}@{}", cfg.dest_user, cfg.dest_host);
+    remote_writable::verify(&destination, &remote_dir, || {
+        let quoted_dir = shell_quote(&remote_dir);
+        let quoted_probe = shell_quote(&format!("{remote_dir}/{probe_name}"));
+        let remote_command = format!("mkdir -p {quoted_dir} && touch {quoted_probe} && rm -f {quoted_probe}");
+        let ssh_args = ssh_args(cfg);
+        let output = Command::new(&ssh_args[0])
+            .args(&ssh_args[1..])
+            .arg(&destination)
+            .arg(remote_command)
+            .output()
+            .map_err(|e| e.to_string())?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
         }
-        if (last_event_time.elapsed().as_secs() > csv_event_wait_seconds
-            || event_vec.len() > csv_event_upper_limit as usize)
-            && !event_vec.is_empty()
-        {
-            match handle_csv_file_event(
-                &dest_user,
-                &dest_host,
-                &dest_dir,
-                &hashmap,
-                &file_suffix,
-                &event_vec,
-            ) {
-                Ok(_) => event_vec.clear(),
-                Err(e) => error!("Error handling csv file event: {:?}", e),
-            }
+        Ok(())
+    })
+}
+
+fn send_files_with_mkdir(cfg: &AppConfig, mut rsync_args: Vec<String>, remote_dir: &str) -> std::io::Result<Transfer> {
+    // Run rsync with a remote `mkdir -p` of the destination directory, handling failures to create it
+    rsync_args.insert(3, format!("--rsync-path=mkdir -p {} && rsync", shell_quote(remote_dir)));
+    let mut mkdir_retries = 0;
+    let mkdir_error = loop {
+        let output = run_rsync_command(cfg, &rsync_args)?;
+        let Some(mkdir_error) = remote_mkdir_error(&output) else {
+            return Ok(rsync_transfer(cfg, &rsync_args, &output));
+        };
+        if mkdir_retries >= cfg.remote_mkdir_retries {
+            break mkdir_error;
+        }
+        mkdir_retries += 1;
+        warn!(
+            "Failed to create remote directory {} on {}, retrying ({}/{}): {}",
+            remote_dir, cfg.dest_host, mkdir_retries, cfg.remote_mkdir_retries, mkdir_error
+        );
+        thread::sleep(REMOTE_MKDIR_RETRY_DELAY);
+    };
+    error!("Failed to create remote directory {} on {}: {}", remote_dir, cfg.dest_host, mkdir_error);
+    if cfg.remote_mkdir_failure == MkdirFailurePolicy::Fail {
+        let mut message = format!("Failed to create remote directory {remote_dir}: {mkdir_error}");
+        if cfg.upload_log_rsync_command {
+            message += &format!(" Command: {}", redacted_rsync_command(cfg, &rsync_args));
         }
+        return Ok(Transfer::Failed(message));
     }
+    // REMOTE_MKDIR_FAILURE=retry assumes the directory exists, so rsync reports its own error if it doesn't
+    info!("Retrying transfer to {} without creating the remote directory", remote_dir);
+    rsync_args.remove(3);
+    run_rsync_command(cfg, &rsync_args).map(|output| rsync_transfer(cfg, &rsync_args, &output))
 }
 
-fn handle_csv_file_event(
-    dest_user: &str,
-    dest_host: &str,
-    dest_dir: &str,
-    hashmap: &HashMap<String, String>,
-    file_suffix: &str,
-    event_vec: &Vec<notify::Event>,
-) -> std::io::Result<()> {
-    // Handle csv file events
-    info!(
-        "Handling CSV file events. Total event count: {:?}",
-        event_vec.len()
-    );
-    // debug!("Event Vec: {:?}", event_vec);
-    /*
-    Rsync hashmap structure:
-    {
-        "table_name": {
-            "src_files": [src_file...],
-            "metadata_files": [metadata_file...]
-            "uploaded_by": [username...]
+fn shell_quote(arg: &str) -> String {
+    // Single quote an argument for a POSIX shell, such as the remote one ssh runs commands in
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+fn ssh_args(cfg: &AppConfig) -> Vec<String> {
+    // ssh command line that authenticates to DEST_HOST per DEST_AUTH
+    let args: Vec<&str> = match cfg.dest_auth {
+        DestAuth::Agent => vec!["ssh"],
+        DestAuth::Key => vec![
+            "ssh",
+            "-i",
+            cfg.dest_identity_file.as_deref().unwrap_or_default(),
+            "-o",
+            "IdentitiesOnly=yes",
+        ],
+        DestAuth::Password => vec![
+            "sshpass",
+            "-f",
+            cfg.dest_password_file.as_deref().unwrap_or_default(),
+            "ssh",
+            "-o",
+            "PubkeyAuthentication=no",
+        ],
+    };
+    args.into_iter().map(str::to_string).collect()
+}
+
+fn ssh_command(cfg: &AppConfig) -> Option<String> {
+    // Remote shell for rsync, None for ssh's defaults. Arguments other than plain words are quoted.
+    if cfg.dest_auth == DestAuth::Agent {
+        return None;
+    }
+    let plain = |arg: &str| arg.chars().all(|c| c.is_ascii_alphanumeric() || "-=_".contains(c));
+    let args: Vec<String> = ssh_args(cfg)
+        .into_iter()
+        .map(|arg| if plain(&arg) { arg } else { shell_quote(&arg) })
+        .collect();
+    Some(args.join(" "))
+}
+
+fn run_rsync_command(cfg: &AppConfig, rsync_args: &[String]) -> std::io::Result<Output> {
+    info!("Running rsync command: {}", redacted_rsync_command(cfg, rsync_args));
+    let output = match cfg.transfer_stall_seconds {
+        Some(stall_seconds) => run_watching_progress(cfg, rsync_args, Duration::from_secs(stall_seconds))?,
+        None => Command::new(&cfg.rsync_binary).args(rsync_args).output()?,
+    };
+    // Full rsync output is only logged at debug level, info level gets a summary
+    if cfg.dry_run == DryRun::Rsync {
+        info!("Rsync dry run output: {}", String::from_utf8_lossy(&output.stdout).trim_end());
+    }
+    debug!("Rsync stdout: {}", String::from_utf8_lossy(&output.stdout));
+    debug!("Rsync stderr: {}", String::from_utf8_lossy(&output.stderr));
+    Ok(output)
+}
+
+fn run_watching_progress(cfg: &AppConfig, rsync_args: &[String], stall_window: Duration) -> std::io::Result<Output> {
+    // Run rsync with its output streamed, warning about a transfer whose byte count hasn't moved for stall_window,
+    // and with TRANSFER_STALL_ACTION=abort killing it. Progress lines are left out of the returned stdout.
+    let mut child = Command::new(&cfg.rsync_binary)
+        .args(rsync_args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let last_progress = Arc::new(Mutex::new(Instant::now()));
+    let (stdout_tx, stdout_rx) = channel();
+    let (stderr_tx, stderr_rx) = channel();
+    let stdout = child.stdout.take().unwrap();
+    let progress = last_progress.clone();
+    thread::spawn(move || stdout_tx.send(read_rsync_progress(stdout, &progress)));
+    let mut stderr = child.stderr.take().unwrap();
+    thread::spawn(move || {
+        let mut contents = Vec::new();
+        let _ = stderr.read_to_end(&mut contents);
+        stderr_tx.send(contents)
+    });
+    let (mut stalled, mut aborted) = (false, false);
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        let idle = last_progress.lock().unwrap().elapsed();
+        if idle >= stall_window && !stalled {
+            stalled = true;
+            warn!("rsync transfer stalled, no progress for {} second(s)", idle.as_secs());
+            if cfg.transfer_stall_action == StallAction::Abort {
+                error!("Aborting stalled rsync transfer");
+                aborted = child.kill().is_ok();
+            }
+        } else if idle < stall_window && stalled && !aborted {
+            info!("Stalled rsync transfer is making progress again");
+            stalled = false;
         }
+        thread::sleep(Duration::from_millis(200));
+    };
+    // The ssh rsync started can outlive a killed rsync and keep its output open, so what it wrote is only waited for
+    // briefly after an abort
+    let deadline = aborted.then(|| Instant::now() + Duration::from_secs(5));
+    let read_timeout = || deadline.map_or(Duration::MAX, |deadline| deadline.saturating_duration_since(Instant::now()));
+    let stdout = stdout_rx.recv_timeout(read_timeout()).unwrap_or_default();
+    let mut stderr = stderr_rx.recv_timeout(read_timeout()).unwrap_or_default();
+    if aborted {
+        stderr.extend(format!("Transfer stalled, aborted after {} second(s) without progress\n", stall_window.as_secs()).bytes());
     }
-     */
-    let mut rsync_hashmap: HashMap<String, HashMap<String, Vec<String>>> = HashMap::new();
-    for event in event_vec.iter() {
-        let src_file_path = event.paths[0].to_str().unwrap();
-        let src_file_basename = event.paths[0].file_name().unwrap().to_str().unwrap();
-        let match_result = match_col_headers(src_file_path, &hashmap);
-        match match_result {
-            Ok(table_name) => {
-                if !table_name.is_empty() {
-                    let username = get_file_owner(src_file_path).unwrap();
-                    let src_file_with_suffix =
-                        suffix_file_name(src_file_path, &file_suffix)?;
-                    info!("Source file with suffix: {:?}", src_file_with_suffix);
-                    let metadata_file = match create_metadata_file(&src_file_with_suffix) {
-                        Ok(file) => file,
-                        Err(e) => {
-                            error!("Error creating metadata file: {:?}", e);
-                            String::new()
-                        }
-                    };
-                    let table_entry = rsync_hashmap.entry(table_name).or_insert(HashMap::new());
-                    table_entry
-                        .entry("src_files".to_string())
-                        .or_insert(Vec::new())
-                        .push(src_file_with_suffix);
-                    table_entry
-                        .entry("metadata_files".to_string())
-                        .or_insert(Vec::new())
-                        .push(metadata_file);
-                    table_entry
-                        .entry("uploaded_by".to_string())
-                        .or_insert(Vec::new())
-                        .push(username);
+    Ok(Output { status, stdout, stderr })
+}
+
+fn read_rsync_progress(mut stdout: impl Read, last_progress: &Mutex<Instant>) -> Vec<u8> {
+    // Read rsync's stdout to the end, noting when the byte count of its "1,234,567  45% ..." progress lines, which
+    // end in a carriage return while they update, last went up. Every other line is returned.
+    let (mut output, mut line, mut buffer) = (Vec::new(), Vec::new(), [0u8; 4096]);
+    let mut bytes_seen = 0;
+    loop {
+        let read = match stdout.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(read) => read,
+        };
+        for &byte in &buffer[..read] {
+            if byte != b'\r' && byte != b'\n' {
+                line.push(byte);
+                continue;
+            }
+            let text = String::from_utf8_lossy(&line);
+            let progress_bytes = text
+                .split_whitespace()
+                .next()
+                .filter(|_| text.contains('%'))
+                .and_then(|bytes| bytes.replace(',', "").parse::<u64>().ok());
+            match progress_bytes {
+                Some(progress_bytes) if progress_bytes > bytes_seen => {
+                    bytes_seen = progress_bytes;
+                    *last_progress.lock().unwrap() = Instant::now();
                 }
-            },
-            Err(e) => {
-                error!("Error matching column headers: {:?}", e);
-                match &event.paths[0].parent() {
-                    Some(log_dir) => log_upload_status(
-                        log_dir.to_str().unwrap(),
-                        format!("Upload failed! File: {src_file_basename} Reason: {e}").to_string(),
-                    ),
-                    None => error!("Failed to get parent directory of source file."),
+                None if !line.is_empty() => {
+                    output.append(&mut line);
+                    output.push(b'\n');
                 }
+                _ => (),
             }
+            line.clear();
         }
     }
-    match run_rsync(&rsync_hashmap, &dest_user, &dest_host, &dest_dir, 0) {
-        Ok(_) => {
-            let msg = serde_json::to_string(&rsync_hashmap).unwrap();
-            dbg!(&msg);
-            let dest_addr = format!("{}:50000", dest_host);
-            if let Ok(mut stream) = TcpStream::connect(dest_addr) {
-                let _ = stream.write(&msg.into_bytes());
-            } else {
-                error!("Failed to connect to destination host ({}) on port 50000", dest_host);
-            }
-        },
-        Err(_) => (),
+    output.extend(line);
+    output
+}
+
+fn redacted_rsync_command(cfg: &AppConfig, rsync_args: &[String]) -> String {
+    // The rsync command line with credentials replaced: the configured key and password files, and the value of any
+    // option that names a key or password file, whether given to rsync or inside its --rsh command
+    let mut command = format!("{} {}", cfg.rsync_binary, rsync_args.join(" "));
+    for secret in [&cfg.dest_identity_file, &cfg.dest_password_file].into_iter().flatten() {
+        if !secret.is_empty() {
+            // Quoted as ssh_command puts it in --rsh first, a path with a quote isn't found as it is there
+            command = command.replace(&shell_quote(secret), "<redacted>").replace(secret.as_str(), "<redacted>");
+        }
     }
-    Ok(())
+    // The value is a whole shell word, which may join quoted and escaped parts such as 'a'\''b'
+    let credential_options =
+        Regex::new(r"(--password-file[= ]|IdentityFile[= ]|(?:^|[\s'])-[if] ?)((?:'[^']*'|\\.|[^\s'\\])+)").unwrap();
+    credential_options.replace_all(&command, "${1}<redacted>").to_string()
 }
 
-fn match_col_headers(csv_path: &str, hashmap: &HashMap<String, String>) -> std::io::Result<String> {
-    // Match column header templates and returns the matching table name as a String
-    if Path::new(csv_path).exists() {
-        let csv_file = File::open(csv_path)?;
-        let binding = PathBuf::from(csv_path);
-        let csv_file_basename = binding.file_name().unwrap().to_str().unwrap();
-        let reader = BufReader::new(csv_file);
-        let csv_headers = reader.lines().next().unwrap_or_else(|| Ok(String::new()))?;
-        info!("CSV Headers: {:?}", csv_headers);
-        match hashmap.get(csv_headers.trim_end_matches(",")) {
-            Some(table_name) => {
-                info!("Matching table headers found, table name: {:?}", table_name);
-                return Ok(table_name.to_string());
-            }
-            None => {
-                info!("No matching table headers found. Ignoring csv file.");
-                match PathBuf::from(csv_path).parent() {
-                    Some(log_dir) => log_upload_status(log_dir.to_str().unwrap(), format!("Upload failed! File: {csv_file_basename} Reason: No matching table headers found.").to_string()),
-                    None => error!("Failed to get parent directory of source file."),
-                }
+fn rsync_transfer(cfg: &AppConfig, rsync_args: &[String], output: &Output) -> Transfer {
+    let tolerated_code = output
+        .status
+        .code()
+        .filter(|code| cfg.rsync_success_codes.contains(code));
+    if output.status.success() || tolerated_code.is_some() {
+        return Transfer::Succeeded(tolerated_code, rsync_stats(&String::from_utf8_lossy(&output.stdout)));
+    }
+    // A killed rsync (OOM killer, timeout) has no exit code and usually nothing on stderr. Like any failed
+    // transfer, it is retried.
+    let reason = match termination_signal(&output.status) {
+        Some(signal) => {
+            error!("rsync was terminated by signal {}", signal);
+            // Anything on stderr tells why, e.g. a transfer aborted by TRANSFER_STALL_ACTION
+            match String::from_utf8_lossy(&output.stderr).trim() {
+                "" => format!("rsync terminated by signal {signal}"),
+                stderr => format!("rsync terminated by signal {signal}: {stderr}"),
             }
         }
+        None => String::from_utf8_lossy(&output.stderr).to_string(),
+    };
+    if cfg.upload_log_rsync_command {
+        Transfer::Failed(format!("{} Command: {}", reason.trim_end(), redacted_rsync_command(cfg, rsync_args)))
+    } else {
+        Transfer::Failed(reason)
     }
-    // If csv file does not exist or no matching table headers, return empty string
-    Ok(String::new())
 }
 
-fn delete_src_file_and_metadata(src_file: &str, src_file_metadata: &str) {
-    // Delete source file and metadata after rsync
-    let files_to_remove = vec![src_file, src_file_metadata];
-    info!(
-        "Attempting to delete source file and metadata: {}, {}",
-        src_file, src_file_metadata
-    );
-    for file in files_to_remove {
-        match fs::remove_file(file) {
-            Ok(_) => info!("Successfully removed {}", file),
-            Err(e) => error!("Failed to remove {}: {}", file, e),
+#[cfg(not(unix))]
+fn termination_signal(_status: &std::process::ExitStatus) -> Option<i32> {
+    // Processes are not terminated by signals outside unix
+    None
+}
+
+#[cfg(unix)]
+fn termination_signal(status: &std::process::ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+fn rsync_stats(stdout: &str) -> Option<RsyncStats> {
+    // The --stats summary, None without one. Missing figures count as 0.
+    let bytes_sent = rsync_stat(stdout, "Total bytes sent:")?;
+    Some(RsyncStats {
+        bytes_sent,
+        bytes_received: rsync_stat(stdout, "Total bytes received:").unwrap_or(0),
+        total_file_size: rsync_stat(stdout, "Total file size:").unwrap_or(0),
+    })
+}
+
+fn rsync_stat(stdout: &str, label: &str) -> Option<u64> {
+    // e.g. "Total bytes sent: 1,234", with or without thousands separators
+    stdout
+        .lines()
+        .find_map(|line| line.trim().strip_prefix(label))
+        .and_then(|rest| rest.split_whitespace().next())
+        .and_then(|bytes| bytes.replace(',', "").parse().ok())
+}
+
+fn remote_mkdir_error(output: &Output) -> Option<String> {
+    // The `mkdir -p` of the --rsync-path wrapper runs before the remote rsync, so its error comes first and rsync
+    // itself only reports a closed connection
+    if output.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .find(|line| line.trim_start().starts_with("mkdir:"))
+        .map(|line| line.trim().to_string())
+}
+
+fn uses_sftp(cfg: &AppConfig) -> bool {
+    // Local destinations are copied or rsynced directly unless LOCAL_DEST_MODE asks for the remote path
+    cfg.transport == Transport::Sftp && !(cfg.dest_is_local && cfg.local_dest_mode != LocalDestMode::Ssh)
+}
+
+fn probe_target(cfg: &AppConfig) -> probe::Target {
+    // Probe the destination the way files are transferred to it
+    if uses_sftp(cfg) {
+        probe::Target::Tcp(format!("{}:{}", cfg.dest_host, cfg.sftp.port))
+    } else if cfg.dest_is_local && cfg.local_dest_mode != LocalDestMode::Ssh {
+        probe::Target::Local(PathBuf::from(&cfg.dest_dir))
+    } else {
+        probe::Target::Ssh {
+            command: ssh_args(cfg),
+            destination: format!("{}@{}", cfg.dest_user, cfg.dest_host),
         }
     }
 }
 
-fn log_upload_status(log_dir: &str, log_msg: String) {
-    // Create an upload log file at specified log directory
-    let log_file_path = Path::new(log_dir).join("upload.log");
-    let log_time = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
-    match fs::OpenOptions::new()
-        .append(true)
-        .create(true)
-        .open(log_file_path)
-    {
-        Ok(mut log_file) => match log_file.write(format!("{log_time} - {log_msg}\n").as_bytes()) {
-            Ok(_) => info!("Upload log file updated successfully."),
-            Err(e) => error!("Failed to write to upload log file. Error: {}", e),
-        },
-        Err(e) => error!("Failed to create upload log file. Error: {}", e),
+fn copy_files_locally(files: &[&String], dest_dir: &Path) -> Transfer {
+    // Copy files into a local destination, renaming into place so readers never see a partial file
+    info!("Copying {} file(s) to local destination {:?}", files.len(), dest_dir);
+    let copied = fs::create_dir_all(dest_dir).and_then(|_| {
+        for file in files {
+            let file_name = Path::new(file.as_str()).file_name().unwrap().to_string_lossy().to_string();
+            let partial_path = dest_dir.join(format!(".{file_name}.partial"));
+            fs::copy(file, &partial_path)?;
+            fs::rename(&partial_path, dest_dir.join(&file_name))?;
+        }
+        Ok(())
+    });
+    match copied {
+        Ok(_) => Transfer::Succeeded(None, None),
+        Err(e) => Transfer::Failed(format!("Local copy failed: {e}")),
     }
 }
 
 fn run_rsync(
-    rsync_hashmap: &HashMap<String, HashMap<String, Vec<String>>>,
-    dest_user: &str,
-    dest_host: &str,
-    dest_dir: &str,
-    retry_count: u8,
-) -> Result<(), String> {
-    // Run rsync command to sync csv files to destination host
+    rsync_hashmap: &RsyncHashmap,
+    cfg: &AppConfig,
+    templates: &SharedTemplates,
+) -> Result<Vec<(PendingFile, String)>, String> {
+    // Run rsync command to sync csv files to destination host. Returns the files that failed, with the error.
     debug!("Rsync Hashmap: {:?}", rsync_hashmap);
-    for table_name in rsync_hashmap.keys() {
-        let table_entry = rsync_hashmap.get(table_name).unwrap();
-        let src_files = table_entry.get("src_files").unwrap();
-        let metadata_files = table_entry.get("metadata_files").unwrap();
-        let mkdir_command = format!(
-            "\"mkdir -p \"{}\" && rsync\"",
-            PathBuf::from(dest_dir).join(table_name).display()
-        );
-        let rsync_command = format!(
-            "rsync -aLvz --partial-dir=tmp --timeout=10 --rsync-path={} \"{}\" \"{}\" {}@{}:{}",
-            mkdir_command,
-            src_files.join("\" \""),
-            metadata_files.join("\" \""),
-            dest_user,
-            dest_host,
-            PathBuf::from(dest_dir).join(table_name).display()
-        );
-        info!("Running rsync command: {}", rsync_command);
-        match Command::new("sh").arg("-c").arg(&rsync_command).output() {
-            Ok(output) => {
-                if output.status.success() {
-                    info!("Success: {}", String::from_utf8_lossy(&output.stdout));
-                    for src_file in src_files {
-                        let src_file_metadata =
-                            &metadata_files[src_files.iter().position(|x| x == src_file).unwrap()];
-                        let binding = PathBuf::from(src_file);
-                        let src_file_basename = binding.file_name().unwrap().to_str().unwrap();
-                        delete_src_file_and_metadata(src_file, src_file_metadata);
-                        match PathBuf::from(src_file).parent() {
-                            Some(log_dir) => log_upload_status(
-                                log_dir.to_str().unwrap(),
-                                format!("Upload succeeded! File: {src_file_basename}").to_string(),
-                            ),
-                            None => {
-                                error!("Failed to get source file parent directory");
-                                Err("Failed to get source file parent directory")?;
-                            }
-                        }
+    let mut failed_files: Vec<(PendingFile, String)> = Vec::new();
+    let table_names: Vec<&String> = rsync_hashmap.keys().collect();
+    let mut remaining = &table_names[..];
+    // Tables are transferred in waves of the current concurrency, which adapts between waves
+    while !remaining.is_empty() {
+        let (wave, rest) = remaining.split_at(concurrency::current().min(remaining.len()));
+        remaining = rest;
+        for (table_name, remote_dir, rsync_stages, transfer) in transfer_wave(rsync_hashmap, cfg, templates, wave) {
+            concurrency::record(matches!(transfer, Ok(Transfer::Succeeded(..))));
+            let table_entry = &rsync_hashmap[table_name];
+            handle_transfer_result(
+                cfg,
+                table_name,
+                table_entry,
+                &remote_dir,
+                rsync_stages,
+                transfer,
+                &mut failed_files,
+            )?;
+        }
+    }
+    Ok(failed_files)
+}
+
+fn transfer_wave<'a>(
+    rsync_hashmap: &'a RsyncHashmap,
+    cfg: &AppConfig,
+    templates: &SharedTemplates,
+    wave: &[&'a String],
+) -> Vec<(&'a String, PathBuf, Vec<telemetry::Stage>, std::io::Result<Transfer>)> {
+    // Transfer the tables of a wave in parallel, one thread per table
+    let rsync_stages: Vec<Vec<telemetry::Stage>> = wave
+        .iter()
+        .map(|table_name| {
+            rsync_hashmap[*table_name]["src_files"]
+                .iter()
+                .map(|src_file| telemetry::start_stage(src_file, "rsync"))
+                .collect()
+        })
+        .collect();
+    let remote_dirs: Vec<PathBuf> =
+        wave.iter().map(|table_name| remote_table_dir(cfg, templates, table_name)).collect();
+    // Sequence numbers are assigned before the threads start, in wave order. A table whose numbers can't be saved fails.
+    let sequence_errors: Vec<Option<String>> = wave
+        .iter()
+        .map(|table_name| {
+            sequence::assign(&rsync_hashmap[*table_name]["src_files"])
+                .err()
+                .map(|e| format!("Failed to save SEQUENCE_FILE: {e}"))
+        })
+        .collect();
+    let transfers: Vec<std::io::Result<Transfer>> = thread::scope(|scope| {
+        let handles: Vec<_> = wave
+            .iter()
+            .zip(&remote_dirs)
+            .zip(&sequence_errors)
+            .map(|((table_name, remote_dir), sequence_error)| {
+                let table_entry = &rsync_hashmap[*table_name];
+                scope.spawn(move || match sequence_error {
+                    Some(e) => Ok(Transfer::Failed(e.clone())),
+                    None => transfer_routed_files(
+                        cfg,
+                        table_name,
+                        &table_entry["src_files"],
+                        &table_entry["metadata_files"],
+                        remote_dir,
+                    ),
+                })
+            })
+            .collect();
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    });
+    wave.iter()
+        .copied()
+        .zip(remote_dirs)
+        .zip(rsync_stages)
+        .zip(transfers)
+        .map(|(((table_name, remote_dir), stages), transfer)| (table_name, remote_dir, stages, transfer))
+        .collect()
+}
+
+fn handle_transfer_result(
+    cfg: &AppConfig,
+    table_name: &str,
+    table_entry: &HashMap<String, Vec<String>>,
+    remote_dir: &Path,
+    rsync_stages: Vec<telemetry::Stage>,
+    transfer: std::io::Result<Transfer>,
+    failed_files: &mut Vec<(PendingFile, String)>,
+) -> Result<(), String> {
+    // Delete and log the files of a successfully transferred table, or collect them as failed
+    let src_files = &table_entry["src_files"];
+    let metadata_files = &table_entry["metadata_files"];
+    match transfer {
+        Ok(Transfer::Succeeded(tolerated_code, _)) => {
+            match tolerated_code {
+                Some(code) => info!(
+                    "Success (tolerated code {}): rsync transferred {} file(s) for table {:?}",
+                    code,
+                    src_files.len(),
+                    table_name
+                ),
+                None => info!(
+                    "Success: rsync transferred {} file(s) for table {:?}",
+                    src_files.len(),
+                    table_name
+                ),
+            }
+            if cfg.dry_run != DryRun::Off {
+                // Nothing was uploaded, so the source files stay, without their metadata files, and no upload is recorded
+                info!("Dry run, keeping the {} source file(s) of table {:?}", src_files.len(), table_name);
+                rsync_stages.into_iter().for_each(|stage| stage.end("dry_run"));
+                for (src_file, metadata_file) in src_files.iter().zip(metadata_files) {
+                    if let Err(e) = fs::remove_file(metadata_file) {
+                        error!("Failed to remove metadata file {}. Error: {}", metadata_file, e);
+                    }
+                    telemetry::finish_file(src_file, "dry_run");
+                    slow_files::finish(src_file, "dry_run");
+                    remote_exists::forget(src_file);
+                    bundles::forget(src_file);
+                    preambles::forget(src_file);
+                }
+                return Ok(());
+            }
+            rsync_stages.into_iter().for_each(|stage| stage.end("success"));
+            bundles::log_outcome(table_name, src_files, true);
+            if let Some(marker_path) = &cfg.upload_marker_path {
+                write_upload_marker(marker_path, table_name, src_files.len());
+            }
+            for src_file in src_files {
+                preambles::forget(src_file);
+                let src_file_metadata =
+                    &metadata_files[src_files.iter().position(|x| x == src_file).unwrap()];
+                let binding = PathBuf::from(src_file);
+                let src_file_basename = binding.file_name().unwrap().to_str().unwrap();
+                if let Some(existing_path) = remote_exists::skipped(src_file) {
+                    // Not sent, so the source file stays where it is, without its metadata file
+                    info!("Keeping {}, it was not sent because {} already exists", src_file, existing_path);
+                    if let Err(e) = fs::remove_file(src_file_metadata) {
+                        error!("Failed to remove metadata file {}. Error: {}", src_file_metadata, e);
+                    }
+                    telemetry::finish_file(src_file, "skipped");
+                    slow_files::finish(src_file, "skipped");
+                    remote_exists::forget(src_file);
+                    let log_msg =
+                        format!("Upload skipped! File: {src_file_basename} Reason: Already exists on the destination as {existing_path}");
+                    if let Some(log_dir) = binding.parent() {
+                        log_upload_status(cfg, log_dir.to_str().unwrap(), log_msg);
                     }
+                    continue;
+                }
+                // Read the modification time before the file is deleted
+                let src_file_mtime = if cfg.upload_log_mtime {
+                    file_mtime(src_file)
                 } else {
-                    let err_msg = String::from_utf8_lossy(&output.stderr);
-                    error!("Error: {}", err_msg);
-                    if retry_count < 3 {
-                        info!("Retrying rsync command...");
-                        let _ = run_rsync(
-                            &rsync_hashmap,
-                            &dest_user,
-                            &dest_host,
-                            &dest_dir,
-                            retry_count + 1,
-                        );
-                    } else {
-                        for src_file in src_files {
-                            let binding = PathBuf::from(src_file);
-                            let src_file_basename = binding.file_name().unwrap().to_str().unwrap();
-                            match PathBuf::from(src_file).parent() {
-                                Some(log_dir) => log_upload_status(
-                                    log_dir.to_str().unwrap(),
-                                    format!(
-                                        "Upload failed! File: {src_file_basename} Reason: {err_msg}"
-                                    )
-                                    .to_string(),
-                                ),
-                                None => {
-                                    error!("Failed to get source file parent directory");
-                                    Err("Failed to get source file parent directory")?;
-                                }
-                            }
-                        }
+                    None
+                };
+                // Volume counts the source file's size, before any compression
+                let src_file_stat = fs::metadata(src_file);
+                let src_file_size = src_file_stat.as_ref().map(|metadata| metadata.len()).unwrap_or(0);
+                if let Ok(modified) = src_file_stat.and_then(|metadata| metadata.modified()) {
+                    freshness::record(table_name, modified);
+                }
+                let delete_stage = telemetry::start_stage(src_file, "delete");
+                match ack_path(cfg, table_name, &routed_remote_dir(cfg, remote_dir, src_file), src_file) {
+                    Some(ack_path) => {
+                        info!("Keeping {} until its ack file {} appears", src_file, ack_path);
+                        ack::await_ack(src_file, src_file_metadata, ack_path);
+                        delete_stage.end("awaiting_ack");
+                    }
+                    None => {
+                        remove_uploaded_file(cfg, src_file, src_file_metadata);
+                        delete_stage.end("deleted");
+                    }
+                }
+                telemetry::finish_file(src_file, "uploaded");
+                slow_files::finish(src_file, "uploaded");
+                volume::record(table_name, src_file_size);
+                events::publish(
+                    "uploaded",
+                    src_file,
+                    serde_json::json!({
+                        "table": table_name,
+                        "remote_dir": remote_rsync_path(&routed_remote_dir(cfg, remote_dir, src_file)),
+                        "size": src_file_size,
+                        "modified": src_file_mtime,
+                        "sequence": sequence::of(src_file),
+                    }),
+                );
+                let mut log_msg = match src_file_mtime {
+                    Some(mtime) => format!("Upload succeeded! File: {src_file_basename} Modified: {mtime}"),
+                    None => format!("Upload succeeded! File: {src_file_basename}"),
+                };
+                if let Some(sequence) = sequence::of(src_file) {
+                    info!("Uploaded {} as sequence number {}", src_file, sequence);
+                    log_msg += &format!(" Sequence: {sequence}");
+                    sequence::forget(src_file);
+                }
+                if let Some(version) = remote_exists::version(src_file) {
+                    log_msg += &format!(" Version: {version}");
+                    remote_exists::forget(src_file);
+                }
+                match PathBuf::from(src_file).parent() {
+                    Some(log_dir) => log_upload_status(cfg, log_dir.to_str().unwrap(), log_msg),
+                    None => {
+                        error!("Failed to get source file parent directory");
+                        Err("Failed to get source file parent directory")?;
                     }
                 }
-            },
-            Err(e) => {
-                error!("Failed to execute rsync command. Error: {}", e);
-                Err("Failed to get source file parent directory")?;
             }
         }
+        Ok(Transfer::Failed(err_msg)) => {
+            error!("Error: {}", err_msg);
+            rsync_stages.into_iter().for_each(|stage| stage.end("failed"));
+            bundles::log_outcome(table_name, src_files, false);
+            failed_files.extend(pending_table_files(table_name, table_entry).map(|file| (file, err_msg.clone())));
+        }
+        Err(e) => {
+            rsync_stages.into_iter().for_each(|stage| stage.end("error"));
+            error!("Failed to transfer files. Error: {}", e);
+            bundles::log_outcome(table_name, src_files, false);
+            let err_msg = format!("Failed to transfer files: {e}");
+            failed_files.extend(pending_table_files(table_name, table_entry).map(|file| (file, err_msg.clone())));
+        }
     }
     Ok(())
 }
 
-fn load_env_vars() -> (String, String, String, String, String, String, u64, u64) {
-    // Load environment variables and set rsync src and dest paths
-    dotenv().ok();
-    let src_dir = env::var("SOURCE_DIR").unwrap();
-    let dest_user = env::var("DEST_USER").unwrap();
-    let dest_host = env::var("DEST_HOST").unwrap();
-    let dest_dir = env::var("DEST_DIR").unwrap();
-    let template_dir = env::var("TEMPLATE_DIR").unwrap();
-    let file_suffix = env::var("FILE_SUFFIX").unwrap();
-    let csv_event_wait_seconds = env::var("CSV_EVENT_WAIT_SECONDS")
-        .unwrap()
-        .parse::<u64>()
-        .unwrap();
-    let csv_event_upper_limit = env::var("CSV_EVENT_UPPER_LIMIT")
-        .unwrap()
-        .parse::<u64>()
-        .unwrap();
-    (
-        src_dir,
-        dest_user,
-        dest_host,
-        dest_dir,
-        template_dir,
-        file_suffix,
-        csv_event_wait_seconds,
-        csv_event_upper_limit,
-    )
-}
-
-fn load_headers(template_dir: String) -> std::io::Result<HashMap<String, String>> {
-    // Load headers from template csv files and store in hashmap
-    let mut table_headers: HashMap<String, String> = HashMap::new();
-    let template_files = std::fs::read_dir(template_dir).unwrap();
-    for template_file in template_files {
-        let template_path = template_file?.path();
-        match template_path.clone().file_stem() {
-            Some(fname) => match &fname.to_str() {
-                Some(v) => {
-                    let table_name = v.strip_suffix("_template").unwrap().to_string();
-                    let mut file = File::open(template_path).unwrap();
-                    let mut headers = String::new();
-                    let _ = file.read_to_string(&mut headers);
-                    headers = headers.trim().to_string();
-                    table_headers.insert(headers, table_name);
-                }
-                None => info!("Invalid File Name"),
-            },
-            None => error!("No File Name"),
-        }
+fn write_upload_marker(marker_path: &str, table_name: &str, file_count: usize) {
+    // Replace the table's marker file, whose mtime is the time of its last successful upload
+    let marker_path = PathBuf::from(marker_path.replace("{table}", table_name));
+    let partial_path = PathBuf::from(format!("{}.partial", marker_path.display()));
+    let contents = format!(
+        "{} {} file(s) run {}\n",
+        chrono::Local::now().to_rfc3339(),
+        file_count,
+        logging::run_id()
+    );
+    let written = marker_path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&partial_path, contents))
+        .and_then(|_| fs::rename(&partial_path, &marker_path));
+    match written {
+        Ok(_) => debug!("Updated upload marker {:?}", marker_path),
+        Err(e) => error!("Failed to update upload marker {:?}. Error: {}", marker_path, e),
     }
-    Ok(table_headers)
 }
 
 fn suffix_file_name(src_file: &str, file_suffix: &str) -> std::io::Result<String> {
@@ -407,12 +3591,21 @@ fn suffix_file_name(src_file: &str, file_suffix: &str) -> std::io::Result<String
     let src_file_with_suffix = binding.with_file_name(src_file_with_suffix);
     if let Err(err) = fs::rename(src_file, &src_file_with_suffix) {
         error!("Failed to rename source file. Error: {}", err);
-        return Err(err)?;
+        Err(err)?;
     }
     Ok(src_file_with_suffix.to_str().unwrap().to_string())
 }
 
+#[cfg(not(unix))]
+fn get_file_owner(file_path: &str) -> std::io::Result<String> {
+    // File ownership is not exposed by the standard library outside unix, uploaded_by is left empty
+    fs::metadata(file_path)?;
+    Ok(String::new())
+}
+
+#[cfg(unix)]
 fn get_file_owner(file_path: &str) -> std::io::Result<String> {
+    use std::os::unix::fs::MetadataExt;
     let attr = fs::metadata(file_path)?;
     let mut username: String = "".to_string();
     match Command::new("id")
@@ -438,6 +3631,16 @@ fn get_file_owner(file_path: &str) -> std::io::Result<String> {
     Ok(username)
 }
 
+fn file_mtime(file_path: &str) -> Option<String> {
+    // Get the last modified time of a file formatted as local time, None if unavailable
+    let modified = fs::metadata(file_path).and_then(|attr| attr.modified()).ok()?;
+    Some(
+        chrono::DateTime::<chrono::Local>::from(modified)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string(),
+    )
+}
+
 fn create_metadata_file(src_file: &str) -> std::io::Result<String> {
     // Create metadata file
     let username = get_file_owner(src_file).unwrap();
@@ -473,27 +3676,503 @@ fn create_metadata_file(src_file: &str) -> std::io::Result<String> {
 }
 
 fn main() -> std::io::Result<()> {
-    SimpleLogger::new().init().unwrap();
-    let (
-        src_dir,
-        dest_user,
-        dest_host,
-        dest_dir,
-        template_dir,
-        file_suffix,
-        csv_event_wait_seconds,
-        csv_event_upper_limit,
-    ) = load_env_vars();
-    let hashmap = load_headers(template_dir)?;
-    let _ = watch_for_file_changes(
-        src_dir,
-        dest_user,
-        dest_host,
-        dest_dir,
-        hashmap,
-        file_suffix,
-        csv_event_wait_seconds,
-        csv_event_upper_limit,
+    let args: Vec<String> = std::env::args().collect();
+    if args.iter().any(|arg| arg == "--print-config-schema") {
+        config::print_config_schema();
+        return Ok(());
+    }
+    let layers = config::Layers::load(&args);
+    if args.iter().any(|arg| arg == "--print-config") {
+        config::print_config(&layers);
+        return Ok(());
+    }
+    let cfg = AppConfig::from_layers(&layers);
+    logging::init(&cfg);
+    telemetry::init(cfg.otel_endpoint.as_deref());
+    slow_files::init(cfg.slow_file_warning_seconds);
+    concurrency::init(cfg.transfer_concurrency_min, cfg.transfer_concurrency_max);
+    queue_age::init(cfg.queue_age_report_seconds);
+    freshness::init(
+        cfg.freshness_slo_seconds,
+        &cfg.freshness_slo_table_seconds,
+        cfg.freshness_slo_window_seconds,
+        cfg.freshness_slo_report_seconds,
+    );
+    failure_rate::init(
+        cfg.failure_rate_alert_percent,
+        cfg.failure_rate_window_seconds,
+        cfg.failure_rate_min_attempts,
+    );
+    remote_dirs::init(cfg.remote_mkdir_once);
+    remote_writable::init(cfg.verify_remote_writable);
+    bundles::init(cfg.bundle_window_seconds.is_some());
+    preambles::init(cfg.split_max_rows.is_some() || cfg.merge_window_seconds.is_some());
+    remote_exists::init(
+        cfg.remote_exists_policy != RemoteExistsPolicy::Overwrite
+            || cfg.remote_exists_table_policies.values().any(|policy| *policy != RemoteExistsPolicy::Overwrite),
+    );
+    // A dry run would use up sequence numbers without uploading
+    sequence::init(cfg.sequence_file.as_deref().filter(|_| cfg.dry_run == DryRun::Off));
+    events::init(cfg.events_nats_url.as_deref(), &cfg.events_subject);
+    ack::init(cfg.ack_file_path.as_deref(), cfg.ack_poll_seconds, cfg.ack_timeout_seconds);
+    connectivity::init(cfg.connectivity_probe_seconds);
+    xlsx::init(&cfg.xlsx_mode);
+    volume::init(cfg.volume_report_dir.as_deref(), cfg.dest_date_tz);
+    match cfg.dry_run {
+        DryRun::Log => warn!("Dry run, transfers are logged instead of run and no source file is deleted"),
+        DryRun::Rsync => warn!("Dry run, rsync runs with --dry-run and no source file is deleted"),
+        DryRun::Off => (),
+    }
+    if let Some(protocol) = cfg.rsync_protocol.filter(|_| cfg.transport == Transport::Rsync) {
+        info!("Pinning rsync protocol version {}", protocol);
+    }
+    for (key, dir) in &cfg.excluded_source_dirs {
+        warn!("{} {:?} is inside SOURCE_DIR, excluding it from watching and scans", key, dir);
+    }
+    if cfg.dest_is_local {
+        match cfg.local_dest_mode {
+            LocalDestMode::Rsync => info!("DEST_HOST {} is this host, using local rsync without ssh", cfg.dest_host),
+            LocalDestMode::Copy => info!("DEST_HOST {} is this host, copying files without ssh", cfg.dest_host),
+            LocalDestMode::Ssh => info!("DEST_HOST {} is this host, LOCAL_DEST_MODE keeps rsync over ssh", cfg.dest_host),
+        }
+    }
+    let load_options = LoadOptions {
+        recursive: cfg.template_subdir_routing,
+        name_regex: cfg.template_name_regex.clone(),
+        name_chars: cfg.template_name_chars.clone(),
+        wrapper_chars: cfg.header_wrapper_chars.clone(),
+        unicode_form: cfg.header_unicode_form.clone(),
+        threads: cfg.template_load_threads,
+        stability_check: None,
+        encoding: cfg.template_encoding.clone(),
+        shadowing: Some(cfg.template_shadowing.clone()).filter(|_| cfg.header_match_mode == HeaderMatchMode::Prefix),
+    };
+    let templates: SharedTemplates = Arc::new(RwLock::new(load_headers(&cfg.template_dirs, &load_options)?));
+    if let Some(shadowing) = &load_options.shadowing {
+        let shadowed = templates::warn_shadowing(&templates.read().unwrap());
+        if shadowed > 0 && *shadowing == ShadowingPolicy::Error {
+            panic!("{} template(s) shadow another under HEADER_MATCH_MODE prefix, see the warnings above", shadowed);
+        }
+    }
+    let template_count = templates.read().unwrap().headers.len();
+    info!("Loaded {} template(s) from {:?}", template_count, cfg.template_dirs);
+    if template_count == 0 {
+        // An empty or misconfigured template directory otherwise leaves a watcher that never uploads anything
+        if cfg.no_templates_policy == NoTemplatesPolicy::Error {
+            panic!("No usable templates found in {:?}, refusing to start with NO_TEMPLATES_POLICY error", cfg.template_dirs);
+        }
+        warn!(
+            "No usable templates found in {:?}, no csv file can match a table until templates are added. Check TEMPLATE_DIR and the template warnings above",
+            cfg.template_dirs
+        );
+    }
+    if let Some(manifest_path) = config::command_arg(&args, "dump-templates") {
+        return templates::dump_templates(&templates.read().unwrap(), &manifest_path);
+    }
+    // Keep the template watcher alive for the lifetime of the process
+    let _template_watcher = if cfg.template_hot_reload {
+        let debounce = Duration::from_millis(cfg.template_reload_debounce_ms);
+        let stability_check = Duration::from_millis(cfg.template_stability_ms);
+        match watch_template_dir(&cfg.template_dirs, &load_options, templates.clone(), debounce, stability_check) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                error!("Failed to watch template directory, hot reload disabled: {:?}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if cfg.transport == Transport::Sftp {
+        sftp::init(&cfg);
+    }
+    probe::start(cfg.dest_probe_seconds, probe_target(&cfg), cfg.dest_probe_status_path.clone());
+    source_space::start(
+        &cfg.src_dir,
+        cfg.source_free_space_min_mb,
+        cfg.source_free_space_check_seconds,
+        cfg.source_free_space_status_path.clone(),
     );
+    if args.iter().any(|arg| arg == "--once") {
+        run_once(&cfg, &templates)?;
+    } else {
+        let _ = watch_for_file_changes(&cfg, templates);
+    }
+    sftp::shutdown();
+    telemetry::shutdown();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        // A directory of its own under the system temporary directory, so tests can run in parallel
+        let dir = std::env::temp_dir().join(format!("rsync_csv_test_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn test_file(name: &str, contents: &[u8]) -> String {
+        let path = test_dir(name).join(name);
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    fn test_config(settings: &[(&str, &str)]) -> AppConfig {
+        // Configuration with the required settings filled in, overridden by the given ones
+        let mut all_settings = vec![
+            ("SOURCE_DIR", "/src"),
+            ("DEST_USER", "user"),
+            ("DEST_HOST", "dest"),
+            ("DEST_DIR", "/dest"),
+            ("TEMPLATE_DIR", "/templates"),
+            ("FILE_SUFFIX", "_x"),
+        ];
+        all_settings.extend_from_slice(settings);
+        AppConfig::from_layers(&config::Layers::from_settings(&all_settings))
+    }
+
+    fn templates(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(headers, table_name)| (headers.to_string(), table_name.to_string())).collect()
+    }
+
+    fn split(name: &str, contents: &[u8], preamble_lines: usize, max_rows: usize) -> Vec<String> {
+        // Contents of the files that split_oversized_files sends for a file
+        let src_file = test_file(&format!("{name}.csv"), contents);
+        let work_dir = test_dir(&format!("{name}_parts"));
+        let (files, _) =
+            split_oversized_files(&[(src_file, preamble_lines)], &HeaderEncoding::Auto, max_rows, &work_dir).unwrap();
+        files.iter().map(|file| fs::read_to_string(file).unwrap()).collect()
+    }
+
+    #[test]
+    fn windows_1252_rows_pass_typed_template_checks() {
+        let path = test_file("windows_1252.csv", b"caf\xe9,count\nd\xe9j\xe0 vu,1\nna\xefve,2\n");
+        let column_types = [ColumnType::String, ColumnType::Int];
+        assert!(has_data_rows(&path, &HeaderEncoding::Auto, 0).unwrap());
+        assert_eq!(field_count_mismatch(&path, &HeaderEncoding::Auto, ',', 0), None);
+        assert_eq!(schema_violation(&path, &HeaderEncoding::Auto, &column_types, 10, ',', 0, false), None);
+        let lines: Vec<String> = decoded_lines(&path, &HeaderEncoding::Auto).unwrap().map(Result::unwrap).collect();
+        assert_eq!(lines, ["café,count", "déjà vu,1", "naïve,2"]);
+    }
+
+    #[test]
+    fn utf16_rows_are_counted_in_decoded_lines() {
+        let text = "preamble\r\nname,count\r\nx,1\r\n";
+        let mut contents = vec![0xff, 0xfe];
+        contents.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        let path = test_file("utf16.csv", &contents);
+        let column_types = [ColumnType::String, ColumnType::Int];
+        assert!(has_data_rows(&path, &HeaderEncoding::Auto, 1).unwrap());
+        assert!(!is_header_only(&path, &HeaderEncoding::Auto, 1).unwrap());
+        assert_eq!(field_count_mismatch(&path, &HeaderEncoding::Auto, ',', 1), None);
+        assert_eq!(schema_violation(&path, &HeaderEncoding::Auto, &column_types, 10, ',', 1, false), None);
+    }
+
+    #[test]
+    fn schema_check_reads_quoted_fields_across_lines() {
+        let path = test_file("schema_quoted.csv", b"note,count\n\"line 1\nline 2, \"\"quoted\"\"\",1\n\"x\",2\ny,z\n");
+        let column_types = [ColumnType::String, ColumnType::Int];
+        assert_eq!(schema_violation(&path, &HeaderEncoding::Auto, &column_types, 2, ',', 0, false), None);
+        // Records are numbered by the line they start on
+        assert_eq!(
+            schema_violation(&path, &HeaderEncoding::Auto, &column_types, 3, ',', 0, false).as_deref(),
+            Some("Line 5 column 2 value \"z\" is not Int")
+        );
+        let records: Vec<(usize, String)> = csv_records(["a,\"b".to_string(), "c\",d".to_string()].map(Ok).into_iter(), 1)
+            .map(|(index, record)| (index, record.unwrap()))
+            .collect();
+        assert_eq!(records, [(1, "a,\"b\nc\",d".to_string())]);
+        assert_eq!(csv_fields("a,\"b\nc, \"\"d\"\"\",", ','), ["a", "b\nc, \"d\"", ""]);
+    }
+
+    #[test]
+    fn field_count_ignores_quoted_delimiters() {
+        let path = test_file("field_count_quoted.csv", b"id,name,score\n1,\"Smith, J\",3\n");
+        assert_eq!(field_count_mismatch(&path, &HeaderEncoding::Auto, ',', 0), None);
+        let path = test_file("field_count_multiline.csv", b"id,note\n1,\"a\nb;c\"\n");
+        assert_eq!(field_count_mismatch(&path, &HeaderEncoding::Auto, ',', 0), None);
+        let path = test_file("field_count_mismatch.csv", b"id,name,score\n1,Smith, J,3\n");
+        assert_eq!(
+            field_count_mismatch(&path, &HeaderEncoding::Auto, ',', 0).as_deref(),
+            Some("line 2 has 4 fields, the header has 3")
+        );
+    }
+
+    #[test]
+    fn lookup_table_resolves_delimiter_ambiguity() {
+        let hashmap =
+            templates(&[("a;b;c", "one_field"), ("a,b,c", "three_fields"), ("a;b,c", "comma"), ("a,b;c", "semicolon")]);
+        let cfg = test_config(&[("CSV_DELIMITERS", ",;")]);
+        let cases = [
+            // The delimiter splitting the header into the most fields wins
+            ("a;b;c", Some(("three_fields", ';'))),
+            // Between delimiters giving as many fields, the earliest listed wins
+            ("a;b,c", Some(("comma", ','))),
+            ("a,b;c", Some(("semicolon", ','))),
+            // A trailing delimiter doesn't add a field
+            ("a;b;c;", Some(("three_fields", ';'))),
+            ("a|b|c", None),
+        ];
+        for (csv_headers, expected) in cases {
+            let found = lookup_table(csv_headers, &hashmap, &cfg)
+                .map(|(table_name, delimiter)| (table_name.as_str(), delimiter));
+            assert_eq!(found, expected, "{csv_headers:?}");
+        }
+    }
+
+    #[test]
+    fn lookup_table_strips_wrappers_and_applies_aliases() {
+        let hashmap = templates(&[("id,name", "customers")]);
+        let cfg = test_config(&[("HEADER_WRAPPER_CHARS", "\"[]"), ("HEADER_ALIASES", "customer_id=id")]);
+        let cases = [
+            ("id,name", Some("customers")),
+            ("\"id\",[name]", Some("customers")),
+            ("customer_id,name", Some("customers")),
+            ("\"customer_id\",\"name\"", Some("customers")),
+            // Aliases replace whole fields only
+            ("customer_id2,name", None),
+            ("id,\"na\"me", None),
+        ];
+        for (csv_headers, expected) in cases {
+            let found = lookup_table(csv_headers, &hashmap, &cfg).map(|(table_name, _)| table_name.as_str());
+            assert_eq!(found, expected, "{csv_headers:?}");
+        }
+    }
+
+    #[test]
+    fn longest_prefix_template_prefers_most_columns() {
+        let hashmap = templates(&[("a", "short"), ("a,b", "longer"), ("a,bc", "other"), ("b,c", "unrelated")]);
+        let cases = [
+            ("a,b,c", Some("longer")),
+            ("a,b", Some("longer")),
+            ("a,bc,d", Some("other")),
+            // Prefixes end at a field boundary
+            ("a,bcd", Some("short")),
+            ("ab,c", None),
+            ("c,a,b", None),
+        ];
+        for (csv_headers, expected) in cases {
+            assert_eq!(longest_prefix_template(csv_headers, &hashmap).map(String::as_str), expected, "{csv_headers:?}");
+        }
+        // The prefix mode matches through lookup_table as well
+        let cfg = test_config(&[("HEADER_MATCH_MODE", "prefix")]);
+        assert_eq!(lookup_table("a,b,extra", &hashmap, &cfg).map(|(table_name, _)| table_name.as_str()), Some("longer"));
+    }
+
+    #[test]
+    fn dest_file_name_applies_table_case_and_extension() {
+        let cfg = test_config(&[
+            ("TABLE_DEST_FILENAME_CASE", "upper=upper,lower=lower"),
+            ("TABLE_DEST_FILENAME_EXTENSION", "upper=.TXT,lower=dat"),
+            ("PASSTHROUGH_EXTENSIONS", "json"),
+        ]);
+        let cases = [
+            ("upper", "Sales_2024.csv", "SALES_2024.TXT"),
+            ("lower", "Sales_2024.csv", "sales_2024.dat"),
+            ("lower", "Sales.2024.CSV", "sales.2024.dat"),
+            ("lower", "no_extension", "no_extension.dat"),
+            // Passthrough files keep their own extension
+            ("lower", "Notes.json", "notes.json"),
+            ("other", "Sales_2024.csv", "Sales_2024.csv"),
+        ];
+        for (table_name, file_name, expected) in cases {
+            assert_eq!(dest_file_name(&cfg, table_name, file_name, None, None), expected, "{table_name} {file_name}");
+        }
+    }
+
+    #[test]
+    fn dest_file_name_prefixes_zero_padded_sequence() {
+        let cfg = test_config(&[("SEQUENCE_FILE", "/sequence"), ("SEQUENCE_IN_FILENAME", "true")]);
+        assert_eq!(dest_file_name(&cfg, "table", "sales.csv", Some(42), None), "000000000042_sales.csv");
+        assert_eq!(dest_file_name(&cfg, "table", "sales.csv", None, None), "sales.csv");
+        // The table's case and extension still apply to the rest of the name
+        let cfg = test_config(&[
+            ("SEQUENCE_FILE", "/sequence"),
+            ("SEQUENCE_IN_FILENAME", "true"),
+            ("TABLE_DEST_FILENAME_CASE", "table=upper"),
+            ("TABLE_DEST_FILENAME_EXTENSION", "table=txt"),
+        ]);
+        assert_eq!(dest_file_name(&cfg, "table", "sales.csv", Some(7), None), "000000000007_SALES.txt");
+        // Numbers are only added to names with SEQUENCE_IN_FILENAME
+        let cfg = test_config(&[("SEQUENCE_FILE", "/sequence")]);
+        assert_eq!(dest_file_name(&cfg, "table", "sales.csv", Some(42), None), "sales.csv");
+    }
+
+    #[test]
+    fn dest_file_name_versions_before_the_extension() {
+        let cfg = test_config(&[("TABLE_DEST_FILENAME_EXTENSION", "renamed=txt")]);
+        let cases = [
+            ("table", "sales.csv", "sales_v2.csv"),
+            ("table", "sales.2024.csv", "sales.2024_v2.csv"),
+            ("table", "no_extension", "no_extension_v2"),
+            // A leading dot starts a hidden name, not an extension
+            ("table", ".hidden", ".hidden_v2"),
+            ("renamed", "sales.csv", "sales_v2.txt"),
+        ];
+        for (table_name, file_name, expected) in cases {
+            assert_eq!(dest_file_name(&cfg, table_name, file_name, None, Some(2)), expected, "{table_name} {file_name}");
+        }
+    }
+
+    #[test]
+    fn redacted_rsync_command_hides_credentials() {
+        let cfg = test_config(&[]);
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<String>>();
+        let cases = [
+            (args(&["--rsh=ssh -i /keys/id_rsa -p 22", "a.csv"]), "-i <redacted> -p 22 a.csv"),
+            (args(&["--rsh=ssh -i'/keys/id rsa'", "a.csv"]), "-i<redacted> a.csv"),
+            (args(&["--rsh=sshpass -f '/secrets/pw' ssh", "a.csv"]), "-f <redacted> ssh a.csv"),
+            (args(&["--password-file=/secrets/pw", "a.csv"]), "--password-file=<redacted> a.csv"),
+            (args(&["--rsh=ssh -o IdentityFile=/keys/id_rsa", "a.csv"]), "-o IdentityFile=<redacted> a.csv"),
+            // Quoted paths with quotes in them are redacted whole
+            (args(&["--rsh=ssh -i '/keys/o'\\''key' -p 22", "a.csv"]), "-i <redacted> -p 22 a.csv"),
+            (args(&["--password-file='/it'\\''s/pw'", "it's.csv"]), "--password-file=<redacted> it's.csv"),
+        ];
+        for (rsync_args, expected) in cases {
+            // Only the end of the command is compared, the rest is unchanged
+            let redacted = redacted_rsync_command(&cfg, &rsync_args);
+            assert!(redacted.ends_with(expected), "{redacted:?} doesn't end with {expected:?}");
+        }
+        // The configured key is redacted wherever it appears, also as ssh_command quotes it
+        let identity_file = test_file("o'key", b"key");
+        let cfg = test_config(&[("DEST_AUTH", "key"), ("DEST_IDENTITY_FILE", &identity_file)]);
+        let rsync_args = args(&["-az", &format!("--rsh={}", ssh_command(&cfg).unwrap()), "a.csv"]);
+        let redacted = redacted_rsync_command(&cfg, &rsync_args);
+        assert!(redacted.ends_with(" -az --rsh=ssh -i <redacted> -o IdentitiesOnly=yes a.csv"), "{redacted:?}");
+        assert!(!redacted_rsync_command(&cfg, &args(&["--log-file", &identity_file])).contains("key"));
+    }
+
+    #[test]
+    fn permission_denied_is_a_lock_only_for_the_local_file() {
+        let src_file = test_file("locked.csv", b"h\n1\n");
+        let cases = [
+            (format!("rsync: [sender] send_files failed to open \"{src_file}\": Permission denied (13)"), true),
+            ("rsync: send_files failed to open \"locked.csv\": Permission denied (13)".to_string(), true),
+            (format!("rsync: open \"{src_file}\" failed: Permission denied (13)"), true),
+            (format!("rsync: read errors mapping \"{src_file}\": Resource temporarily unavailable (11)"), true),
+            // Failures writing on the destination
+            ("rsync: mkstemp \"/dest/.locked.csv.Xy12\" failed: Permission denied (13)".to_string(), false),
+            ("rsync: [Receiver] mkdir \"/dest/table\" failed: Permission denied (13)".to_string(), false),
+            ("Permission denied (publickey,password).".to_string(), false),
+            ("rsync error: some files could not be transferred (code 23)".to_string(), false),
+        ];
+        for (err_msg, locked) in cases {
+            assert_eq!(is_lock_failure(&err_msg, &src_file), locked, "{err_msg}");
+        }
+        // A file that still can't be opened has a permission problem, not a lock
+        let missing = format!("{src_file}.missing");
+        let err_msg = format!("rsync: [sender] send_files failed to open \"{missing}\": Permission denied (13)");
+        assert!(!is_lock_failure(&err_msg, &missing));
+    }
+
+    fn merge(name: &str, files: &[(&[u8], usize)], max_bytes: u64) -> Vec<String> {
+        // Contents of the files that merge_small_files sends for files of a table, each with its preamble lines
+        let src_files: Vec<(String, usize)> = files
+            .iter()
+            .enumerate()
+            .map(|(i, (contents, preamble_lines))| (test_file(&format!("{name}_{i}.csv"), contents), *preamble_lines))
+            .collect();
+        let work_dir = test_dir(&format!("{name}_merged"));
+        let files = merge_small_files("table", &src_files, &HeaderEncoding::Auto, 1024, max_bytes, &work_dir).unwrap();
+        files.iter().map(|(file, _)| fs::read_to_string(file).unwrap()).collect()
+    }
+
+    #[test]
+    fn merge_keeps_header_once() {
+        assert_eq!(merge("header_once", &[(b"h\n1\n", 0), (b"h\n2\n", 0), (b"h\n3\n", 0)], 1024), ["h\n1\n2\n3\n"]);
+        // A UTF-8 BOM doesn't make an appended file's header differ
+        assert_eq!(merge("bom", &[(b"h\n1\n", 0), (b"\xef\xbb\xbfh\n2\n", 0)], 1024), ["h\n1\n2\n"]);
+    }
+
+    #[test]
+    fn merge_ends_rows_without_final_newline() {
+        assert_eq!(merge("no_final_newline", &[(b"h\n1", 0), (b"h\n2", 0)], 1024), ["h\n1\n2\n"]);
+    }
+
+    #[test]
+    fn merge_keeps_crlf() {
+        assert_eq!(merge("crlf", &[(b"h\r\n1\r\n", 0), (b"h\r\n2", 0)], 1024), ["h\r\n1\r\n2\r\n"]);
+    }
+
+    #[test]
+    fn merge_starts_new_file_on_differing_header() {
+        let merged = merge("differing", &[(b"h\n1\n", 0), (b"h\n2\n", 0), (b"other\n3\n", 0), (b"other\n4\n", 0)], 1024);
+        assert_eq!(merged, ["h\n1\n2\n", "other\n3\n4\n"]);
+        // A file with nothing to merge with is sent as it is
+        assert_eq!(merge("single", &[(b"h\n1\n", 0), (b"other\n2\n", 0)], 1024), ["h\n1\n", "other\n2\n"]);
+    }
+
+    #[test]
+    fn merge_compares_header_after_preamble() {
+        let merged = merge("preamble", &[(b"title 1\nh\n1\n", 1), (b"title 2\nh\n2\n", 1), (b"h\n3\n", 0)], 1024);
+        assert_eq!(merged, ["title 1\nh\n1\n2\n3\n"]);
+    }
+
+    #[test]
+    fn merge_keeps_encodings_apart() {
+        let mut utf16 = vec![0xff, 0xfe];
+        utf16.extend("h\n3\n".encode_utf16().flat_map(u16::to_le_bytes));
+        let contents: [&[u8]; 5] = [b"h\n1\n", b"h\n2\n", &utf16, b"h\ncaf\xe9\n", b"h\nna\xefve\n"];
+        let src_files: Vec<(String, usize)> = contents
+            .iter()
+            .enumerate()
+            .map(|(i, contents)| (test_file(&format!("encodings_{i}.csv"), contents), 0))
+            .collect();
+        let work_dir = test_dir("encodings_merged");
+        let files = merge_small_files("table", &src_files, &HeaderEncoding::Auto, 1024, 1024, &work_dir).unwrap();
+        let contents: Vec<Vec<u8>> = files.iter().map(|(file, _)| fs::read(file).unwrap()).collect();
+        // The UTF-16 file is sent as it is, the windows-1252 files are merged apart from the UTF-8 ones
+        assert_eq!(contents, [utf16, b"h\n1\n2\n".to_vec(), b"h\ncaf\xe9\nna\xefve\n".to_vec()]);
+        assert_eq!(files[0].0, src_files[2].0);
+    }
+
+    #[test]
+    fn split_parts_at_exact_multiples_of_max_rows() {
+        assert_eq!(split("exact_multiple", b"h\n1\n2\n3\n4\n", 0, 2), ["h\n1\n2\n", "h\n3\n4\n"]);
+        assert_eq!(split("exact_max", b"h\n1\n2\n3\n4\n", 0, 4), ["h\n1\n2\n3\n4\n"]);
+    }
+
+    #[test]
+    fn split_repeats_preamble_and_header() {
+        assert_eq!(split("preamble", b"title\nh\n1\n2\n3\n", 1, 2), ["title\nh\n1\n2\n", "title\nh\n3\n"]);
+        // The preamble is not counted as rows
+        assert_eq!(split("preamble_max", b"title\nh\n1\n2\n", 1, 2), ["title\nh\n1\n2\n"]);
+    }
+
+    #[test]
+    fn split_keeps_crlf_and_missing_final_newline() {
+        assert_eq!(split("crlf", b"h\r\n1\r\n2\r\n3", 0, 2), ["h\r\n1\r\n2\r\n", "h\r\n3"]);
+    }
+
+    #[test]
+    fn split_keeps_quoted_line_breaks_in_one_part() {
+        let contents = b"id,note\n1,\"first\nsecond\"\n2,\"say \"\"hi\"\"\nthere\"\n3,x\n";
+        let parts = split("quoted_line_breaks", contents, 0, 1);
+        let expected = ["id,note\n1,\"first\nsecond\"\n", "id,note\n2,\"say \"\"hi\"\"\nthere\"\n", "id,note\n3,x\n"];
+        assert_eq!(parts, expected);
+        // Rows spanning lines count once against max_rows
+        assert_eq!(split("quoted_line_breaks_fit", contents, 0, 3), [std::str::from_utf8(contents).unwrap()]);
+    }
+
+    #[test]
+    fn split_sends_utf16_files_whole() {
+        let mut contents = vec![0xff, 0xfe];
+        contents.extend("h\n1\n2\n3\n".encode_utf16().flat_map(u16::to_le_bytes));
+        let src_file = test_file("split_utf16.csv", &contents);
+        let work_dir = test_dir("split_utf16_parts");
+        let (files, split_files) =
+            split_oversized_files(&[(src_file.clone(), 0)], &HeaderEncoding::Auto, 1, &work_dir).unwrap();
+        assert_eq!(files, std::slice::from_ref(&src_file));
+        assert!(split_files.is_empty());
+        assert_eq!(fs::read_dir(&work_dir).unwrap().count(), 0);
+        // Also without a BOM, when HEADER_ENCODING names the encoding
+        let src_file = test_file("split_utf16_label.csv", &contents[2..]);
+        let encoding: HeaderEncoding = "utf-16le".parse().unwrap();
+        let (files, _) = split_oversized_files(&[(src_file.clone(), 0)], &encoding, 1, &work_dir).unwrap();
+        assert_eq!(files, [src_file]);
+    }
+}
@@ -0,0 +1,37 @@
+// Lines before the header of queued files, for files whose header was found below their first line with
+// HEADER_SCAN_LINES. Recorded when a file is queued, keyed by its suffixed name, so SPLIT_MAX_ROWS and
+// MERGE_WINDOW_SECONDS, which rewrite files at transfer time, know which line is the header.
+// Without SPLIT_MAX_ROWS or MERGE_WINDOW_SECONDS, every function here is a no-op and every file counts as having
+// its header on the first line.
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+};
+
+static PREAMBLES: OnceLock<Mutex<HashMap<String, usize>>> = OnceLock::new();
+
+pub fn init(enabled: bool) {
+    if enabled {
+        let _ = PREAMBLES.set(Mutex::new(HashMap::new()));
+    }
+}
+
+pub fn assign(src_file: &str, lines: usize) {
+    // Files with their header on the first line are not recorded
+    if let Some(preambles) = PREAMBLES.get().filter(|_| lines > 0) {
+        preambles.lock().unwrap().insert(src_file.to_string(), lines);
+    }
+}
+
+pub fn lines(src_file: &str) -> usize {
+    PREAMBLES
+        .get()
+        .and_then(|preambles| preambles.lock().unwrap().get(src_file).copied())
+        .unwrap_or_default()
+}
+
+pub fn forget(src_file: &str) {
+    if let Some(preambles) = PREAMBLES.get() {
+        preambles.lock().unwrap().remove(src_file);
+    }
+}
@@ -0,0 +1,124 @@
+// Destination reachability probe, for early warning of destination outages before files need to transfer.
+// Runs in its own thread, so a slow or failing probe never delays or fails transfers, it is only reported: logged
+// when reachability changes and written to DEST_PROBE_STATUS_PATH after every probe.
+// Without DEST_PROBE_SECONDS, no probe is started.
+use log::{debug, error, info, warn};
+use std::{
+    fs,
+    net::{TcpStream, ToSocketAddrs},
+    path::PathBuf,
+    process::Command,
+    thread,
+    time::Duration,
+};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How the destination is probed, matching how files are transferred to it
+pub enum Target {
+    // Run `true` over ssh, with the ssh command line and user@host of the transfers
+    Ssh { command: Vec<String>, destination: String },
+    // Open a connection to host:port, for the sftp transport
+    Tcp(String),
+    // Check that the destination directory exists, for local destinations
+    Local(PathBuf),
+}
+
+impl Target {
+    pub fn probe(&self) -> Result<(), String> {
+        match self {
+            Target::Ssh { command, destination } => {
+                let output = Command::new(&command[0])
+                    .args(&command[1..])
+                    .args(["-o", &format!("ConnectTimeout={}", PROBE_TIMEOUT.as_secs())])
+                    .arg(destination)
+                    .arg("true")
+                    .output()
+                    .map_err(|e| e.to_string())?;
+                if output.status.success() {
+                    Ok(())
+                } else {
+                    Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+                }
+            }
+            Target::Tcp(address) => {
+                let address = address
+                    .to_socket_addrs()
+                    .map_err(|e| e.to_string())?
+                    .next()
+                    .ok_or_else(|| format!("{address} did not resolve"))?;
+                TcpStream::connect_timeout(&address, PROBE_TIMEOUT).map(|_| ()).map_err(|e| e.to_string())
+            }
+            Target::Local(dir) => match fs::metadata(dir) {
+                Ok(metadata) if metadata.is_dir() => Ok(()),
+                Ok(_) => Err(format!("{dir:?} is not a directory")),
+                Err(e) => Err(e.to_string()),
+            },
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Target::Ssh { destination, .. } => destination.clone(),
+            Target::Tcp(address) => address.clone(),
+            Target::Local(dir) => dir.to_string_lossy().to_string(),
+        }
+    }
+}
+
+pub fn start(interval_seconds: Option<u64>, target: Target, status_path: Option<String>) {
+    let Some(interval_seconds) = interval_seconds else {
+        return;
+    };
+    let destination = target.describe();
+    info!("Probing destination {} every {} second(s)", destination, interval_seconds);
+    thread::spawn(move || {
+        let mut reachable: Option<bool> = None;
+        let mut consecutive_failures: u64 = 0;
+        let mut last_success: Option<String> = None;
+        loop {
+            let result = target.probe();
+            let checked_at = chrono::Local::now().to_rfc3339();
+            match &result {
+                Ok(_) => {
+                    if reachable != Some(true) {
+                        info!("Destination {} is reachable", destination);
+                    }
+                    consecutive_failures = 0;
+                    last_success = Some(checked_at.clone());
+                }
+                Err(e) => {
+                    if reachable != Some(false) {
+                        warn!("Destination {} is unreachable: {}", destination, e);
+                    } else {
+                        debug!("Destination {} is still unreachable: {}", destination, e);
+                    }
+                    consecutive_failures += 1;
+                }
+            }
+            reachable = Some(result.is_ok());
+            if let Some(status_path) = &status_path {
+                let status = serde_json::json!({
+                    "destination": destination,
+                    "reachable": result.is_ok(),
+                    "error": result.err(),
+                    "checked_at": checked_at,
+                    "last_success": last_success,
+                    "consecutive_failures": consecutive_failures,
+                    "run_id": crate::logging::run_id(),
+                });
+                if let Err(e) = write_status(status_path, &status) {
+                    error!("Failed to write destination probe status {}. Error: {}", status_path, e);
+                }
+            }
+            thread::sleep(Duration::from_secs(interval_seconds));
+        }
+    });
+}
+
+fn write_status(status_path: &str, status: &serde_json::Value) -> std::io::Result<()> {
+    // Rename into place, so monitoring never reads a partial file
+    let partial_path = format!("{status_path}.partial");
+    fs::write(&partial_path, serde_json::to_string_pretty(status)?)?;
+    fs::rename(&partial_path, status_path)
+}
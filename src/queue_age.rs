@@ -0,0 +1,90 @@
+// Age distribution of csv files waiting to be processed, as early warning of a growing backlog. Files are timed from
+// the first event that queues them until they are processed, and every QUEUE_AGE_REPORT_SECONDS the ages of waiting
+// files and the waits of files processed since the last report are logged.
+// Without QUEUE_AGE_REPORT_SECONDS, every function here is a no-op.
+use log::info;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+struct QueueAges {
+    interval: Duration,
+    last_report: Instant,
+    // Queued files mapped to when they were first queued
+    waiting: HashMap<PathBuf, Instant>,
+    // Waits of files processed since the last report
+    waits: Vec<Duration>,
+}
+
+static QUEUE_AGES: OnceLock<Mutex<QueueAges>> = OnceLock::new();
+
+pub fn init(interval_seconds: Option<u64>) {
+    let Some(interval_seconds) = interval_seconds else {
+        return;
+    };
+    let _ = QUEUE_AGES.set(Mutex::new(QueueAges {
+        interval: Duration::from_secs(interval_seconds),
+        last_report: Instant::now(),
+        waiting: HashMap::new(),
+        waits: Vec::new(),
+    }));
+}
+
+pub fn enqueued(path: &Path) {
+    // Repeated events for a queued file keep its first queue time
+    if let Some(queue_ages) = QUEUE_AGES.get() {
+        queue_ages.lock().unwrap().waiting.entry(path.to_path_buf()).or_insert_with(Instant::now);
+    }
+}
+
+pub fn processed(path: &Path) {
+    if let Some(queue_ages) = QUEUE_AGES.get() {
+        let mut queue_ages = queue_ages.lock().unwrap();
+        if let Some(queued) = queue_ages.waiting.remove(path) {
+            queue_ages.waits.push(queued.elapsed());
+        }
+    }
+}
+
+pub fn report() {
+    // Called from the watch loop, logs once the interval has passed and there was anything queued
+    let Some(queue_ages) = QUEUE_AGES.get() else {
+        return;
+    };
+    let mut queue_ages = queue_ages.lock().unwrap();
+    if queue_ages.last_report.elapsed() < queue_ages.interval {
+        return;
+    }
+    queue_ages.last_report = Instant::now();
+    let mut ages: Vec<Duration> = queue_ages.waiting.values().map(|queued| queued.elapsed()).collect();
+    let mut waits: Vec<Duration> = std::mem::take(&mut queue_ages.waits);
+    if ages.is_empty() && waits.is_empty() {
+        return;
+    }
+    ages.sort();
+    waits.sort();
+    info!(
+        "Queue ages: {} file(s) waiting (oldest {}, p50 {}, p95 {}), {} file(s) processed since the last report (max wait {}, p50 {}, p95 {})",
+        ages.len(),
+        seconds(ages.last()),
+        seconds(percentile(&ages, 50)),
+        seconds(percentile(&ages, 95)),
+        waits.len(),
+        seconds(waits.last()),
+        seconds(percentile(&waits, 50)),
+        seconds(percentile(&waits, 95)),
+    );
+}
+
+fn percentile(sorted: &[Duration], percent: usize) -> Option<&Duration> {
+    // Nearest rank percentile of sorted durations
+    let rank = (sorted.len() * percent).div_ceil(100);
+    sorted.get(rank.max(1) - 1)
+}
+
+fn seconds(duration: Option<&Duration>) -> String {
+    duration.map_or("-".to_string(), |duration| format!("{:.1}s", duration.as_secs_f64()))
+}
@@ -0,0 +1,23 @@
+// Remote directories ensured this run. Once a transfer created a destination directory, later transfers to it skip
+// the remote `mkdir -p`, and concurrent first transfers to the same new directory wait for the one creating it.
+// Without REMOTE_MKDIR_ONCE, every function here is a no-op.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+// Whether each directory, keyed by user@host:path, is known to exist
+static ENSURED: OnceLock<Mutex<HashMap<String, Arc<Mutex<bool>>>>> = OnceLock::new();
+
+pub fn init(enabled: bool) {
+    if enabled {
+        let _ = ENSURED.set(Mutex::new(HashMap::new()));
+    }
+}
+
+pub fn entry(destination: &str, remote_dir: &str) -> Option<Arc<Mutex<bool>>> {
+    // The flag of a remote directory. Transfers hold its lock until they know whether the directory exists.
+    let ensured = ENSURED.get()?;
+    let key = format!("{destination}:{remote_dir}");
+    Some(ensured.lock().unwrap().entry(key).or_default().clone())
+}
@@ -0,0 +1,51 @@
+// What to do about files whose remote name is already taken on the destination, decided per file right before its
+// transfer by REMOTE_EXISTS_POLICY: a skipped file is left in place instead of being sent, a versioned file is sent
+// under its name with the first free `_v<N>` counter.
+// Without a policy other than overwrite, every function here is a no-op.
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+};
+
+pub enum Decision {
+    // Kept in place, carrying the remote path that exists
+    Skipped(String),
+    // Sent with this version counter
+    Versioned(u32),
+}
+
+static DECISIONS: OnceLock<Mutex<HashMap<String, Decision>>> = OnceLock::new();
+
+pub fn init(enabled: bool) {
+    if enabled {
+        let _ = DECISIONS.set(Mutex::new(HashMap::new()));
+    }
+}
+
+pub fn decide(src_file: &str, decision: Decision) {
+    if let Some(decisions) = DECISIONS.get() {
+        decisions.lock().unwrap().insert(src_file.to_string(), decision);
+    }
+}
+
+pub fn version(src_file: &str) -> Option<u32> {
+    match DECISIONS.get()?.lock().unwrap().get(src_file) {
+        Some(Decision::Versioned(version)) => Some(*version),
+        _ => None,
+    }
+}
+
+pub fn skipped(src_file: &str) -> Option<String> {
+    // The existing remote path of a file that was skipped, None if it was sent
+    match DECISIONS.get()?.lock().unwrap().get(src_file) {
+        Some(Decision::Skipped(remote_path)) => Some(remote_path.clone()),
+        _ => None,
+    }
+}
+
+pub fn forget(src_file: &str) {
+    // Drop the decision about a file once it was handled, so a retry decides again
+    if let Some(decisions) = DECISIONS.get() {
+        decisions.lock().unwrap().remove(src_file);
+    }
+}
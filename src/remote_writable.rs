@@ -0,0 +1,42 @@
+// Pre-flight write test of destination directories. Before the first transfer to a directory, a probe file is created
+// and deleted in it, so a read-only directory fails the transfer with a clear reason instead of partway through it.
+// A directory that passed is not probed again this run. One that failed is probed again on the next transfer, so
+// fixing its permissions is picked up without a restart.
+// Without VERIFY_REMOTE_WRITABLE, every function here is a no-op.
+use log::{debug, error, info};
+use std::{
+    collections::HashSet,
+    sync::{Mutex, OnceLock},
+};
+
+// Directories, keyed by user@host:path, that passed the write test
+static WRITABLE: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+
+pub fn init(enabled: bool) {
+    if enabled {
+        let _ = WRITABLE.set(Mutex::new(HashSet::new()));
+    }
+}
+
+pub fn verify(destination: &str, remote_dir: &str, probe: impl FnOnce() -> Result<(), String>) -> Result<(), String> {
+    let Some(writable) = WRITABLE.get() else {
+        return Ok(());
+    };
+    let key = format!("{destination}:{remote_dir}");
+    if writable.lock().unwrap().contains(&key) {
+        debug!("Remote directory {} on {} was verified writable earlier this run", remote_dir, destination);
+        return Ok(());
+    }
+    info!("Verifying that remote directory {} on {} is writable", remote_dir, destination);
+    match probe() {
+        Ok(_) => {
+            info!("Remote directory {} on {} is writable", remote_dir, destination);
+            writable.lock().unwrap().insert(key);
+            Ok(())
+        }
+        Err(e) => {
+            error!("Remote directory {} on {} is not writable: {}", remote_dir, destination, e);
+            Err(format!("Remote directory {remote_dir} on {destination} is not writable: {e}"))
+        }
+    }
+}
@@ -0,0 +1,180 @@
+// Per-file retry queue. Failed uploads wait here for their backoff to elapse instead of blocking new files.
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+// A suffixed source file waiting to be uploaded again
+pub struct PendingFile {
+    pub table_name: String,
+    pub src_file: String,
+    pub metadata_file: String,
+    pub uploaded_by: String,
+}
+
+pub struct RetryQueue {
+    max_retries: u32,
+    backoff: Duration,
+    // Failed attempts so far, keyed by source file
+    attempts: HashMap<String, u32>,
+    // Attempts that failed because the file was locked, counted apart from the other failures
+    lock_attempts: HashMap<String, u32>,
+    pending: Vec<(Instant, PendingFile)>,
+    // When each file last ran out of retries, keyed by source file
+    gave_up: HashMap<String, Instant>,
+}
+
+impl RetryQueue {
+    pub fn new(max_retries: u32, backoff: Duration) -> RetryQueue {
+        RetryQueue {
+            max_retries,
+            backoff,
+            attempts: HashMap::new(),
+            lock_attempts: HashMap::new(),
+            pending: Vec::new(),
+            gave_up: HashMap::new(),
+        }
+    }
+
+    pub fn failed(&mut self, file: PendingFile) -> Result<Duration, PendingFile> {
+        // Record a failed attempt and schedule the next one. Once retries are used up, the file is handed back.
+        let attempts = self.attempts.entry(file.src_file.clone()).or_insert(0);
+        *attempts += 1;
+        if *attempts > self.max_retries {
+            self.attempts.remove(&file.src_file);
+            self.gave_up.insert(file.src_file.clone(), Instant::now());
+            return Err(file);
+        }
+        // Double the backoff after every failed attempt
+        let delay = self.backoff.saturating_mul(1 << (*attempts - 1).min(16));
+        self.pending.push((Instant::now() + delay, file));
+        Ok(delay)
+    }
+
+    pub fn locked(&mut self, file: PendingFile, delay: Duration, max_lock_retries: u32) -> Result<u32, PendingFile> {
+        // Schedule a file that was locked while it was sent for another attempt after delay, without using up one of
+        // its retries. Once its lock retries are used up, the file is handed back to be handled as a failure.
+        let lock_attempts = self.lock_attempts.entry(file.src_file.clone()).or_insert(0);
+        if *lock_attempts >= max_lock_retries {
+            self.lock_attempts.remove(&file.src_file);
+            return Err(file);
+        }
+        *lock_attempts += 1;
+        let lock_attempts = *lock_attempts;
+        self.attempts.entry(file.src_file.clone()).or_insert(0);
+        self.pending.push((Instant::now() + delay, file));
+        Ok(lock_attempts)
+    }
+
+    pub fn requeue(&mut self, file: PendingFile) {
+        // Queue a file to go again right away without counting the failed attempt, e.g. once connectivity returns
+        self.attempts.entry(file.src_file.clone()).or_insert(0);
+        self.pending.push((Instant::now(), file));
+    }
+
+    pub fn forget(&mut self, src_file: &str) {
+        // Drop the attempt count of a file that was uploaded or no longer exists
+        self.attempts.remove(src_file);
+        self.lock_attempts.remove(src_file);
+        self.gave_up.remove(src_file);
+    }
+
+    pub fn is_pending(&self, src_file: &str) -> bool {
+        // Whether a file is waiting for a retry or being retried
+        self.attempts.contains_key(src_file)
+    }
+
+    pub fn gave_up_within(&mut self, src_file: &str, window: Duration) -> bool {
+        // Whether a file ran out of retries less than window ago. Older entries are dropped.
+        self.gave_up.retain(|_, gave_up_at| gave_up_at.elapsed() < window);
+        self.gave_up.contains_key(src_file)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        // Whether no file is waiting for its backoff to elapse
+        self.pending.is_empty()
+    }
+
+    pub fn gave_up_files(&self) -> Vec<&String> {
+        // Files that ran out of retries and were not uploaded since, sorted for logging
+        let mut files: Vec<&String> = self.gave_up.keys().collect();
+        files.sort();
+        files
+    }
+
+    pub fn take_due(&mut self) -> Vec<PendingFile> {
+        // Remove and return every file whose backoff has elapsed
+        let now = Instant::now();
+        let (due, waiting): (Vec<_>, Vec<_>) = self.pending.drain(..).partition(|(next_attempt, _)| *next_attempt <= now);
+        self.pending = waiting;
+        due.into_iter().map(|(_, file)| file).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending_file(src_file: &str) -> PendingFile {
+        PendingFile {
+            table_name: "table".to_string(),
+            src_file: src_file.to_string(),
+            metadata_file: format!("{src_file}.json"),
+            uploaded_by: "test".to_string(),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_until_retries_are_used_up() {
+        let mut retry_queue = RetryQueue::new(3, Duration::from_secs(10));
+        let delays: Vec<Option<Duration>> = (0..3).map(|_| retry_queue.failed(pending_file("a")).ok()).collect();
+        assert_eq!(delays, [10, 20, 40].map(|seconds| Some(Duration::from_secs(seconds))));
+        assert!(retry_queue.is_pending("a"));
+        let given_up = retry_queue.failed(pending_file("a")).unwrap_err();
+        assert_eq!(given_up.src_file, "a");
+        assert!(!retry_queue.is_pending("a"));
+        assert_eq!(retry_queue.gave_up_files(), ["a"]);
+        // Files are counted apart from each other
+        assert_eq!(retry_queue.failed(pending_file("b")).ok(), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn lock_attempts_dont_use_up_retries() {
+        let mut retry_queue = RetryQueue::new(1, Duration::from_secs(10));
+        let delay = Duration::from_secs(1);
+        assert_eq!(retry_queue.locked(pending_file("a"), delay, 2).ok(), Some(1));
+        assert_eq!(retry_queue.locked(pending_file("a"), delay, 2).ok(), Some(2));
+        assert!(retry_queue.is_pending("a"));
+        // Once the lock retries are used up, the file is handed back to be handled as a failure
+        let file = retry_queue.locked(pending_file("a"), delay, 2).unwrap_err();
+        assert_eq!(retry_queue.failed(file).ok(), Some(Duration::from_secs(10)));
+        assert!(retry_queue.failed(pending_file("a")).is_err());
+    }
+
+    #[test]
+    fn due_files_are_taken_once() {
+        let mut retry_queue = RetryQueue::new(3, Duration::ZERO);
+        assert!(retry_queue.failed(pending_file("a")).is_ok());
+        assert!(retry_queue.locked(pending_file("b"), Duration::from_secs(3600), 1).is_ok());
+        let due: Vec<String> = retry_queue.take_due().into_iter().map(|file| file.src_file).collect();
+        assert_eq!(due, ["a"]);
+        assert!(retry_queue.take_due().is_empty());
+        assert!(!retry_queue.is_empty());
+    }
+
+    #[test]
+    fn gave_up_within_drops_entries_older_than_the_window() {
+        let mut retry_queue = RetryQueue::new(0, Duration::from_secs(10));
+        assert!(retry_queue.failed(pending_file("a")).is_err());
+        assert!(retry_queue.failed(pending_file("b")).is_err());
+        assert!(retry_queue.gave_up_within("a", Duration::from_secs(3600)));
+        assert!(!retry_queue.gave_up_within("c", Duration::from_secs(3600)));
+        assert_eq!(retry_queue.gave_up_files(), ["a", "b"]);
+        // A zero window has every entry older than it, including those of other files
+        assert!(!retry_queue.gave_up_within("a", Duration::ZERO));
+        assert!(retry_queue.gave_up_files().is_empty());
+        assert!(retry_queue.failed(pending_file("c")).is_err());
+        retry_queue.forget("c");
+        assert!(!retry_queue.gave_up_within("c", Duration::from_secs(3600)));
+    }
+}
@@ -0,0 +1,109 @@
+// Sequence numbers of uploaded files, increasing across all tables and restarts, for downstream consumers that need a
+// total order of uploads. Numbers are given to files right before their transfer and the counter is saved first, so
+// a restart never hands out a number twice. A failed transfer leaves a gap, its files get new numbers when retried.
+// Without SEQUENCE_FILE, every function here is a no-op.
+use log::info;
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+};
+
+struct Counter {
+    path: PathBuf,
+    // Last number handed out, 0 before the first
+    last: u64,
+    // Number of each file being transferred, keyed by source file
+    files: HashMap<String, u64>,
+}
+
+static COUNTER: OnceLock<Mutex<Counter>> = OnceLock::new();
+
+impl Counter {
+    fn load(path: &str) -> Counter {
+        // A counter that can't be read would restart numbering, so refuse to start instead
+        let last = match fs::read_to_string(path) {
+            Ok(contents) => match contents.trim().parse() {
+                Ok(last) => last,
+                Err(e) => panic!("SEQUENCE_FILE {} doesn't hold a sequence number: {}", path, e),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => panic!("SEQUENCE_FILE {} can't be read: {}", path, e),
+        };
+        Counter {
+            path: PathBuf::from(path),
+            last,
+            files: HashMap::new(),
+        }
+    }
+
+    fn assign(&mut self, src_files: &[String]) -> std::io::Result<()> {
+        // Give the files the next numbers in order, saving the counter before they are used
+        let last = self.last + src_files.len() as u64;
+        let partial_path = PathBuf::from(format!("{}.partial", self.path.display()));
+        fs::write(&partial_path, format!("{last}\n"))?;
+        fs::rename(&partial_path, &self.path)?;
+        let first = self.last + 1;
+        self.files.extend(src_files.iter().cloned().zip(first..=last));
+        self.last = last;
+        Ok(())
+    }
+}
+
+pub fn init(path: Option<&str>) {
+    let Some(path) = path else {
+        return;
+    };
+    let counter = Counter::load(path);
+    info!("Numbering uploaded files from {}, saved in {}", counter.last + 1, path);
+    let _ = COUNTER.set(Mutex::new(counter));
+}
+
+pub fn assign(src_files: &[String]) -> std::io::Result<()> {
+    match COUNTER.get() {
+        Some(counter) => counter.lock().unwrap().assign(src_files),
+        None => Ok(()),
+    }
+}
+
+pub fn of(src_file: &str) -> Option<u64> {
+    COUNTER.get()?.lock().unwrap().files.get(src_file).copied()
+}
+
+pub fn forget(src_file: &str) {
+    // Drop the number of a file that was uploaded or given up on
+    if let Some(counter) = COUNTER.get() {
+        counter.lock().unwrap().files.remove(src_file);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_batches_in_order_and_resumes_from_the_file() {
+        let dir = std::env::temp_dir().join(format!("rsync_csv_test_{}_sequence", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sequence");
+        let path = path.to_str().unwrap();
+        let files = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<String>>();
+        // Without a file, numbering starts at 1
+        let mut counter = Counter::load(path);
+        counter.assign(&files(&["a", "b"])).unwrap();
+        assert_eq!((counter.files["a"], counter.files["b"]), (1, 2));
+        fs::write(path, "41\n").unwrap();
+        let mut counter = Counter::load(path);
+        counter.assign(&files(&["c", "d", "e"])).unwrap();
+        assert_eq!([counter.files["c"], counter.files["d"], counter.files["e"]], [42, 43, 44]);
+        counter.assign(&files(&["f"])).unwrap();
+        assert_eq!(counter.files["f"], 45);
+        // The counter is saved through a partial file renamed into place
+        assert_eq!(fs::read_to_string(path).unwrap(), "45\n");
+        assert!(!dir.join("sequence.partial").exists());
+        assert_eq!(Counter::load(path).last, 45);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,227 @@
+// Native SFTP transport with a pool of reusable SSH sessions per destination.
+// Without the "sftp" cargo feature, uploads fail with an explanatory error.
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+
+// Writes the contents of a remote file to it
+pub type WriteContents<'a> = Box<dyn FnMut(&mut dyn Write) -> io::Result<()> + 'a>;
+
+// A remote file whose contents are produced while it is uploaded, instead of being read from a local file
+#[cfg_attr(not(feature = "sftp"), allow(dead_code))]
+pub struct Stream<'a> {
+    pub remote_name: String,
+    pub write: WriteContents<'a>,
+}
+
+pub fn file_stream(file: &String) -> Stream<'_> {
+    // Upload a local file under its own name
+    Stream {
+        remote_name: Path::new(file.as_str()).file_name().unwrap().to_string_lossy().to_string(),
+        write: Box::new(move |remote_file| io::copy(&mut File::open(file)?, remote_file).map(|_| ())),
+    }
+}
+
+#[cfg(feature = "sftp")]
+mod pool {
+    use super::{file_stream, Stream};
+    use crate::config::{AppConfig, DestAuth};
+    use log::{debug, error, info};
+    use ssh2::{RenameFlags, Session, Sftp};
+    use std::{
+        collections::HashMap,
+        fs,
+        io::{self, Write},
+        net::TcpStream,
+        path::Path,
+        sync::{Mutex, OnceLock},
+        time::{Duration, Instant},
+    };
+
+    struct PooledSession {
+        session: Session,
+        sftp: Sftp,
+        last_used: Instant,
+    }
+
+    struct Pool {
+        size: usize,
+        idle_timeout: Duration,
+        // Idle sessions keyed by user@host:port
+        idle: Mutex<HashMap<String, Vec<PooledSession>>>,
+    }
+
+    static POOL: OnceLock<Pool> = OnceLock::new();
+
+    pub fn init(cfg: &AppConfig) {
+        let _ = POOL.set(Pool {
+            size: cfg.sftp.pool_size,
+            idle_timeout: Duration::from_secs(cfg.sftp.idle_timeout_seconds),
+            idle: Mutex::new(HashMap::new()),
+        });
+        info!(
+            "Using SFTP transport with up to {} idle session(s) per destination",
+            cfg.sftp.pool_size
+        );
+    }
+
+    pub fn shutdown() {
+        if let Some(pool) = POOL.get() {
+            for (destination, sessions) in pool.idle.lock().unwrap().drain() {
+                for pooled in sessions {
+                    close_session(&destination, pooled);
+                }
+            }
+        }
+    }
+
+    pub fn upload(cfg: &AppConfig, files: &[&String], remote_dir: &str) -> Result<(), String> {
+        upload_streams(cfg, files.iter().map(|file| file_stream(file)).collect(), remote_dir)
+    }
+
+    pub fn upload_streams(cfg: &AppConfig, streams: Vec<Stream>, remote_dir: &str) -> Result<(), String> {
+        // Upload over a pooled session. A session that errors is discarded instead of returned to the pool.
+        let Some(pool) = POOL.get() else {
+            return Err("SFTP transport is not initialised".to_string());
+        };
+        let destination = format!("{}@{}:{}", cfg.dest_user, cfg.dest_host, cfg.sftp.port);
+        let pooled = match checkout(pool, &destination) {
+            Some(pooled) => pooled,
+            None => connect(cfg).map_err(|e| format!("Failed to open SFTP session to {destination}: {e}"))?,
+        };
+        match upload_files(&pooled.sftp, streams, remote_dir) {
+            Ok(_) => {
+                checkin(pool, &destination, pooled);
+                Ok(())
+            }
+            Err(e) => {
+                close_session(&destination, pooled);
+                Err(format!("SFTP upload to {destination} failed: {e}"))
+            }
+        }
+    }
+
+    fn checkout(pool: &Pool, destination: &str) -> Option<PooledSession> {
+        // Take the most recently used idle session, closing any that have been idle too long
+        let mut idle = pool.idle.lock().unwrap();
+        let sessions = idle.get_mut(destination)?;
+        while let Some(pooled) = sessions.pop() {
+            if pooled.last_used.elapsed() < pool.idle_timeout {
+                debug!("Reusing SFTP session to {}", destination);
+                return Some(pooled);
+            }
+            close_session(destination, pooled);
+        }
+        None
+    }
+
+    fn checkin(pool: &Pool, destination: &str, mut pooled: PooledSession) {
+        let mut idle = pool.idle.lock().unwrap();
+        let sessions = idle.entry(destination.to_string()).or_default();
+        if sessions.len() < pool.size {
+            pooled.last_used = Instant::now();
+            sessions.push(pooled);
+        } else {
+            close_session(destination, pooled);
+        }
+    }
+
+    fn connect(cfg: &AppConfig) -> io::Result<PooledSession> {
+        info!("Opening SFTP session to {}@{}:{}", cfg.dest_user, cfg.dest_host, cfg.sftp.port);
+        let tcp = TcpStream::connect((cfg.dest_host.as_str(), cfg.sftp.port))?;
+        let mut session = Session::new()?;
+        session.set_tcp_stream(tcp);
+        // Same limit as rsync's --timeout, at its RSYNC_TIMEOUT_SECONDS default
+        session.set_timeout(10_000);
+        session.handshake()?;
+        match (&cfg.dest_auth, &cfg.dest_identity_file, &cfg.dest_password_file) {
+            (DestAuth::Key, Some(identity_file), _) => {
+                session.userauth_pubkey_file(&cfg.dest_user, None, Path::new(identity_file), None)?
+            }
+            (DestAuth::Password, _, Some(password_file)) => {
+                let password = fs::read_to_string(password_file)?;
+                session.userauth_password(&cfg.dest_user, password.trim_end_matches(['\r', '\n']))?
+            }
+            _ => match &cfg.sftp.identity_file {
+                Some(identity_file) => session.userauth_pubkey_file(&cfg.dest_user, None, Path::new(identity_file), None)?,
+                None => session.userauth_agent(&cfg.dest_user)?,
+            },
+        }
+        let sftp = session.sftp()?;
+        Ok(PooledSession { session, sftp, last_used: Instant::now() })
+    }
+
+    fn close_session(destination: &str, pooled: PooledSession) {
+        debug!("Closing SFTP session to {}", destination);
+        drop(pooled.sftp);
+        if let Err(e) = pooled.session.disconnect(None, "closing", None) {
+            error!("Failed to close SFTP session to {}. Error: {}", destination, e);
+        }
+    }
+
+    fn upload_files(sftp: &Sftp, streams: Vec<Stream>, remote_dir: &str) -> io::Result<()> {
+        create_remote_dir(sftp, remote_dir)?;
+        for mut stream in streams {
+            let partial_path = format!("{remote_dir}/.{}.partial", stream.remote_name);
+            let final_path = format!("{remote_dir}/{}", stream.remote_name);
+            let mut remote_file = sftp.create(Path::new(&partial_path))?;
+            let written = (stream.write)(&mut remote_file).and_then(|_| remote_file.flush());
+            drop(remote_file);
+            // Rename into place so readers never see a partial file
+            let uploaded = written.and_then(|_| {
+                sftp.rename(
+                    Path::new(&partial_path),
+                    Path::new(&final_path),
+                    Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE),
+                )
+                .map_err(io::Error::from)
+            });
+            if let Err(e) = uploaded {
+                // Don't leave the partial file behind. The session may already be unusable, so this is best effort.
+                if let Err(unlink_error) = sftp.unlink(Path::new(&partial_path)) {
+                    debug!("Failed to remove partial remote file {}. Error: {}", partial_path, unlink_error);
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn create_remote_dir(sftp: &Sftp, remote_dir: &str) -> io::Result<()> {
+        // Equivalent of mkdir -p on the remote host
+        let mut path = if remote_dir.starts_with('/') { "/".to_string() } else { String::new() };
+        for component in remote_dir.split('/').filter(|component| !component.is_empty()) {
+            path.push_str(component);
+            if sftp.stat(Path::new(&path)).is_err() {
+                sftp.mkdir(Path::new(&path), 0o755)?;
+            }
+            path.push('/');
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "sftp"))]
+mod pool {
+    use super::Stream;
+    use crate::config::AppConfig;
+    use log::error;
+
+    pub fn init(_cfg: &AppConfig) {
+        error!("TRANSPORT is sftp but rsync_csv was built without the sftp feature, uploads will fail");
+    }
+
+    pub fn shutdown() {}
+
+    pub fn upload(_cfg: &AppConfig, _files: &[&String], _remote_dir: &str) -> Result<(), String> {
+        Err("rsync_csv was built without the sftp feature".to_string())
+    }
+
+    pub fn upload_streams(_cfg: &AppConfig, _streams: Vec<Stream>, _remote_dir: &str) -> Result<(), String> {
+        Err("rsync_csv was built without the sftp feature".to_string())
+    }
+}
+
+pub use pool::*;
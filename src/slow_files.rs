@@ -0,0 +1,88 @@
+// Slow file warnings. Each processing attempt of a file is timed from matching until it is uploaded, fails or is
+// dropped, and a warning is logged when it takes longer than SLOW_FILE_WARNING_SECONDS. Processing is never aborted.
+// Without SLOW_FILE_WARNING_SECONDS, every function here is a no-op.
+use log::warn;
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+struct Attempt {
+    started: Instant,
+    // Whether the watchdog already warned about this attempt while it was still running
+    warned: bool,
+}
+
+struct SlowFiles {
+    threshold: Duration,
+    attempts: Mutex<HashMap<String, Attempt>>,
+}
+
+static SLOW_FILES: OnceLock<SlowFiles> = OnceLock::new();
+
+pub fn init(threshold_seconds: Option<u64>) {
+    let Some(threshold_seconds) = threshold_seconds else {
+        return;
+    };
+    let _ = SLOW_FILES.set(SlowFiles {
+        threshold: Duration::from_secs(threshold_seconds),
+        attempts: Mutex::new(HashMap::new()),
+    });
+    // Processing is single threaded, so a file that hangs can only be reported from another thread
+    thread::spawn(|| loop {
+        thread::sleep(Duration::from_secs(1));
+        let slow_files = SLOW_FILES.get().unwrap();
+        for (path, attempt) in slow_files.attempts.lock().unwrap().iter_mut() {
+            if !attempt.warned && attempt.started.elapsed() > slow_files.threshold {
+                warn!(
+                    "Slow file: {} still processing after {} second(s)",
+                    path,
+                    attempt.started.elapsed().as_secs()
+                );
+                attempt.warned = true;
+            }
+        }
+    });
+}
+
+pub fn start(path: &str) {
+    if let Some(slow_files) = SLOW_FILES.get() {
+        slow_files.attempts.lock().unwrap().insert(
+            path.to_string(),
+            Attempt {
+                started: Instant::now(),
+                warned: false,
+            },
+        );
+    }
+}
+
+pub fn rename(old_path: &str, new_path: &str) {
+    if let Some(slow_files) = SLOW_FILES.get() {
+        let mut attempts = slow_files.attempts.lock().unwrap();
+        if let Some(attempt) = attempts.remove(old_path) {
+            attempts.insert(new_path.to_string(), attempt);
+        }
+    }
+}
+
+pub fn finish(path: &str, outcome: &str) {
+    let Some(slow_files) = SLOW_FILES.get() else {
+        return;
+    };
+    let Some(attempt) = slow_files.attempts.lock().unwrap().remove(path) else {
+        return;
+    };
+    let elapsed = attempt.started.elapsed();
+    if elapsed > slow_files.threshold {
+        warn!(
+            "Slow file: {} took {:.1} second(s) to process, over the {} second threshold. Outcome: {}",
+            path,
+            elapsed.as_secs_f64(),
+            slow_files.threshold.as_secs(),
+            outcome
+        );
+    }
+}
@@ -0,0 +1,84 @@
+// Free space check of the source filesystem, for early warning before it fills and the exporters writing to it fail,
+// e.g. when files arrive faster than they are transferred and deleted. Runs in its own thread: logged when the free
+// space drops below SOURCE_FREE_SPACE_MIN_MB and when it recovers, and written to SOURCE_FREE_SPACE_STATUS_PATH
+// after every check.
+// Without SOURCE_FREE_SPACE_MIN_MB, no check is started.
+use log::{debug, error, info, warn};
+use std::{fs, process::Command, thread, time::Duration};
+
+pub fn start(src_dir: &str, min_free_mb: Option<u64>, interval_seconds: u64, status_path: Option<String>) {
+    let Some(min_free_mb) = min_free_mb else {
+        return;
+    };
+    info!(
+        "Checking free space of source directory {} every {} second(s), warning below {} MB",
+        src_dir, interval_seconds, min_free_mb
+    );
+    let src_dir = src_dir.to_string();
+    thread::spawn(move || {
+        let mut low: Option<bool> = None;
+        loop {
+            let result = free_mb(&src_dir);
+            match &result {
+                Ok(free_mb) if *free_mb < min_free_mb => {
+                    if low != Some(true) {
+                        warn!(
+                            "Source directory {} is low on free space: {} MB left, below SOURCE_FREE_SPACE_MIN_MB {}",
+                            src_dir, free_mb, min_free_mb
+                        );
+                    } else {
+                        debug!("Source directory {} is still low on free space: {} MB left", src_dir, free_mb);
+                    }
+                    low = Some(true);
+                }
+                Ok(free_mb) => {
+                    if low == Some(true) {
+                        info!("Source directory {} has {} MB free again", src_dir, free_mb);
+                    } else if low.is_none() {
+                        info!("Source directory {} has {} MB free", src_dir, free_mb);
+                    }
+                    low = Some(false);
+                }
+                Err(e) => error!("Failed to check free space of source directory {}. Error: {}", src_dir, e),
+            }
+            if let Some(status_path) = &status_path {
+                let status = serde_json::json!({
+                    "source_dir": src_dir,
+                    "free_mb": result.as_ref().ok(),
+                    "min_free_mb": min_free_mb,
+                    "low": result.as_ref().ok().map(|free_mb| *free_mb < min_free_mb),
+                    "error": result.as_ref().err(),
+                    "checked_at": chrono::Local::now().to_rfc3339(),
+                    "run_id": crate::logging::run_id(),
+                });
+                if let Err(e) = write_status(status_path, &status) {
+                    error!("Failed to write source free space status {}. Error: {}", status_path, e);
+                }
+            }
+            thread::sleep(Duration::from_secs(interval_seconds));
+        }
+    });
+}
+
+fn free_mb(dir: &str) -> Result<u64, String> {
+    // Space available to unprivileged users, from the POSIX output of df: the 4th field of its second line, in KB
+    let output = Command::new("df").args(["-Pk", dir]).output().map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout
+        .lines()
+        .nth(1)
+        .and_then(|line| line.split_whitespace().nth(3))
+        .and_then(|available_kb| available_kb.parse::<u64>().ok())
+        .map(|available_kb| available_kb / 1024)
+        .ok_or_else(|| format!("unexpected df output {:?}", stdout.trim()))
+}
+
+fn write_status(status_path: &str, status: &serde_json::Value) -> std::io::Result<()> {
+    // Rename into place, so monitoring never reads a partial file
+    let partial_path = format!("{status_path}.partial");
+    fs::write(&partial_path, serde_json::to_string_pretty(status)?)?;
+    fs::rename(&partial_path, status_path)
+}
@@ -0,0 +1,138 @@
+// Optional OpenTelemetry tracing of each file's journey (match -> rsync -> delete).
+// Without the "otel" cargo feature, or without an endpoint configured, every function here is a no-op.
+
+#[cfg(feature = "otel")]
+mod otel {
+    use log::{error, info};
+    use opentelemetry::{
+        global::{self, BoxedSpan},
+        trace::{Span, TraceContextExt, Tracer},
+        Context, KeyValue,
+    };
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use std::{
+        collections::HashMap,
+        sync::{Mutex, OnceLock},
+    };
+
+    static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+    // Root span context of every file currently being processed, keyed by file path
+    static FILE_SPANS: OnceLock<Mutex<HashMap<String, Context>>> = OnceLock::new();
+
+    pub struct Stage(Option<BoxedSpan>);
+
+    impl Stage {
+        pub fn end(mut self, outcome: &str) {
+            if let Some(span) = self.0.as_mut() {
+                span.set_attribute(KeyValue::new("outcome", outcome.to_string()));
+                span.end();
+            }
+        }
+    }
+
+    pub fn init(endpoint: Option<&str>) {
+        let Some(endpoint) = endpoint else {
+            return;
+        };
+        match opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+        {
+            Ok(exporter) => {
+                let provider = SdkTracerProvider::builder().with_batch_exporter(exporter).build();
+                global::set_tracer_provider(provider.clone());
+                let _ = PROVIDER.set(provider);
+                let _ = FILE_SPANS.set(Mutex::new(HashMap::new()));
+                info!("Exporting OpenTelemetry traces to {}", endpoint);
+            }
+            Err(e) => error!("Failed to create OTLP exporter, tracing disabled. Error: {}", e),
+        }
+    }
+
+    pub fn shutdown() {
+        if let Some(provider) = PROVIDER.get() {
+            if let Err(e) = provider.shutdown() {
+                error!("Failed to shut down OpenTelemetry tracer provider. Error: {}", e);
+            }
+        }
+    }
+
+    pub fn start_file(path: &str) {
+        let Some(file_spans) = FILE_SPANS.get() else {
+            return;
+        };
+        let mut span = global::tracer("rsync_csv").start("process_file");
+        span.set_attribute(KeyValue::new("file.path", path.to_string()));
+        if let Ok(metadata) = std::fs::metadata(path) {
+            span.set_attribute(KeyValue::new("file.size", metadata.len() as i64));
+        }
+        file_spans
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), Context::new().with_span(span));
+    }
+
+    pub fn rename_file(old_path: &str, new_path: &str) {
+        if let Some(file_spans) = FILE_SPANS.get() {
+            let mut file_spans = file_spans.lock().unwrap();
+            if let Some(cx) = file_spans.remove(old_path) {
+                file_spans.insert(new_path.to_string(), cx);
+            }
+        }
+    }
+
+    pub fn set_table(path: &str, table_name: &str) {
+        if let Some(cx) = FILE_SPANS.get().and_then(|spans| spans.lock().unwrap().get(path).cloned()) {
+            cx.span().set_attribute(KeyValue::new("table", table_name.to_string()));
+        }
+    }
+
+    pub fn start_stage(path: &str, name: &'static str) -> Stage {
+        match FILE_SPANS.get().and_then(|spans| spans.lock().unwrap().get(path).cloned()) {
+            Some(cx) => Stage(Some(global::tracer("rsync_csv").start_with_context(name, &cx))),
+            None => Stage(None),
+        }
+    }
+
+    pub fn finish_file(path: &str, outcome: &str) {
+        if let Some(cx) = FILE_SPANS.get().and_then(|spans| spans.lock().unwrap().remove(path)) {
+            cx.span().set_attribute(KeyValue::new("outcome", outcome.to_string()));
+            cx.span().end();
+        }
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+mod otel {
+    use log::warn;
+
+    pub struct Stage;
+
+    impl Stage {
+        pub fn end(self, _outcome: &str) {}
+    }
+
+    pub fn init(endpoint: Option<&str>) {
+        if endpoint.is_some() {
+            warn!("OTEL_EXPORTER_OTLP_ENDPOINT is set but rsync_csv was built without the otel feature");
+        }
+    }
+
+    pub fn shutdown() {}
+
+    pub fn start_file(_path: &str) {}
+
+    pub fn rename_file(_old_path: &str, _new_path: &str) {}
+
+    pub fn set_table(_path: &str, _table_name: &str) {}
+
+    pub fn start_stage(_path: &str, _name: &'static str) -> Stage {
+        Stage
+    }
+
+    pub fn finish_file(_path: &str, _outcome: &str) {}
+}
+
+pub use otel::*;
@@ -0,0 +1,703 @@
+use crate::config::{HeaderEncoding, ShadowingPolicy, UnicodeForm};
+use encoding_rs::{CoderResult, Decoder, Encoding, UTF_8, WINDOWS_1252};
+use log::{debug, error, info, warn};
+use regex::Regex;
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use unicode_normalization::UnicodeNormalization;
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{Read, Seek},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{mpsc, Arc, RwLock},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+// Reloads retried while templates are in flux, before their previous mappings are kept until the next change
+const MAX_IN_FLUX_RETRIES: u32 = 10;
+
+#[derive(Debug, Clone)]
+pub enum ColumnType {
+    String,
+    Int,
+    Float,
+    Bool,
+    Date,
+    DateTime,
+}
+
+impl FromStr for ColumnType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "string" => Ok(ColumnType::String),
+            "int" => Ok(ColumnType::Int),
+            "float" => Ok(ColumnType::Float),
+            "bool" => Ok(ColumnType::Bool),
+            "date" => Ok(ColumnType::Date),
+            "datetime" => Ok(ColumnType::DateTime),
+            _ => Err(format!("unknown column type {:?}", s)),
+        }
+    }
+}
+
+impl ColumnType {
+    pub fn accepts(&self, value: &str) -> bool {
+        // Empty values are accepted for every type
+        let value = value.trim();
+        if value.is_empty() {
+            return true;
+        }
+        match self {
+            ColumnType::String => true,
+            ColumnType::Int => value.parse::<i64>().is_ok(),
+            ColumnType::Float => value.parse::<f64>().is_ok(),
+            ColumnType::Bool => matches!(value.to_lowercase().as_str(), "true" | "false" | "0" | "1"),
+            ColumnType::Date => NaiveDate::parse_from_str(value, "%Y-%m-%d").is_ok(),
+            ColumnType::DateTime => {
+                DateTime::parse_from_rfc3339(value).is_ok()
+                    || NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").is_ok()
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TableTemplate {
+    pub headers: String,
+    // Column types from the optional second template line, checked against sampled data rows
+    pub column_types: Option<Vec<ColumnType>>,
+    // Template file the table was loaded from
+    pub path: PathBuf,
+    // Directory of the template relative to its template directory, empty for top level templates
+    pub rel_dir: PathBuf,
+}
+
+#[derive(Default)]
+pub struct Templates {
+    // Template column headers mapped to table name
+    pub headers: HashMap<String, String>,
+    // Table name mapped to its template
+    pub tables: HashMap<String, TableTemplate>,
+    // Incremented every time the templates are reloaded
+    pub generation: u64,
+    // Template subdirectories of tables whose templates a reload removed, so files matched before still go where
+    // they were matched to
+    pub retired_dirs: HashMap<String, PathBuf>,
+    // Template files skipped because they looked mid-write, with their table name
+    pub in_flux: Vec<(PathBuf, String)>,
+}
+
+pub type SharedTemplates = Arc<RwLock<Templates>>;
+
+// Bytes from the start of a csv file its encoding is detected from, with HEADER_ENCODING auto
+const ENCODING_DETECT_BYTES: u64 = 1 << 20;
+
+pub struct DecodedLines {
+    file: File,
+    decoder: Decoder,
+    buffer: Vec<u8>,
+    // Decoded text not yet returned as lines
+    decoded: String,
+    done: bool,
+}
+
+// How template directories are read
+#[derive(Clone)]
+pub struct LoadOptions {
+    // Descend into subdirectories of the template directories
+    pub recursive: bool,
+    // Matched against template file stems, capture group 1 is the table name
+    pub name_regex: Regex,
+    // Matches names made only of allowed characters
+    pub name_chars: Option<Regex>,
+    // Stripped from both ends of every header field
+    pub wrapper_chars: Vec<char>,
+    // Unicode normalization form headers are converted to
+    pub unicode_form: UnicodeForm,
+    // Template files are read on up to this many threads
+    pub threads: usize,
+    // Template files whose size or modification time changes over this interval, or whose header is empty, are
+    // skipped as in flux. Only set for reloads.
+    pub stability_check: Option<Duration>,
+    // Encoding template files are decoded from
+    pub encoding: HeaderEncoding,
+    // What to do with reloaded templates that shadow each other, only set with HEADER_MATCH_MODE prefix
+    pub shadowing: Option<ShadowingPolicy>,
+}
+
+pub fn load_headers(template_dirs: &[String], options: &LoadOptions) -> std::io::Result<Templates> {
+    // Load headers from template csv files of every template directory and store in hashmap.
+    // Directories are merged in order, so later directories override earlier ones on table name collision.
+    let mut templates = Templates::default();
+    for template_dir in template_dirs {
+        load_template_dir(template_dir, options, &mut templates)?;
+    }
+    Ok(templates)
+}
+
+fn load_template_dir(template_dir: &str, options: &LoadOptions, templates: &mut Templates) -> std::io::Result<()> {
+    let mut template_paths: Vec<PathBuf> = Vec::new();
+    collect_template_paths(Path::new(template_dir), options.recursive, &mut template_paths)?;
+    // Sort so that collisions within a directory resolve the same way on every load
+    template_paths.sort();
+    let mut table_templates: Vec<(PathBuf, String)> = Vec::new();
+    for template_path in template_paths {
+        match template_path.clone().file_stem() {
+            Some(fname) => match &fname.to_str() {
+                Some(v) => match options.name_regex.captures(v).and_then(|caps| caps.get(1)) {
+                    Some(table_name) => table_templates.push((template_path, table_name.as_str().to_string())),
+                    None => warn!(
+                        "Skipping template file whose name doesn't match {:?}: {:?}",
+                        options.name_regex.as_str(),
+                        template_path
+                    ),
+                },
+                None => info!("Invalid File Name"),
+            },
+            None => error!("No File Name"),
+        }
+    }
+    let paths: Vec<&PathBuf> = table_templates.iter().map(|(template_path, _)| template_path).collect();
+    let states_before: Vec<Option<(u64, SystemTime)>> = match options.stability_check {
+        Some(_) => paths.iter().map(|path| file_state(path)).collect(),
+        None => Vec::new(),
+    };
+    let contents = read_template_files(&paths, options.threads, &options.encoding);
+    // A template whose size or mtime moved while it was read, or right after, is still being written
+    let states_after: Vec<Option<(u64, SystemTime)>> = match options.stability_check {
+        Some(interval) if !paths.is_empty() => {
+            thread::sleep(interval);
+            paths.iter().map(|path| file_state(path)).collect()
+        }
+        _ => Vec::new(),
+    };
+    // Merge in path order, so the result is the same however the reads were spread across threads
+    let mut read_errors: Vec<std::io::Error> = Vec::new();
+    for (i, ((template_path, table_name), contents)) in table_templates.into_iter().zip(contents).enumerate() {
+        let in_flux = options.stability_check.is_some()
+            && (states_before[i].is_none() || states_before[i] != states_after[i] || contents
+                .as_ref()
+                .is_ok_and(|contents| contents.trim().lines().next().unwrap_or_default().trim().is_empty()));
+        if in_flux {
+            templates.in_flux.push((template_path, table_name));
+            continue;
+        }
+        let contents = match contents {
+            Ok(contents) => contents,
+            Err(e) => {
+                error!("Failed to read template {:?}. Error: {}", template_path, e);
+                read_errors.push(e);
+                continue;
+            }
+        };
+        let mut lines = contents.trim().lines();
+        let headers = clean_headers(
+            lines.next().unwrap_or_default().trim(),
+            &options.wrapper_chars,
+            &options.unicode_form,
+        );
+        if let Some(reason) = options
+            .name_chars
+            .as_ref()
+            .and_then(|name_chars| invalid_name(name_chars, &table_name, &headers))
+        {
+            error!("Rejecting template {:?}: {}", template_path, reason);
+            continue;
+        }
+        let column_types = lines.next().and_then(|line| parse_column_types(&template_path, &headers, line));
+        let rel_dir = template_path
+            .parent()
+            .and_then(|parent| parent.strip_prefix(template_dir).ok())
+            .unwrap_or(Path::new(""))
+            .to_path_buf();
+        insert_template(
+            templates,
+            &table_name,
+            TableTemplate { headers, column_types, path: template_path, rel_dir },
+        );
+    }
+    // Every failed read has been logged, the first one fails the load
+    match read_errors.into_iter().next() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+fn file_state(path: &Path) -> Option<(u64, SystemTime)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}
+
+fn read_template_files(paths: &[&PathBuf], threads: usize, encoding: &HeaderEncoding) -> Vec<std::io::Result<String>> {
+    // Read and decode files on up to `threads` threads, returning the contents in the order of paths
+    let chunk_size = paths.len().div_ceil(threads.max(1)).max(1);
+    let read_template = |path: &&PathBuf| {
+        fs::read(path).map(|bytes| {
+            let (contents, used) = decode_headers(&bytes, encoding);
+            debug!("Decoded template {:?} as {}", path, used.name());
+            contents
+        })
+    };
+    thread::scope(|scope| {
+        let handles: Vec<_> = paths
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter().map(read_template).collect::<Vec<_>>()))
+            .collect();
+        handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+    })
+}
+
+pub fn decode_headers(bytes: &[u8], encoding: &HeaderEncoding) -> (String, &'static Encoding) {
+    // Decode the start of a csv or template file, so headers from files in different encodings compare equal.
+    // A BOM always wins and is removed.
+    let (contents, used, _) = detected_encoding(bytes, encoding).decode(bytes);
+    (contents.into_owned(), used)
+}
+
+pub fn decoded_lines(path: &str, encoding: &HeaderEncoding) -> std::io::Result<DecodedLines> {
+    // Lines of a csv file decoded the way decode_headers decodes its start, so line numbers of the header and of the
+    // rows after it agree whatever the encoding
+    let mut file = File::open(path)?;
+    let mut prefix = Vec::new();
+    (&file).take(ENCODING_DETECT_BYTES).read_to_end(&mut prefix)?;
+    file.rewind()?;
+    Ok(DecodedLines {
+        file,
+        decoder: detected_encoding(&prefix, encoding).new_decoder(),
+        buffer: vec![0u8; 64 * 1024],
+        decoded: String::new(),
+        done: false,
+    })
+}
+
+pub fn file_encoding(path: &str, encoding: &HeaderEncoding) -> std::io::Result<&'static Encoding> {
+    // Encoding decoded_lines reads a file in, where a BOM wins over HEADER_ENCODING as it does for the decoder
+    let mut prefix = Vec::new();
+    File::open(path)?.take(ENCODING_DETECT_BYTES).read_to_end(&mut prefix)?;
+    Ok(match Encoding::for_bom(&prefix) {
+        Some((encoding, _)) => encoding,
+        None => detected_encoding(&prefix, encoding),
+    })
+}
+
+fn detected_encoding(bytes: &[u8], encoding: &HeaderEncoding) -> &'static Encoding {
+    // Bytes cut off at the end of a partial read don't count against UTF-8
+    match encoding {
+        HeaderEncoding::Label(encoding) => encoding,
+        HeaderEncoding::Auto => match Encoding::for_bom(bytes) {
+            Some((encoding, _)) => encoding,
+            None => match std::str::from_utf8(bytes) {
+                Ok(_) => UTF_8,
+                Err(e) if e.error_len().is_none() => UTF_8,
+                Err(_) => WINDOWS_1252,
+            },
+        },
+    }
+}
+
+impl DecodedLines {
+    pub fn encoding(&self) -> &'static Encoding {
+        // Known once the decoder has seen any BOM, i.e. after the first line was read
+        self.decoder.encoding()
+    }
+}
+
+impl Iterator for DecodedLines {
+    type Item = std::io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Like BufRead::lines, without the line break and a carriage return before it
+        loop {
+            let line_end = self.decoded.find('\n');
+            if line_end.is_some() || (self.done && !self.decoded.is_empty()) {
+                let mut line: String = match line_end {
+                    Some(line_end) => self.decoded.drain(..=line_end).collect(),
+                    None => std::mem::take(&mut self.decoded),
+                };
+                if line.ends_with('\n') {
+                    line.pop();
+                }
+                if line.ends_with('\r') {
+                    line.pop();
+                }
+                return Some(Ok(line));
+            }
+            if self.done {
+                return None;
+            }
+            let read = match self.file.read(&mut self.buffer) {
+                Ok(read) => read,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+            self.done = read == 0;
+            let mut input = &self.buffer[..read];
+            loop {
+                let needed = self.decoder.max_utf8_buffer_length(input.len()).unwrap_or(input.len() * 3 + 16);
+                self.decoded.reserve(needed);
+                let (result, consumed, _) = self.decoder.decode_to_string(input, &mut self.decoded, self.done);
+                input = &input[consumed..];
+                if result == CoderResult::InputEmpty {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+pub fn clean_headers(headers: &str, wrapper_chars: &[char], unicode_form: &UnicodeForm) -> String {
+    // Bring csv and template headers into the same shape before they are compared
+    let headers = strip_wrappers(headers, wrapper_chars);
+    match unicode_form {
+        UnicodeForm::Nfc => headers.nfc().collect(),
+        UnicodeForm::Nfd => headers.nfd().collect(),
+        UnicodeForm::Off => headers,
+    }
+}
+
+fn strip_wrappers(headers: &str, wrapper_chars: &[char]) -> String {
+    // Strip quote and bracket characters from both ends of every comma separated header field
+    if wrapper_chars.is_empty() {
+        return headers.to_string();
+    }
+    headers
+        .split(',')
+        .map(|field| field.trim_matches(wrapper_chars))
+        .collect::<Vec<&str>>()
+        .join(",")
+}
+
+pub fn dump_templates(templates: &Templates, manifest_path: &str) -> std::io::Result<()> {
+    // Write the loaded table to header mappings as json, sorted by table name for clean diffs
+    let mut table_names: Vec<&String> = templates.tables.keys().collect();
+    table_names.sort();
+    let manifest: Vec<serde_json::Value> = table_names
+        .into_iter()
+        .map(|table_name| {
+            let template = &templates.tables[table_name];
+            serde_json::json!({
+                "table": table_name,
+                "headers": template.headers.split(',').collect::<Vec<&str>>(),
+                "column_types": template.column_types.as_ref().map(|column_types| {
+                    column_types
+                        .iter()
+                        .map(|column_type| format!("{:?}", column_type).to_lowercase())
+                        .collect::<Vec<String>>()
+                }),
+                "template": template.path,
+            })
+        })
+        .collect();
+    fs::write(manifest_path, serde_json::to_string_pretty(&manifest)? + "\n")?;
+    info!("Wrote {} template mapping(s) to {}", manifest.len(), manifest_path);
+    Ok(())
+}
+
+pub fn warn_shadowing(templates: &Templates) -> usize {
+    // With prefix matching, a template whose headers lead another template's shadows it for every file that has the
+    // shorter header plus other columns. Log each such pair, returning how many there are.
+    let mut pairs: Vec<(&String, &String, &String, &String)> = Vec::new();
+    for (headers, table_name) in &templates.headers {
+        for (longer_headers, longer_table_name) in &templates.headers {
+            let extends = longer_headers
+                .strip_prefix(headers.as_str())
+                .is_some_and(|rest| rest.starts_with(','));
+            if extends {
+                pairs.push((table_name, headers, longer_table_name, longer_headers));
+            }
+        }
+    }
+    pairs.sort();
+    for (table_name, headers, longer_table_name, longer_headers) in &pairs {
+        warn!(
+            "Template {:?} ({}) is a prefix of template {:?} ({}): files starting with {} match {:?}, \
+             other files starting with {} match {:?}",
+            table_name, headers, longer_table_name, longer_headers, longer_headers, longer_table_name, headers, table_name
+        );
+    }
+    pairs.len()
+}
+
+fn invalid_name(name_chars: &Regex, table_name: &str, headers: &str) -> Option<String> {
+    // Check the table name and every header field against the allowed characters
+    if !name_chars.is_match(table_name) {
+        return Some(format!("table name {:?} has characters not allowed by TEMPLATE_NAME_CHARS", table_name));
+    }
+    headers
+        .split(',')
+        .find(|field| !name_chars.is_match(field))
+        .map(|field| format!("header field {:?} has characters not allowed by TEMPLATE_NAME_CHARS", field))
+}
+
+fn parse_column_types(template_path: &Path, headers: &str, line: &str) -> Option<Vec<ColumnType>> {
+    // Parse the type annotation line of a template, ignoring it if it doesn't fit the headers
+    let column_types: Result<Vec<ColumnType>, String> = line.trim().split(',').map(ColumnType::from_str).collect();
+    match column_types {
+        Ok(column_types) if column_types.len() == headers.split(',').count() => Some(column_types),
+        Ok(column_types) => {
+            error!(
+                "Template {:?} has {} column types for {} headers, ignoring its schema",
+                template_path,
+                column_types.len(),
+                headers.split(',').count()
+            );
+            None
+        }
+        Err(e) => {
+            error!("Template {:?} has an invalid schema line, ignoring its schema. Error: {}", template_path, e);
+            None
+        }
+    }
+}
+
+fn insert_template(templates: &mut Templates, table_name: &str, template: TableTemplate) {
+    // Add a template, replacing any earlier template for the same table or with the same headers
+    if let Some(previous) = templates.tables.remove(table_name) {
+        info!(
+            "Template {:?} overrides {:?} for table {:?}",
+            template.path, previous.path, table_name
+        );
+        templates.headers.remove(&previous.headers);
+    }
+    if let Some(previous_table) = templates
+        .headers
+        .insert(template.headers.clone(), table_name.to_string())
+    {
+        info!(
+            "Template {:?} has the same headers as table {:?}, headers now match table {:?}",
+            template.path, previous_table, table_name
+        );
+        templates.tables.remove(&previous_table);
+    }
+    templates.tables.insert(table_name.to_string(), template);
+}
+
+fn collect_template_paths(dir: &Path, recursive: bool, template_paths: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    // List template files in a directory, descending into subdirectories when recursive
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if recursive {
+                collect_template_paths(&path, recursive, template_paths)?;
+            }
+        } else {
+            template_paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+pub fn watch_template_dir(
+    template_dirs: &[String],
+    options: &LoadOptions,
+    templates: SharedTemplates,
+    debounce: Duration,
+    stability_check: Duration,
+) -> notify::Result<RecommendedWatcher> {
+    // Reload templates whenever a file in a template directory changes, or once changes have paused for debounce.
+    // The returned watcher must be kept alive.
+    let reload_dirs = template_dirs.to_vec();
+    let mut reload_options = options.clone();
+    reload_options.stability_check = Some(stability_check).filter(|interval| !interval.is_zero());
+    let (tx, rx) = mpsc::channel::<()>();
+    if !debounce.is_zero() {
+        let (reload_dirs, reload_options, templates) = (reload_dirs.clone(), reload_options.clone(), templates.clone());
+        // Exits once the watcher, and with it the sender, is dropped
+        thread::spawn(move || {
+            while rx.recv().is_ok() {
+                let mut changes = 1;
+                loop {
+                    match rx.recv_timeout(debounce) {
+                        Ok(()) => changes += 1,
+                        Err(mpsc::RecvTimeoutError::Timeout) => break,
+                        Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+                info!("Template directory changes settled after {} event(s), reloading", changes);
+                reload_templates(&reload_dirs, &reload_options, &templates);
+            }
+        });
+    }
+    let mut watcher = RecommendedWatcher::new(
+        move |res: notify::Result<Event>| match res {
+            Ok(event) => {
+                if let EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) = event.kind {
+                    if debounce.is_zero() {
+                        reload_templates(&reload_dirs, &reload_options, &templates);
+                    } else {
+                        let _ = tx.send(());
+                    }
+                }
+            }
+            Err(e) => error!("Template watch error: {:?}", e),
+        },
+        Config::default(),
+    )?;
+    let recursive_mode = if options.recursive {
+        RecursiveMode::Recursive
+    } else {
+        RecursiveMode::NonRecursive
+    };
+    for template_dir in template_dirs {
+        watcher.watch(Path::new(template_dir), recursive_mode)?;
+        info!("Watching template directory {:?} for changes", template_dir);
+    }
+    Ok(watcher)
+}
+
+fn log_table_changes(previous: &Templates, reloaded: &mut Templates) {
+    // Log the tables a reload added and removed, retiring removed tables. Files already matched to a removed table are
+    // still sent to its destination, new files that no longer match are handled like any unmatched file.
+    let mut removed: Vec<&String> = previous.tables.keys().filter(|table| !reloaded.tables.contains_key(*table)).collect();
+    removed.sort();
+    for table_name in removed {
+        info!(
+            "Template of table {:?} was removed. Files already matched to it are still sent, new ones are handled as unmatched",
+            table_name
+        );
+        reloaded.retired_dirs.insert(table_name.clone(), previous.tables[table_name].rel_dir.clone());
+    }
+    for (table_name, rel_dir) in &previous.retired_dirs {
+        if !reloaded.tables.contains_key(table_name) {
+            reloaded.retired_dirs.entry(table_name.clone()).or_insert_with(|| rel_dir.clone());
+        }
+    }
+    let mut added: Vec<&String> = reloaded.tables.keys().filter(|table| !previous.tables.contains_key(*table)).collect();
+    added.sort();
+    for table_name in added {
+        info!("Template of table {:?} was added, new files are matched against it", table_name);
+    }
+}
+
+fn reload_templates(template_dirs: &[String], options: &LoadOptions, templates: &SharedTemplates) {
+    // Reload, then reload again while templates look mid-write, keeping their previous mappings meanwhile
+    for retry in 0..=MAX_IN_FLUX_RETRIES {
+        if !reload_templates_once(template_dirs, options, templates) {
+            return;
+        }
+        if retry < MAX_IN_FLUX_RETRIES {
+            thread::sleep(options.stability_check.unwrap_or_default());
+        }
+    }
+    warn!(
+        "Templates still in flux after {} retries, keeping their previous mappings until they change again",
+        MAX_IN_FLUX_RETRIES
+    );
+}
+
+fn keep_in_flux_templates(previous: &Templates, reloaded: &mut Templates) {
+    // Carry over the previous mapping of every in flux template, tables without one stay unloaded until it settles
+    for (template_path, table_name) in std::mem::take(&mut reloaded.in_flux) {
+        match previous.tables.get(&table_name).filter(|template| template.path == template_path) {
+            Some(template) => {
+                warn!(
+                    "Template {:?} looks mid-write, keeping the previous mapping of table {:?} and retrying",
+                    template_path, table_name
+                );
+                insert_template(reloaded, &table_name, template.clone());
+            }
+            None => warn!("Template {:?} looks mid-write, skipping it and retrying", template_path),
+        }
+        reloaded.in_flux.push((template_path, table_name));
+    }
+}
+
+fn reload_templates_once(template_dirs: &[String], options: &LoadOptions, templates: &SharedTemplates) -> bool {
+    // Swap in freshly loaded templates, keeping the previous ones if loading fails. True if templates were in flux.
+    match load_headers(template_dirs, options) {
+        Ok(mut reloaded) => {
+            let mut templates = templates.write().unwrap();
+            keep_in_flux_templates(&templates, &mut reloaded);
+            let in_flux = !reloaded.in_flux.is_empty();
+            if let Some(shadowing) = &options.shadowing {
+                let shadowed = warn_shadowing(&reloaded);
+                if shadowed > 0 && *shadowing == ShadowingPolicy::Error {
+                    error!(
+                        "{} reloaded template(s) shadow another under HEADER_MATCH_MODE prefix, keeping previous templates",
+                        shadowed
+                    );
+                    return in_flux;
+                }
+            }
+            info!(
+                "Reloaded templates from {:?}. Template count: {} -> {}",
+                template_dirs,
+                templates.headers.len(),
+                reloaded.headers.len()
+            );
+            reloaded.generation = templates.generation + 1;
+            log_table_changes(&templates, &mut reloaded);
+            *templates = reloaded;
+            in_flux
+        }
+        Err(e) => {
+            error!("Failed to reload templates, keeping previous templates. Error: {}", e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn templates(pairs: &[(&str, &str)]) -> Templates {
+        let headers = pairs.iter().map(|(headers, table_name)| (headers.to_string(), table_name.to_string())).collect();
+        Templates { headers, ..Templates::default() }
+    }
+
+    fn load_options(shadowing: Option<ShadowingPolicy>) -> LoadOptions {
+        LoadOptions {
+            recursive: false,
+            name_regex: Regex::new("^(.+)_template$").unwrap(),
+            name_chars: None,
+            wrapper_chars: Vec::new(),
+            unicode_form: UnicodeForm::Off,
+            threads: 1,
+            stability_check: None,
+            encoding: HeaderEncoding::Auto,
+            shadowing,
+        }
+    }
+
+    #[test]
+    fn reload_keeps_templates_that_would_shadow_under_error_policy() {
+        let dir = std::env::temp_dir().join(format!("rsync_csv_test_{}_shadowing_reload", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("long_template.csv"), "a,b\n").unwrap();
+        let template_dirs = [dir.to_string_lossy().to_string()];
+        let templates: SharedTemplates =
+            Arc::new(RwLock::new(load_headers(&template_dirs, &load_options(None)).unwrap()));
+        fs::write(dir.join("short_template.csv"), "a\n").unwrap();
+        reload_templates_once(&template_dirs, &load_options(Some(ShadowingPolicy::Error)), &templates);
+        assert_eq!(templates.read().unwrap().headers.len(), 1);
+        reload_templates_once(&template_dirs, &load_options(Some(ShadowingPolicy::Warn)), &templates);
+        assert_eq!(templates.read().unwrap().headers.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shadowing_needs_a_whole_field_prefix() {
+        let cases: [(&[(&str, &str)], usize); 6] = [
+            (&[("a", "short"), ("a,b", "long")], 1),
+            // a,b is not a prefix of a,bc, both match whole fields only
+            (&[("a,b", "short"), ("a,bc", "long")], 0),
+            (&[("a", "short"), ("ab", "long")], 0),
+            (&[("a,b", "one"), ("b,a", "other")], 0),
+            // Every template leading another is reported, not only the nearest
+            (&[("a", "one"), ("a,b", "two"), ("a,b,c", "three")], 3),
+            (&[("a,b", "only")], 0),
+        ];
+        for (pairs, shadowed) in cases {
+            assert_eq!(warn_shadowing(&templates(pairs)), shadowed, "{pairs:?}");
+        }
+    }
+}
@@ -0,0 +1,225 @@
+// Daily rollup of files and bytes uploaded per table, for billing and capacity tracking.
+// The current day's counters are persisted after every upload so they survive restarts.
+// Without VOLUME_REPORT_DIR, every function here is a no-op.
+use chrono_tz::Tz;
+use log::{error, info};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+const IN_PROGRESS_FILE: &str = "volume_in_progress.json";
+
+#[derive(Default)]
+struct TableVolume {
+    files: u64,
+    bytes: u64,
+    // Sizes before compression and as transferred, of the transfers measured by COMPRESSION_STATS
+    uncompressed_bytes: u64,
+    transferred_bytes: u64,
+    // Size of the files rsync was given and the bytes it sent and received for them, with RSYNC_SPEEDUP_STATS
+    rsync_file_bytes: u64,
+    rsync_traffic_bytes: u64,
+}
+
+struct Rollup {
+    dir: PathBuf,
+    tz: Option<Tz>,
+    day: String,
+    tables: BTreeMap<String, TableVolume>,
+}
+
+static ROLLUP: OnceLock<Mutex<Rollup>> = OnceLock::new();
+
+impl Rollup {
+    fn rotate(&mut self) {
+        // Write the completed day's summary once the day changes, then start counting the new day
+        let today = today(self.tz);
+        if today == self.day {
+            return;
+        }
+        write_summary(&self.dir, &self.day, &self.tables);
+        self.day = today;
+        self.tables.clear();
+        self.persist();
+    }
+
+    fn persist(&self) {
+        let contents = json!({"date": self.day, "tables": tables_json(&self.tables)});
+        if let Err(e) = write_atomically(&self.dir.join(IN_PROGRESS_FILE), &contents) {
+            error!("Failed to persist volume counters. Error: {}", e);
+        }
+    }
+}
+
+pub fn init(dir: Option<&str>, tz: Option<Tz>) {
+    let Some(dir) = dir else {
+        return;
+    };
+    if let Err(e) = fs::create_dir_all(dir) {
+        error!("Failed to create volume report directory {}. Error: {}", dir, e);
+        return;
+    }
+    let mut rollup = Rollup {
+        dir: PathBuf::from(dir),
+        tz,
+        day: today(tz),
+        tables: BTreeMap::new(),
+    };
+    // Resume the counters of a previous run, or summarise them if they belong to an earlier day
+    match load_in_progress(&rollup.dir.join(IN_PROGRESS_FILE)) {
+        Ok(Some((day, tables))) if day == rollup.day => {
+            info!("Resuming volume counters for {}", day);
+            rollup.tables = tables;
+        }
+        Ok(Some((day, tables))) => {
+            rollup.day = day;
+            rollup.tables = tables;
+            rollup.rotate();
+        }
+        Ok(None) => (),
+        Err(e) => error!("Failed to read persisted volume counters, starting from zero. Error: {}", e),
+    }
+    info!("Writing daily volume reports to {}", dir);
+    let _ = ROLLUP.set(Mutex::new(rollup));
+}
+
+pub fn record(table_name: &str, bytes: u64) {
+    let Some(rollup) = ROLLUP.get() else {
+        return;
+    };
+    let mut rollup = rollup.lock().unwrap();
+    rollup.rotate();
+    let volume = rollup.tables.entry(table_name.to_string()).or_default();
+    volume.files += 1;
+    volume.bytes += bytes;
+    rollup.persist();
+}
+
+pub fn record_compression(table_name: &str, uncompressed_bytes: u64, transferred_bytes: u64) {
+    let Some(rollup) = ROLLUP.get() else {
+        return;
+    };
+    let mut rollup = rollup.lock().unwrap();
+    rollup.rotate();
+    let volume = rollup.tables.entry(table_name.to_string()).or_default();
+    volume.uncompressed_bytes += uncompressed_bytes;
+    volume.transferred_bytes += transferred_bytes;
+    rollup.persist();
+}
+
+pub fn record_speedup(table_name: &str, file_bytes: u64, traffic_bytes: u64) {
+    let Some(rollup) = ROLLUP.get() else {
+        return;
+    };
+    let mut rollup = rollup.lock().unwrap();
+    rollup.rotate();
+    let volume = rollup.tables.entry(table_name.to_string()).or_default();
+    volume.rsync_file_bytes += file_bytes;
+    volume.rsync_traffic_bytes += traffic_bytes;
+    rollup.persist();
+}
+
+pub fn rotate() {
+    // Called from the watch loop, so a day without uploads is still summarised at the boundary
+    if let Some(rollup) = ROLLUP.get() {
+        rollup.lock().unwrap().rotate();
+    }
+}
+
+fn today(tz: Option<Tz>) -> String {
+    match tz {
+        Some(tz) => chrono::Utc::now().with_timezone(&tz).format("%Y-%m-%d").to_string(),
+        None => chrono::Local::now().format("%Y-%m-%d").to_string(),
+    }
+}
+
+fn tables_json(tables: &BTreeMap<String, TableVolume>) -> Value {
+    let tables: Map<String, Value> = tables
+        .iter()
+        .map(|(table_name, volume)| {
+            let volume = json!({
+                "files": volume.files,
+                "bytes": volume.bytes,
+                "uncompressed_bytes": volume.uncompressed_bytes,
+                "transferred_bytes": volume.transferred_bytes,
+                "rsync_file_bytes": volume.rsync_file_bytes,
+                "rsync_traffic_bytes": volume.rsync_traffic_bytes,
+                "rsync_speedup": speedup(volume.rsync_file_bytes, volume.rsync_traffic_bytes),
+            });
+            (table_name.clone(), volume)
+        })
+        .collect();
+    Value::Object(tables)
+}
+
+fn speedup(file_bytes: u64, traffic_bytes: u64) -> Option<f64> {
+    // Like rsync's own speedup figure, null before any rsync transfer was measured
+    (traffic_bytes > 0).then(|| (file_bytes as f64 / traffic_bytes as f64 * 100.0).round() / 100.0)
+}
+
+fn write_summary(dir: &Path, day: &str, tables: &BTreeMap<String, TableVolume>) {
+    let mut summary = json!({
+        "date": day,
+        "tables": tables_json(tables),
+        "total": {
+            "files": tables.values().map(|volume| volume.files).sum::<u64>(),
+            "bytes": tables.values().map(|volume| volume.bytes).sum::<u64>(),
+            "uncompressed_bytes": tables.values().map(|volume| volume.uncompressed_bytes).sum::<u64>(),
+            "transferred_bytes": tables.values().map(|volume| volume.transferred_bytes).sum::<u64>(),
+            "rsync_file_bytes": tables.values().map(|volume| volume.rsync_file_bytes).sum::<u64>(),
+            "rsync_traffic_bytes": tables.values().map(|volume| volume.rsync_traffic_bytes).sum::<u64>(),
+            "rsync_speedup": speedup(
+                tables.values().map(|volume| volume.rsync_file_bytes).sum(),
+                tables.values().map(|volume| volume.rsync_traffic_bytes).sum(),
+            ),
+        },
+    });
+    if let Some(metadata) = crate::logging::metadata_json() {
+        summary["metadata"] = metadata;
+    }
+    let summary_path = dir.join(format!("volume_{day}.json"));
+    match write_atomically(&summary_path, &summary) {
+        Ok(_) => info!("Wrote volume report for {} to {:?}", day, summary_path),
+        Err(e) => error!("Failed to write volume report {:?}. Error: {}", summary_path, e),
+    }
+}
+
+fn load_in_progress(path: &Path) -> std::io::Result<Option<(String, BTreeMap<String, TableVolume>)>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let value: Value = serde_json::from_str(&contents)?;
+    let Some(day) = value["date"].as_str() else {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "missing date"));
+    };
+    let tables = value["tables"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(table_name, volume)| {
+            let volume = TableVolume {
+                files: volume["files"].as_u64().unwrap_or(0),
+                bytes: volume["bytes"].as_u64().unwrap_or(0),
+                uncompressed_bytes: volume["uncompressed_bytes"].as_u64().unwrap_or(0),
+                transferred_bytes: volume["transferred_bytes"].as_u64().unwrap_or(0),
+                rsync_file_bytes: volume["rsync_file_bytes"].as_u64().unwrap_or(0),
+                rsync_traffic_bytes: volume["rsync_traffic_bytes"].as_u64().unwrap_or(0),
+            };
+            (table_name.clone(), volume)
+        })
+        .collect();
+    Ok(Some((day.to_string(), tables)))
+}
+
+fn write_atomically(path: &Path, contents: &Value) -> std::io::Result<()> {
+    // Write next to the target and rename, so a crash never leaves a half written file
+    let partial_path = path.with_extension("json.partial");
+    fs::write(&partial_path, serde_json::to_string_pretty(contents)?)?;
+    fs::rename(&partial_path, path)
+}
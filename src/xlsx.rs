@@ -0,0 +1,118 @@
+// Header matching for .xlsx exports. The first sheet of a workbook is read like a csv file, and a matching workbook is
+// either converted to csv before upload or uploaded as is, per XLSX_MODE.
+// Without the "xlsx" cargo feature, or with XLSX_MODE=off, .xlsx files are ignored like any other non-csv file.
+
+#[cfg(feature = "xlsx")]
+mod workbook {
+    use crate::config::XlsxMode;
+    use calamine::{open_workbook, Data, Reader, Xlsx};
+    use log::{debug, info};
+    use std::{
+        fs::{self, File},
+        io::{self, BufReader},
+        path::Path,
+        sync::OnceLock,
+    };
+
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+
+    pub fn init(mode: &XlsxMode) {
+        let enabled = *mode != XlsxMode::Off;
+        if enabled {
+            info!("Matching the first sheet of .xlsx files against templates");
+        }
+        let _ = ENABLED.set(enabled);
+    }
+
+    pub fn accepts(path: &Path) -> bool {
+        *ENABLED.get().unwrap_or(&false) && path.extension().and_then(|s| s.to_str()) == Some("xlsx")
+    }
+
+    pub fn rows(xlsx_path: &str, limit: Option<usize>) -> io::Result<Vec<Vec<String>>> {
+        // Read up to limit rows of the first sheet, with missing cells as empty strings
+        let mut workbook: Xlsx<BufReader<File>> = open_workbook(xlsx_path).map_err(invalid_data)?;
+        let sheet = workbook
+            .worksheet_range_at(0)
+            .ok_or_else(|| invalid_data("workbook has no sheets"))?
+            .map_err(invalid_data)?;
+        // The range starts at the first used cell, so columns before it are padded back in
+        let first_column = sheet.start().map_or(0, |(_, column)| column as usize);
+        let rows = sheet
+            .rows()
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|row| {
+                let mut values: Vec<String> = vec![String::new(); first_column];
+                values.extend(row.iter().map(Data::to_string));
+                // Every row is as wide as the widest one, the extra cells would add empty fields
+                while values.last().is_some_and(String::is_empty) {
+                    values.pop();
+                }
+                values
+            })
+            .collect();
+        Ok(rows)
+    }
+
+    pub fn convert_to_csv(xlsx_path: &str) -> io::Result<String> {
+        // Write the first sheet next to the workbook as csv and remove the workbook, returning the csv's path
+        let csv_path = Path::new(xlsx_path).with_extension("csv");
+        if csv_path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", csv_path.display()),
+            ));
+        }
+        let mut csv = String::new();
+        for row in rows(xlsx_path, None)? {
+            let fields: Vec<String> = row.iter().map(|value| csv_field(value)).collect();
+            csv.push_str(&fields.join(","));
+            csv.push('\n');
+        }
+        // Write under a name the watcher ignores, then rename, so the csv never appears half written
+        let partial_path = csv_path.with_extension("csv.partial");
+        fs::write(&partial_path, csv)?;
+        fs::rename(&partial_path, &csv_path)?;
+        fs::remove_file(xlsx_path)?;
+        debug!("Converted {} to {:?}", xlsx_path, csv_path);
+        Ok(csv_path.to_string_lossy().to_string())
+    }
+
+    fn csv_field(value: &str) -> String {
+        if value.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", value.replace('"', "\"\""))
+        } else {
+            value.to_string()
+        }
+    }
+
+    fn invalid_data(error: impl ToString) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+    }
+}
+
+#[cfg(not(feature = "xlsx"))]
+mod workbook {
+    use crate::config::XlsxMode;
+    use log::error;
+    use std::{io, path::Path};
+
+    pub fn init(mode: &XlsxMode) {
+        if *mode != XlsxMode::Off {
+            error!("XLSX_MODE is set but rsync_csv was built without the xlsx feature, .xlsx files are ignored");
+        }
+    }
+
+    pub fn accepts(_path: &Path) -> bool {
+        false
+    }
+
+    pub fn rows(_xlsx_path: &str, _limit: Option<usize>) -> io::Result<Vec<Vec<String>>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "rsync_csv was built without the xlsx feature"))
+    }
+
+    pub fn convert_to_csv(_xlsx_path: &str) -> io::Result<String> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "rsync_csv was built without the xlsx feature"))
+    }
+}
+
+pub use workbook::*;